@@ -0,0 +1,99 @@
+#[macro_use]
+extern crate criterion;
+
+use std::fs;
+use std::path::Path;
+
+use criterion::{BatchSize, Criterion, ParameterizedBenchmark, Throughput};
+use tempfile::TempDir;
+
+use kvs::{Codec, KvsEngine, KvStore, KvStoreOptions};
+
+const KEYS: usize = 1 << 10;
+const CODECS: [&str; 4] = ["none", "lz4", "snappy", "zstd"];
+
+/// a verbose JSON document, like the values we store
+fn value(i: usize) -> String {
+    let items: Vec<String> = (0..8)
+        .map(|j| format!(r#"{{"id":{},"name":"item-{}","enabled":true,"tags":["alpha","beta"],"score":{}.5}}"#, j, i + j, j * 7))
+        .collect();
+    format!(r#"{{"user":"user-{}","created_at":"2020-01-01T00:00:00Z","items":[{}]}}"#, i, items.join(","))
+}
+
+fn options(codec: &str) -> KvStoreOptions {
+    match codec {
+        "none" => KvStoreOptions::new(),
+        codec => KvStoreOptions::new().compression(codec.parse::<Codec>().unwrap()),
+    }
+}
+
+fn log_bytes(path: &Path) -> u64 {
+    fs::read_dir(path).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "log"))
+        .map(|p| p.metadata().unwrap().len())
+        .sum()
+}
+
+/// print the log size each codec leaves, criterion only measures time
+fn print_sizes() {
+    let raw: usize = (0..KEYS).map(|i| value(i).len()).sum();
+    for codec in CODECS.iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open_with(temp_dir.path(), options(codec)).unwrap();
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), value(i)).unwrap();
+        }
+        let bytes = log_bytes(temp_dir.path());
+        println!("{:>6}: {} bytes of log for {} bytes of values ({:.1}%)", codec, bytes, raw, bytes as f64 * 100.0 / raw as f64);
+    }
+}
+
+fn set_bench(c: &mut Criterion) {
+    print_sizes();
+    let raw: usize = (0..KEYS).map(|i| value(i).len()).sum();
+    let bench = ParameterizedBenchmark::new(
+        "set",
+        |b, codec| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    (KvStore::open_with(temp_dir.path(), options(codec)).unwrap(), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    for i in 0..KEYS {
+                        store.set(format!("key{}", i), value(i)).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        },
+        CODECS.to_vec(),
+    )
+        .throughput(move |_| Throughput::Bytes(raw as u64));
+    c.bench("compression", bench);
+}
+
+fn get_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "get",
+        |b, codec| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open_with(temp_dir.path(), options(codec)).unwrap();
+            for i in 0..KEYS {
+                store.set(format!("key{}", i), value(i)).unwrap();
+            }
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % KEYS;
+                store.get(format!("key{}", i)).unwrap();
+            })
+        },
+        CODECS.to_vec(),
+    )
+        .throughput(|_| Throughput::Bytes(value(0).len() as u64));
+    c.bench("compression", bench);
+}
+
+criterion_group!(benches, set_bench, get_bench);
+criterion_main!(benches);
@@ -3,12 +3,12 @@ extern crate criterion;
 
 use std::iter;
 
-use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
+use criterion::{BatchSize, Bencher, Criterion, ParameterizedBenchmark};
 use rand::prelude::*;
 use sled::Db;
 use tempfile::TempDir;
 
-use kvs::{KvsEngine, KvStore, SledKvsEngine};
+use kvs::{BTreeEngine, KvsEngine, KvStore, KvStoreOptions, LsmEngine, SledKvsEngine};
 
 fn set_bench(c: &mut Criterion) {
     let bench = ParameterizedBenchmark::new(
@@ -42,6 +42,34 @@ fn set_bench(c: &mut Criterion) {
                 },
                 BatchSize::SmallInput,
             )
+        })
+        .with_function("lsm", |b, _| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    (LsmEngine::open(temp_dir.path()).unwrap(), temp_dir)
+                },
+                |(mut engine, _temp_dir)| {
+                    for i in 1..(1 << 12) {
+                        engine.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        })
+        .with_function("btree", |b, _| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    (BTreeEngine::open(temp_dir.path()).unwrap(), temp_dir)
+                },
+                |(mut engine, _temp_dir)| {
+                    for i in 1..(1 << 12) {
+                        engine.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
         });
     c.bench("set_bench", bench);
 }
@@ -66,6 +94,8 @@ fn get_bench(c: &mut Criterion) {
         },
         vec![8, 12, 16],
     )
+        .with_function("kvs_sealed_seek", |b, i| get_sealed(b, *i, false))
+        .with_function("kvs_sealed_mmap", |b, i| get_sealed(b, *i, true))
         .with_function("sled", |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
@@ -77,9 +107,49 @@ fn get_bench(c: &mut Criterion) {
             b.iter(|| {
                 db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
             })
+        })
+        .with_function("lsm", |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut engine = LsmEngine::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                engine.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                engine.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+            })
+        })
+        .with_function("btree", |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut engine = BTreeEngine::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                engine.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                engine.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+            })
         });
     c.bench("get_bench", bench);
 }
 
+/// get from a store reopened after the sets, so every read hits a sealed generation
+fn get_sealed(b: &mut Bencher, i: u32, mmap: bool) {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for key_i in 1..(1 << i) {
+            store.set(format!("key{}", key_i), "value".to_string()).unwrap();
+        }
+    }
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().mmap(mmap)).unwrap();
+    let mut rng = SmallRng::from_seed([0; 16]);
+    b.iter(|| {
+        store.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+    })
+}
+
 criterion_group!(benches, set_bench, get_bench);
 criterion_main!(benches);
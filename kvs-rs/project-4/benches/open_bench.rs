@@ -0,0 +1,66 @@
+#[macro_use]
+extern crate criterion;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvStore, KvStoreOptions};
+
+const VALUE_SIZE: usize = 1024;
+
+/// A compacted store of `KVS_OPEN_BENCH_MB` megabytes, 256 by default.
+/// Set it to a few thousand to measure a multi-GB store.
+fn compacted_store() -> TempDir {
+    let mb: usize = env::var("KVS_OPEN_BENCH_MB").ok().and_then(|s| s.parse().ok()).unwrap_or(256);
+    let temp_dir = TempDir::new().unwrap();
+    // without a checkpoint, so open reads the hints or the logs
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().checkpoint_every(0)).unwrap();
+    let value = "v".repeat(VALUE_SIZE);
+    for i in 0..mb * 1024 {
+        store.set(format!("key{}", i), value.clone()).unwrap();
+    }
+    // overwriting more than the compaction threshold writes the hint
+    for i in 0..2048 {
+        store.set(format!("key{}", i), value.clone()).unwrap();
+    }
+    temp_dir
+}
+
+/// a copy of the files of `src`, without its hint files unless `hints`
+fn copy_store(src: &Path, hints: bool) -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    for entry in fs::read_dir(src).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_file() && (hints || path.extension() != Some("hint".as_ref())) {
+            fs::copy(&path, temp_dir.path().join(path.file_name().unwrap())).unwrap();
+        }
+    }
+    temp_dir
+}
+
+fn open_bench(c: &mut Criterion) {
+    let store = compacted_store();
+    // one directory per arm, each iteration opens a fresh copy of it
+    // since opening starts a new active log
+    let (hinted, unhinted) = (copy_store(store.path(), true), copy_store(store.path(), false));
+    let bench = ParameterizedBenchmark::new(
+        "open",
+        move |b, with_hint| {
+            let src = if *with_hint { hinted.path() } else { unhinted.path() };
+            b.iter_batched_ref(
+                || copy_store(src, true),
+                |dir| KvStore::open(dir.path()).unwrap(),
+                BatchSize::PerIteration,
+            )
+        },
+        vec![true, false],
+    );
+    c.bench("open_bench", bench.sample_size(10));
+}
+
+criterion_group!(benches, open_bench);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+#[macro_use]
+extern crate criterion;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use criterion::{Bencher, Criterion, ParameterizedBenchmark};
+use tempfile::TempDir;
+
+use kvs::{KvsClient, KvsServer, KvStore};
+use kvs::thread_pool::{DynamicThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
+
+const CLIENTS: usize = 16;
+const SETS_PER_CLIENT: usize = 64;
+
+/// every server of the run listens on a port of its own
+static NEXT_PORT: AtomicU16 = AtomicU16::new(4400);
+
+/// `CLIENTS` clients at once, each setting `SETS_PER_CLIENT` keys on its own
+/// connection, against a server with a pool of `P` of `threads` threads
+fn write_bench<P: ThreadPool + Send + 'static>(b: &mut Bencher, threads: u32) {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = format!("127.0.0.1:{}", NEXT_PORT.fetch_add(1, Ordering::SeqCst)).parse().unwrap();
+    let server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), P::new(threads).unwrap());
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(200));
+    b.iter(|| {
+        let clients: Vec<_> = (0..CLIENTS).map(|c| thread::spawn(move || {
+            let mut client = KvsClient::connect(addr).unwrap();
+            for i in 0..SETS_PER_CLIENT {
+                client.set(format!("key{}-{}", c, i), "value".to_owned()).unwrap();
+            }
+        })).collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    })
+}
+
+fn pool_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "shared_queue",
+        |b, threads| write_bench::<SharedQueueThreadPool>(b, *threads),
+        vec![2, 4, 8],
+    )
+        .with_function("rayon", |b, threads| write_bench::<RayonThreadPool>(b, *threads))
+        .with_function("work_stealing", |b, threads| write_bench::<WorkStealingThreadPool>(b, *threads))
+        .with_function("dynamic", |b, threads| write_bench::<DynamicThreadPool>(b, *threads));
+    c.bench("server_write_bench", bench.sample_size(10));
+}
+
+criterion_group!(benches, pool_bench);
+criterion_main!(benches);
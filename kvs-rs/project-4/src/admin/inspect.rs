@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+use serde_json::Deserializer;
+
+use crate::{Op, Result};
+use crate::dbengines::{blob, checkpoint, hint, Record as LogRecord};
+use crate::dbengines::crypto::{self, Keyring};
+use crate::error::KvsError;
+use crate::utils::{format_path, ls_logs};
+
+/// One record of a log file, located the way `Pos` records it, with its value decompressed.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub id: u16,
+    pub off: u32,
+    pub size: u32,
+    pub op: Op,
+}
+
+/// A byte range of a log file that holds no valid record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadRange {
+    pub id: u16,
+    pub off: u32,
+    pub len: u32,
+    pub reason: String,
+}
+
+/// What `verify` found in a data directory.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub records: u64,
+    pub bad: Vec<BadRange>,
+    /// removes of a key that was not live at that point
+    pub dangling_tombstones: Vec<Record>,
+    /// keys set more than once, the older sets are dead
+    pub duplicate_keys: u64,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.bad.is_empty() && self.dangling_tombstones.is_empty()
+    }
+}
+
+/// Live and dead bytes of one generation.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GenerationStats {
+    pub id: u16,
+    pub records: u64,
+    pub live_records: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairMode {
+    /// drop everything from the first bad record to the end of its file
+    Truncate,
+    /// drop only the bad bytes and keep the valid records after them
+    Skip,
+}
+
+/// Split the content of log `id` of `dir` into valid records and bad ranges.
+/// After a bad record, parsing resumes at the next `{` where a whole record parses.
+/// A record whose value does not decompress or is not in its blob file is bad too, but one that does not
+/// decrypt fails the whole parse: with the wrong key every record would look bad.
+fn parse(dir: &Path, id: u16, data: &[u8], keys: Option<&Keyring>) -> Result<(Vec<Record>, Vec<BadRange>)> {
+    let (mut records, mut bad) = (Vec::new(), Vec::new());
+    let mut start = 0;
+    while start < data.len() {
+        let mut stream = Deserializer::from_slice(&data[start..]).into_iter::<LogRecord>();
+        let mut off = 0;
+        let mut failed = None;
+        while let Some(record) = stream.next() {
+            let op = match record {
+                Ok(record) => match crypto::unseal(record, keys).and_then(|record| unpack(dir, record, keys)) {
+                    Err(e @ KvsError::WrongKey(_)) => return Err(e),
+                    op => op.map_err(|e| e.to_string()),
+                },
+                Err(e) => Err(e.to_string()),
+            };
+            match op {
+                Ok(op) => {
+                    let end = stream.byte_offset();
+                    // the stream skips the whitespace before a record
+                    let lead = data[start + off..start + end].iter().take_while(|b| b.is_ascii_whitespace()).count();
+                    records.push(Record { id, off: (start + off + lead) as u32, size: (end - off - lead) as u32, op });
+                    off = end;
+                }
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            }
+        }
+        let reason = match failed {
+            Some(reason) => reason,
+            None => break,
+        };
+        let bad_start = start + off + data[start + off..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let next = (bad_start + 1..data.len())
+            .filter(|i| data[*i] == b'{')
+            .find(|i| matches!(Deserializer::from_slice(&data[*i..]).into_iter::<LogRecord>().next(), Some(Ok(_))))
+            .unwrap_or(data.len());
+        bad.push(BadRange { id, off: bad_start as u32, len: (next - bad_start) as u32, reason });
+        start = next;
+    }
+    Ok((records, bad))
+}
+
+/// the op of `record`, with the value from its blob file.
+/// A dead set into a collected blob file has an empty value.
+fn unpack(dir: &Path, record: LogRecord, keys: Option<&Keyring>) -> Result<Op> {
+    let key = record.key().to_owned();
+    match blob::resolve(dir, record, keys) {
+        Ok(record) => record.into_op(),
+        Err(ref e) if blob::is_collected(e) => Ok(Op::Set { key, value: String::new() }),
+        Err(e) => Err(e),
+    }
+}
+
+/// every valid record of the logs in `dir`, oldest first, with the bad ranges found.
+/// Encrypted records are read with the keys in `KVS_ENCRYPTION_KEYS`.
+pub fn dump(dir: &Path) -> Result<(Vec<Record>, Vec<BadRange>)> {
+    let keys = Keyring::from_env()?;
+    let (mut records, mut bad) = (Vec::new(), Vec::new());
+    for id in ls_logs(dir) {
+        let (r, b) = parse(dir, id, &fs::read(format_path(dir, id))?, keys.as_ref())?;
+        records.extend(r);
+        bad.extend(b);
+    }
+    Ok((records, bad))
+}
+
+/// replay the logs in `dir` as `KvStore::open` does and report anything wrong
+pub fn verify(dir: &Path) -> Result<Report> {
+    let (records, bad) = dump(dir)?;
+    let mut report = Report { records: records.len() as u64, bad, ..Report::default() };
+    let mut live: HashSet<&str> = HashSet::new();
+    let mut seen: HashMap<&str, u32> = HashMap::new();
+    for record in records.iter() {
+        match &record.op {
+            Op::Set { key, .. } => {
+                live.insert(key);
+                *seen.entry(key).or_insert(0) += 1;
+            }
+            Op::Remove { key } => {
+                if !live.remove(key.as_str()) {
+                    report.dangling_tombstones.push(record.clone());
+                }
+            }
+        }
+    }
+    report.duplicate_keys = seen.values().filter(|n| **n > 1).count() as u64;
+    Ok(report)
+}
+
+/// live and dead bytes of every generation, a record is live while the index points at it
+pub fn stats(dir: &Path) -> Result<Vec<GenerationStats>> {
+    let (records, _) = dump(dir)?;
+    let mut gens: BTreeMap<u16, GenerationStats> = BTreeMap::new();
+    let mut index: HashMap<&str, &Record> = HashMap::new();
+    for record in records.iter() {
+        let gen = gens.entry(record.id).or_insert_with(|| GenerationStats { id: record.id, ..GenerationStats::default() });
+        gen.records += 1;
+        gen.dead_bytes += record.size as u64;
+        match &record.op {
+            Op::Set { key, .. } => index.insert(key, record),
+            Op::Remove { key } => index.remove(key.as_str()),
+        };
+    }
+    for record in index.values() {
+        let gen = gens.get_mut(&record.id).unwrap();
+        gen.live_records += 1;
+        gen.live_bytes += record.size as u64;
+        gen.dead_bytes -= record.size as u64;
+    }
+    Ok(gens.into_values().collect())
+}
+
+/// Rewrite every log in `dir` holding bad ranges and return the ranges dropped.
+/// A file is replaced through a temporary file, so a crash leaves it untouched.
+pub fn repair(dir: &Path, mode: RepairMode) -> Result<Vec<BadRange>> {
+    let keys = Keyring::from_env()?;
+    let mut dropped = Vec::new();
+    for id in ls_logs(dir) {
+        let path = format_path(dir, id);
+        let data = fs::read(&path)?;
+        let (records, bad) = parse(dir, id, &data, keys.as_ref())?;
+        let first = match bad.first() {
+            Some(first) => first.off,
+            None => continue,
+        };
+        let mut fixed = Vec::with_capacity(data.len());
+        for record in records.iter() {
+            if mode == RepairMode::Truncate && record.off > first {
+                break;
+            }
+            fixed.extend_from_slice(&data[record.off as usize..(record.off + record.size) as usize]);
+        }
+        let tmp = dir.join(format!("{}.repair", id));
+        fs::write(&tmp, &fixed)?;
+        OpenOptions::new().write(true).open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        // offsets moved, the log is replayed in full from now on
+        hint::remove(dir, id)?;
+        checkpoint::remove(dir)?;
+        match mode {
+            RepairMode::Truncate => dropped.push(BadRange {
+                id,
+                off: first,
+                len: data.len() as u32 - first,
+                reason: bad[0].reason.clone(),
+            }),
+            RepairMode::Skip => dropped.extend(bad),
+        }
+    }
+    Ok(dropped)
+}
@@ -0,0 +1,50 @@
+use crate::{KvsEngine, Result};
+use crate::dbengines::scan_each;
+use crate::error::KvsError;
+use crate::utils::fnv1a;
+
+/// The number of live keys of an engine and a checksum over every pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Summary {
+    pub keys: u64,
+    pub checksum: u64,
+}
+
+impl Summary {
+    fn add(&mut self, key: &str, value: &str) {
+        self.keys += 1;
+        // the separator keeps ("ab", "c") and ("a", "bc") apart
+        let pair = format!("{}\0{}", key, value);
+        self.checksum = self.checksum.rotate_left(1) ^ fnv1a(pair.as_bytes());
+    }
+}
+
+/// count and checksum every pair of `engine`, in key order
+pub fn summarize<E: KvsEngine>(engine: &E) -> Result<Summary> {
+    let mut summary = Summary::default();
+    scan_each(engine, |key, value| {
+        summary.add(&key, &value);
+        Ok(())
+    })?;
+    Ok(summary)
+}
+
+/// Copy every live pair of `src` into `dst`, which must be empty, then read
+/// `dst` back and check it holds exactly the same pairs.
+pub fn migrate<S, D>(src: &S, dst: &D) -> Result<Summary> where S: KvsEngine, D: KvsEngine {
+    if !dst.scan(String::new(), 1)?.is_empty() {
+        return Err(KvsError::StringError("the target engine already holds data".to_string()));
+    }
+    let mut copied = Summary::default();
+    scan_each(src, |key, value| {
+        copied.add(&key, &value);
+        dst.set(key, value)
+    })?;
+    let written = summarize(dst)?;
+    if written != copied {
+        return Err(KvsError::StringError(format!(
+            "migration check failed: copied {} keys ({:016x}), target holds {} keys ({:016x})",
+            copied.keys, copied.checksum, written.keys, written.checksum)));
+    }
+    Ok(copied)
+}
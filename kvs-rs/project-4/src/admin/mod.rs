@@ -0,0 +1,10 @@
+//! Offline maintenance of a data directory, driven by `kvs-admin`.
+//!
+//! These run against engines opened directly on disk, so the server owning
+//! the directory must be stopped first.
+
+pub use self::inspect::{dump, repair, stats, verify, BadRange, GenerationStats, Record, RepairMode, Report};
+pub use self::migrate::{migrate, summarize, Summary};
+
+mod inspect;
+mod migrate;
@@ -0,0 +1,225 @@
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate log;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use log::LevelFilter;
+use structopt::StructOpt;
+
+use kvs::*;
+use kvs::admin::{self, migrate, RepairMode, Summary};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-admin", about = "Offline maintenance of a kvs-server data directory")]
+enum Opt {
+    #[structopt(name = "migrate", about = "Move the data of a directory to another engine")]
+    Migrate {
+        #[structopt(long, help = "The engine the data is in", raw(possible_values = "&Engine::variants()"))]
+        from: Engine,
+        #[structopt(long, help = "The engine to move the data to", raw(possible_values = "&Engine::variants()"))]
+        to: Engine,
+        #[structopt(long = "keep-source", help = "Keeps the files of the source engine")]
+        keep_source: bool,
+        #[structopt(name = "DIR", help = "The data directory of a stopped server", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(name = "dump", about = "Print every record of the kvs logs with its position")]
+    Dump {
+        #[structopt(name = "DIR", help = "The data directory of a stopped server", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(name = "verify", about = "Replay the kvs logs and report corruption")]
+    Verify {
+        #[structopt(name = "DIR", help = "The data directory of a stopped server", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(name = "repair", about = "Drop the corrupted parts of the kvs logs")]
+    Repair {
+        #[structopt(
+        long,
+        help = "truncate drops a log from its first bad record, skip drops only the bad bytes",
+        raw(possible_values = "&Mode::variants()"),
+        raw(default_value = "\"truncate\"")
+        )]
+        mode: Mode,
+        #[structopt(name = "DIR", help = "The data directory of a stopped server", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(name = "stats", about = "Print live and dead bytes of every kvs log")]
+    Stats {
+        #[structopt(name = "DIR", help = "The data directory of a stopped server", parse(from_os_str))]
+        dir: PathBuf,
+    },
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm,
+        btree,
+        memory
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Mode {
+        truncate,
+        skip
+    }
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let res = match Opt::from_args() {
+        Opt::Migrate { from, to, keep_source, dir } => run_migrate(from, to, keep_source, &dir),
+        Opt::Dump { dir } => dump(&dir),
+        Opt::Verify { dir } => verify(&dir),
+        Opt::Repair { mode, dir } => {
+            let mode = match mode {
+                Mode::truncate => RepairMode::Truncate,
+                Mode::skip => RepairMode::Skip,
+            };
+            repair(mode, &dir)
+        }
+        Opt::Stats { dir } => stats(&dir),
+    };
+    if let Err(e) = res {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn run_migrate(from: Engine, to: Engine, keep_source: bool, dir: &Path) -> Result<()> {
+    if from == to {
+        error!("--from and --to are the same engine");
+        exit(1);
+    }
+    match fs::read_to_string(dir.join("engine")) {
+        Ok(ref engine) if engine != &from.to_string() => {
+            error!("{} holds {} data, not {}", dir.display(), engine, from);
+            exit(1);
+        }
+        _ => {}
+    }
+
+    // both engines are dropped before any file is removed
+    let summary = match from {
+        Engine::kvs => copy_to(&KvStore::open(dir)?, to, dir)?,
+        Engine::sled => copy_to(&SledKvsEngine::open(dir)?, to, dir)?,
+        Engine::lsm => copy_to(&LsmEngine::open(dir)?, to, dir)?,
+        Engine::btree => copy_to(&BTreeEngine::open(dir)?, to, dir)?,
+        Engine::memory => copy_to(&MemoryEngine::open(dir)?, to, dir)?,
+    };
+    fs::write(dir.join("engine"), format!("{}", to))?;
+    info!("Migrated {} keys from {} to {}, checksum {:016x}", summary.keys, from, to, summary.checksum);
+
+    if !keep_source {
+        remove_engine_files(from, dir)?;
+        info!("Removed the {} files", from);
+    }
+    Ok(())
+}
+
+fn copy_to<S: KvsEngine>(src: &S, to: Engine, dir: &Path) -> Result<Summary> {
+    match to {
+        Engine::kvs => migrate(src, &KvStore::open(dir)?),
+        Engine::sled => {
+            let dst = SledKvsEngine::open(dir)?;
+            let summary = migrate(src, &dst)?;
+            dst.flush()?;
+            Ok(summary)
+        }
+        Engine::lsm => migrate(src, &LsmEngine::open(dir)?),
+        Engine::btree => {
+            let dst = BTreeEngine::open(dir)?;
+            let summary = migrate(src, &dst)?;
+            dst.checkpoint()?;
+            Ok(summary)
+        }
+        Engine::memory => {
+            let dst = MemoryEngine::open(dir)?;
+            let summary = migrate(src, &dst)?;
+            dst.snapshot()?;
+            Ok(summary)
+        }
+    }
+}
+
+/// the files each engine keeps in its directory
+fn remove_engine_files(engine: Engine, dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let owned = match engine {
+            Engine::kvs => name.ends_with(".log") || name.ends_with(".hint") || name.ends_with(".blob") || name == "index.checkpoint",
+            Engine::sled => name == "conf" || name == "db" || name == "blobs" || name.starts_with("snap."),
+            Engine::lsm => name.ends_with(".sst") || name == "lsm.wal" || name == "LSM_MANIFEST",
+            Engine::btree => name == "btree.db" || name == "btree.wal",
+            Engine::memory => name == "memory.snapshot",
+        };
+        if !owned {
+            continue;
+        }
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn dump(dir: &Path) -> Result<()> {
+    let (records, bad) = admin::dump(dir)?;
+    println!("{:>5} {:>10} {:>6}  op", "file", "offset", "size");
+    for r in records {
+        println!("{:>5} {:>10} {:>6}  {}", r.id, r.off, r.size, serde_json::to_string(&r.op)?);
+    }
+    for b in bad {
+        println!("{:>5} {:>10} {:>6}  BAD: {}", b.id, b.off, b.len, b.reason);
+    }
+    Ok(())
+}
+
+fn verify(dir: &Path) -> Result<()> {
+    let report = admin::verify(dir)?;
+    println!("records: {}", report.records);
+    println!("keys set more than once: {}", report.duplicate_keys);
+    for b in report.bad.iter() {
+        println!("corrupted: {}.log offset {} length {}: {}", b.id, b.off, b.len, b.reason);
+    }
+    for r in report.dangling_tombstones.iter() {
+        println!("dangling tombstone: {}.log offset {}: {}", r.id, r.off, serde_json::to_string(&r.op)?);
+    }
+    if !report.is_ok() {
+        exit(1);
+    }
+    println!("ok");
+    Ok(())
+}
+
+fn repair(mode: RepairMode, dir: &Path) -> Result<()> {
+    let dropped = admin::repair(dir, mode)?;
+    for b in dropped.iter() {
+        println!("dropped {}.log offset {} length {}", b.id, b.off, b.len);
+    }
+    println!("dropped {} bad ranges", dropped.len());
+    Ok(())
+}
+
+fn stats(dir: &Path) -> Result<()> {
+    println!("{:>5} {:>8} {:>8} {:>12} {:>12}", "file", "records", "live", "live bytes", "dead bytes");
+    for g in admin::stats(dir)? {
+        println!("{:>5} {:>8} {:>8} {:>12} {:>12}", g.id, g.records, g.live_records, g.live_bytes, g.dead_bytes);
+    }
+    Ok(())
+}
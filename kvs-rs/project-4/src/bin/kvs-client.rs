@@ -65,6 +65,63 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(name = "replication", about = "Show the replication role and lag of a server")]
+    Replication {
+        #[structopt(
+        long,
+        help = "Sets the server address",
+        raw(value_name = "ADDRESS_FORMAT"),
+        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "promote", about = "Promote a replica to accept writes")]
+    Promote {
+        #[structopt(
+        long,
+        help = "Sets the server address",
+        raw(value_name = "ADDRESS_FORMAT"),
+        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "slowlog", about = "Show the latest requests a server logged as slow")]
+    SlowLog {
+        #[structopt(long, help = "Shows at most this many entries", default_value = "10")]
+        limit: usize,
+        #[structopt(
+        long,
+        help = "Sets the server address",
+        raw(value_name = "ADDRESS_FORMAT"),
+        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "info", about = "Show the version, engine and load of a server")]
+    Info {
+        #[structopt(
+        long,
+        help = "Sets the server address",
+        raw(value_name = "ADDRESS_FORMAT"),
+        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "compact", about = "Compact the engine of a server now")]
+    Compact {
+        #[structopt(
+        long,
+        help = "Sets the server address",
+        raw(value_name = "ADDRESS_FORMAT"),
+        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
@@ -93,6 +150,55 @@ fn run(opt: Opt) -> Result<()> {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key)?;
         }
+        Command::Replication { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let status = client.replica_status()?;
+            println!("role: {}", status.role);
+            if let Some(leader) = status.leader {
+                println!("leader: {}", leader);
+            }
+            println!("applied: {}", status.applied);
+            println!("leader_head: {}", status.leader_head);
+            println!("lag: {}", status.lag);
+        }
+        Command::Promote { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.promote()?;
+        }
+        Command::SlowLog { limit, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            for e in client.slowlog(limit)? {
+                println!(
+                    "{} {} {} {:?} duration={}us engine={}us queue={}us",
+                    e.id, e.timestamp, e.command, e.key, e.duration_us, e.engine_us, e.queue_us
+                );
+            }
+        }
+        Command::Info { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let info = client.info()?;
+            println!("version: {}", info.version);
+            println!("engine: {}", info.engine.engine);
+            println!("uptime_secs: {}", info.uptime_secs);
+            println!("connections: {}", info.connections);
+            println!("total_connections: {}", info.total_connections);
+            println!("keys: {}", info.engine.keys);
+            println!("disk_bytes: {}", info.engine.disk_bytes);
+            if let Some(dead) = info.engine.dead_bytes {
+                println!("dead_bytes: {}", dead);
+            }
+            if let Some(ratio) = info.engine.dead_ratio() {
+                println!("dead_ratio: {:.3}", ratio);
+            }
+            if let Some(generation) = info.engine.generation {
+                println!("generation: {}", generation);
+            }
+            println!("pool: {} ({} threads)", info.pool, info.pool_threads);
+        }
+        Command::Compact { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.compact()?;
+        }
     }
     Ok(())
 }
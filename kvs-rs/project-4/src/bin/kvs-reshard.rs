@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use kvs::Result;
+use kvs::shard::{reshard, ShardMap};
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+name = "kvs-reshard",
+about = "Move keys between kvs-servers after a shard map change"
+)]
+struct Opt {
+    #[structopt(long, help = "The shard map the servers were filled with", parse(from_os_str))]
+    from: PathBuf,
+    #[structopt(long, help = "The new shard map", parse(from_os_str))]
+    to: PathBuf,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let from = ShardMap::load(&opt.from)?;
+    let to = ShardMap::load(&opt.to)?;
+    let moved = reshard(&from, &to)?;
+    println!("moved {} keys", moved);
+    Ok(())
+}
@@ -3,16 +3,21 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 
+use std::collections::HashMap;
 use std::env::current_dir;
 use std::fs;
+use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
 
 use log::LevelFilter;
 use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
 
 use kvs::*;
-use kvs::thread_pool::{RayonThreadPool, ThreadPool};
+use kvs::thread_pool::{DynamicThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
@@ -35,6 +40,218 @@ struct Opt {
     raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+    long = "replica-of",
+    help = "Runs as a read-only replica of the leader at the address",
+    value_name = "IP:PORT",
+    parse(try_from_str)
+    )]
+    replica_of: Option<SocketAddr>,
+    #[structopt(
+    long = "replication",
+    help = "Keeps the writes for replicas to pull, journaled in the data directory unless the engine is memory"
+    )]
+    replication: bool,
+    #[structopt(
+    long = "raft-id",
+    help = "Runs as the raft node with the id, requires --raft-peers",
+    value_name = "ID"
+    )]
+    raft_id: Option<u64>,
+    #[structopt(
+    long = "raft-peers",
+    help = "Sets the server address of every raft node, this one included",
+    value_name = "ID=IP:PORT,...",
+    parse(try_from_str = "parse_peers")
+    )]
+    raft_peers: Option<HashMap<u64, SocketAddr>>,
+    #[structopt(
+    long = "metrics-addr",
+    help = "Serves the metrics in the Prometheus text format at http://IP:PORT/metrics",
+    value_name = "IP:PORT",
+    parse(try_from_str)
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[structopt(
+    long = "log-level",
+    help = "Logs at this level and above, RUST_LOG overrides it",
+    value_name = "LEVEL",
+    default_value = "info",
+    parse(try_from_str)
+    )]
+    log_level: LevelFilter,
+    #[structopt(
+    long = "log-format",
+    help = "Logs as text lines or JSON objects, one per line",
+    value_name = "FORMAT",
+    default_value = "text",
+    raw(possible_values = "&LogFormat::variants()")
+    )]
+    log_format: LogFormat,
+    #[structopt(
+    long = "checkpoint-every",
+    help = "Checkpoints the kvs index every N writes, off by default",
+    value_name = "N"
+    )]
+    checkpoint_every: Option<u64>,
+    #[structopt(
+    long,
+    help = "Compresses the kvs values written with lz4, snappy or zstd",
+    value_name = "CODEC"
+    )]
+    compression: Option<Codec>,
+    #[structopt(
+    long = "compress-threshold",
+    help = "Writes kvs values shorter than BYTES raw",
+    value_name = "BYTES"
+    )]
+    compress_threshold: Option<usize>,
+    #[structopt(
+    long = "encryption-key-file",
+    help = "Encrypts the kvs logs with the ID:KEY lines of the file, else with KVS_ENCRYPTION_KEYS if set",
+    value_name = "PATH",
+    parse(from_os_str)
+    )]
+    encryption_key_file: Option<PathBuf>,
+    #[structopt(
+    long = "blob-threshold",
+    help = "Keeps kvs values of at least BYTES in blob files, out of the logs",
+    value_name = "BYTES"
+    )]
+    blob_threshold: Option<usize>,
+    #[structopt(
+    long = "cache-size",
+    help = "Caches up to BYTES of recently read kvs values",
+    value_name = "BYTES"
+    )]
+    cache_size: Option<usize>,
+    #[structopt(
+    long = "compact-dead-bytes",
+    help = "Compacts the kvs logs once over BYTES are dead, 1 MiB without any other --compact-* trigger",
+    value_name = "BYTES"
+    )]
+    compact_dead_bytes: Option<u64>,
+    #[structopt(
+    long = "compact-dead-ratio",
+    help = "Compacts the kvs logs once over RATIO of their bytes are dead",
+    value_name = "RATIO"
+    )]
+    compact_dead_ratio: Option<f64>,
+    #[structopt(
+    long = "compact-every",
+    help = "Compacts the kvs logs SECS seconds after the last compaction",
+    value_name = "SECS"
+    )]
+    compact_every: Option<u64>,
+    #[structopt(
+    long = "compact-window",
+    help = "Only compacts the kvs logs by itself between these hours UTC, on the triggers given or the default",
+    value_name = "START-END",
+    parse(try_from_str = "parse_window")
+    )]
+    compact_window: Option<(u8, u8)>,
+    #[structopt(
+    long = "compact-manual",
+    help = "Only compacts the kvs logs on kvs-client compact",
+    raw(conflicts_with_all = r#"&["compact_dead_bytes", "compact_dead_ratio", "compact_every", "compact_window"]"#)
+    )]
+    compact_manual: bool,
+    #[structopt(
+    long = "compact-rate",
+    help = "Writes the kvs compactions the policy starts at about BYTES per second",
+    value_name = "BYTES"
+    )]
+    compact_rate: Option<u64>,
+    #[structopt(
+    long = "snapshot-every",
+    help = "Snapshots the memory engine every SECS seconds, without it writes are lost on exit",
+    value_name = "SECS"
+    )]
+    snapshot_every: Option<u64>,
+    #[structopt(
+    long,
+    help = "Serves connections from this kind of thread pool",
+    value_name = "POOL",
+    default_value = "rayon",
+    raw(possible_values = "&Pool::variants()")
+    )]
+    pool: Pool,
+    #[structopt(
+    long,
+    help = "Sets the threads of a fixed size pool",
+    value_name = "N",
+    default_value = "4"
+    )]
+    threads: u32,
+    #[structopt(
+    long = "min-threads",
+    help = "Keeps at least N threads in the dynamic pool",
+    value_name = "N",
+    default_value = "1"
+    )]
+    min_threads: u32,
+    #[structopt(
+    long = "max-threads",
+    help = "Grows the dynamic pool up to N threads",
+    value_name = "N",
+    default_value = "64"
+    )]
+    max_threads: u32,
+    #[structopt(
+    long = "keep-alive",
+    help = "Retires threads of the dynamic pool idle for SECS seconds",
+    value_name = "SECS",
+    default_value = "60"
+    )]
+    keep_alive: u64,
+    #[structopt(
+    long = "slowlog-threshold",
+    help = "Logs requests taking MICROS or longer, for kvs-client slowlog",
+    value_name = "MICROS"
+    )]
+    slowlog_threshold: Option<u64>,
+    #[structopt(
+    long = "slowlog-len",
+    help = "Keeps the latest N slow requests",
+    value_name = "N",
+    default_value = "128"
+    )]
+    slowlog_len: usize,
+    #[structopt(
+    long = "backup-dir",
+    help = "Allows clients to back up the data into directories under DIR",
+    value_name = "DIR",
+    parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "backup", about = "Backs up the data of a running server")]
+    Backup {
+        #[structopt(
+        name = "DEST",
+        help = "An empty or missing directory, relative to the server's --backup-dir",
+        parse(from_os_str)
+        )]
+        dest: PathBuf,
+        #[structopt(
+        long,
+        help = "Sets the server address",
+        value_name = "IP:PORT",
+        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "restore", about = "Restores a backup into the current directory")]
+    Restore {
+        #[structopt(name = "SRC", help = "A directory written by backup", parse(from_os_str))]
+        src: PathBuf,
+    },
 }
 
 arg_enum! {
@@ -42,13 +259,36 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        lsm,
+        btree,
+        memory
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Pool {
+        rayon,
+        shared_queue,
+        work_stealing,
+        dynamic
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum LogFormat {
+        text,
+        json
     }
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
+    init_logging(opt.log_level, opt.log_format);
     let res = current_engine().and_then(move |curr_engine| {
         if opt.engine.is_none() {
             opt.engine = curr_engine;
@@ -65,27 +305,222 @@ fn main() {
     }
 }
 
+/// Log to stderr through `tracing`, records of the `log` crate included.
+/// Requests are logged at debug level inside the span of their connection.
+fn init_logging(level: LevelFilter, format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(level.to_string().to_lowercase()));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(io::stderr);
+    match format {
+        LogFormat::text => builder.init(),
+        LogFormat::json => builder.json().init(),
+    }
+}
+
+fn parse_peers(s: &str) -> std::result::Result<HashMap<u64, SocketAddr>, String> {
+    let mut peers = HashMap::new();
+    for peer in s.split(',') {
+        let mut parts = peer.splitn(2, '=');
+        let id = parts.next().unwrap_or("").parse().map_err(|e| format!("invalid raft id: {}", e))?;
+        let addr = parts.next().ok_or_else(|| format!("missing address of raft node {}", id))?
+            .parse().map_err(|e| format!("invalid address of raft node {}: {}", id, e))?;
+        peers.insert(id, addr);
+    }
+    Ok(peers)
+}
+
+fn parse_window(s: &str) -> std::result::Result<(u8, u8), String> {
+    let mut parts = s.splitn(2, '-');
+    let mut hour = || -> std::result::Result<u8, String> {
+        match parts.next().unwrap_or("").parse() {
+            Ok(hour) if hour < 24 => Ok(hour),
+            _ => Err(format!("invalid compaction window {}, expected hours like 1-5", s)),
+        }
+    };
+    Ok((hour()?, hour()?))
+}
+
+/// the compaction policy the --compact-* flags make, the triggers given or
+/// else the default one, which a window alone only limits
+fn compaction_policy(opt: &Opt) -> CompactionPolicy {
+    let triggers = opt.compact_dead_bytes.is_some() || opt.compact_dead_ratio.is_some() || opt.compact_every.is_some();
+    let mut policy = if opt.compact_manual || triggers {
+        CompactionPolicy::new()
+    } else {
+        CompactionPolicy::default()
+    };
+    if let Some(bytes) = opt.compact_dead_bytes {
+        policy = policy.dead_bytes(bytes);
+    }
+    if let Some(ratio) = opt.compact_dead_ratio {
+        policy = policy.dead_ratio(ratio);
+    }
+    if let Some(secs) = opt.compact_every {
+        policy = policy.every(Duration::from_secs(secs));
+    }
+    if let Some((start, end)) = opt.compact_window {
+        policy = policy.window(start, end);
+    }
+    policy
+}
+
 fn run(opt: Opt) -> Result<()> {
+    match opt.cmd {
+        Some(Command::Backup { ref dest, addr }) => return backup(dest, addr),
+        Some(Command::Restore { ref src }) => return restore(src, opt.engine.unwrap_or(DEFAULT_ENGINE), &opt),
+        None => {}
+    }
+    match (opt.raft_id, &opt.raft_peers) {
+        (Some(id), Some(peers)) if !peers.contains_key(&id) => {
+            error!("--raft-peers does not contain the node {}", id);
+            exit(1);
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            error!("--raft-id and --raft-peers must be given together");
+            exit(1);
+        }
+        (Some(_), Some(_)) if opt.replica_of.is_some() || opt.replication => {
+            error!("--replica-of and --replication can not be used with raft");
+            exit(1);
+        }
+        _ => {}
+    }
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
+    if let Some(leader) = opt.replica_of {
+        info!("Replica of {}", leader);
+    }
+    if let Some(id) = opt.raft_id {
+        info!("Raft node {}", id);
+    }
+    if let Some(addr) = opt.metrics_addr {
+        metrics::serve(addr, metrics::global())?;
+        info!("Metrics on http://{}/metrics", addr);
+    }
 
     // write engine to engine file
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(&current_dir()?)?, opt.addr),
+        Engine::kvs => {
+            let mut options = KvStoreOptions::new();
+            if let Some(every) = opt.checkpoint_every {
+                options = options.checkpoint_every(every);
+            }
+            if let Some(codec) = opt.compression {
+                options = options.compression(codec);
+            }
+            if let Some(bytes) = opt.compress_threshold {
+                options = options.compress_threshold(bytes);
+            }
+            if let Some(bytes) = opt.blob_threshold {
+                options = options.blob_threshold(bytes);
+            }
+            if let Some(bytes) = opt.cache_size {
+                options = options.cache_capacity(bytes);
+            }
+            options = options.compaction(compaction_policy(&opt));
+            if let Some(bytes) = opt.compact_rate {
+                options = options.compaction_rate(bytes);
+            }
+            if let Some(keys) = keyring(&opt)? {
+                info!("Encryption key: {}", keys.active());
+                options = options.encryption(keys);
+            }
+            run_with_engine(KvStore::open_with(&current_dir()?, options)?, &opt)
+        }
         Engine::sled => run_with_engine(
             SledKvsEngine::new(sled::open(current_dir()?)?),
-            opt.addr,
+            &opt,
         ),
+        Engine::lsm => run_with_engine(LsmEngine::open(&current_dir()?)?, &opt),
+        Engine::btree => run_with_engine(BTreeEngine::open(&current_dir()?)?, &opt),
+        Engine::memory => {
+            let mut options = MemoryOptions::new();
+            if let Some(secs) = opt.snapshot_every {
+                options = options.snapshot_every(Duration::from_secs(secs));
+            }
+            run_with_engine(MemoryEngine::open_with(&current_dir()?, options)?, &opt)
+        }
+    }
+}
+
+/// Each raft peer holds a thread, on top of the threads the flags ask for.
+fn run_with_engine<E: KvsEngine + 'static>(engine: E, opt: &Opt) -> Result<()> {
+    let peers = opt.raft_peers.as_ref().map_or(0, |p| p.len() as u32);
+    match opt.pool {
+        Pool::rayon => run_with_pool(engine, RayonThreadPool::new(opt.threads + peers)?, opt),
+        Pool::shared_queue => run_with_pool(engine, SharedQueueThreadPool::new(opt.threads + peers)?, opt),
+        Pool::work_stealing => run_with_pool(engine, WorkStealingThreadPool::new(opt.threads + peers)?, opt),
+        Pool::dynamic => {
+            let (min, max) = (opt.min_threads + peers, opt.max_threads + peers);
+            let pool = DynamicThreadPool::with_limits(min, max, Duration::from_secs(opt.keep_alive))?;
+            run_with_pool(engine, pool, opt)
+        }
     }
 }
 
-fn run_with_engine<E: KvsEngine + 'static>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine, RayonThreadPool::new(4)?);
-    server.run(addr)
+fn run_with_pool<E: KvsEngine + 'static, P: ThreadPool>(engine: E, pool: P, opt: &Opt) -> Result<()> {
+    info!("Thread pool: {} with {} threads", pool.name(), pool.threads());
+    let mut server = KvsServer::new(engine, pool);
+    if let Some(leader) = opt.replica_of {
+        server = server.replica_of(leader);
+    }
+    if let (Some(id), Some(members)) = (opt.raft_id, opt.raft_peers.clone()) {
+        server = server.raft(id, members, current_dir()?);
+    } else if opt.replication || opt.replica_of.is_some() {
+        server = server.replication();
+        // the data of the memory engine does not outlive the server either
+        if opt.engine != Some(Engine::memory) {
+            server = server.replication_dir(current_dir()?);
+        }
+        if let Some(keys) = keyring(opt)? {
+            server = server.replication_keys(keys);
+        }
+    }
+    if let Some(micros) = opt.slowlog_threshold {
+        server = server.slowlog(Duration::from_micros(micros), opt.slowlog_len);
+    }
+    if let Some(dir) = opt.backup_dir.clone() {
+        server = server.backup_dir(dir);
+    }
+    server.run(opt.addr)
+}
+
+fn backup(dest: &Path, addr: SocketAddr) -> Result<()> {
+    KvsClient::connect(addr)?.backup(dest.to_string_lossy().into_owned())?;
+    info!("Backed up {} to {} in its backup directory", addr, dest.display());
+    Ok(())
+}
+
+/// the keys of `--encryption-key-file`, else of `KVS_ENCRYPTION_KEYS`
+fn keyring(opt: &Opt) -> Result<Option<Keyring>> {
+    match opt.encryption_key_file {
+        Some(ref path) => Ok(Some(Keyring::from_file(path)?)),
+        None => Keyring::from_env(),
+    }
+}
+
+fn restore(src: &Path, engine: Engine, opt: &Opt) -> Result<()> {
+    let dir = current_dir()?;
+    match engine {
+        Engine::kvs => {
+            let mut options = KvStoreOptions::new();
+            if let Some(keys) = keyring(opt)? {
+                options = options.encryption(keys);
+            }
+            drop(KvStore::restore_with(src, &dir, options)?)
+        }
+        Engine::sled => drop(SledKvsEngine::restore(src, &dir)?),
+        Engine::lsm => drop(LsmEngine::restore(src, &dir)?),
+        Engine::btree => drop(BTreeEngine::restore(src, &dir)?),
+        Engine::memory => drop(MemoryEngine::restore(src, &dir)?),
+    }
+    fs::write(dir.join("engine"), format!("{}", engine))?;
+    info!("Restored {} with engine {}", src.display(), engine);
+    Ok(())
 }
 
 fn current_engine() -> Result<Option<Engine>> {
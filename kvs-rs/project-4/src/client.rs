@@ -1,14 +1,23 @@
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
 
 use serde::Deserialize;
 use serde_json::Deserializer;
 
 use crate::error::KvsError;
-use crate::msg::Request::{Get, Remove, Set};
-use crate::msg::Response;
+use crate::msg::{Request, Response};
+use crate::raft::Envelope;
+use crate::replication::ReplicaStatus;
+use crate::server::ServerInfo;
+use crate::slowlog::SlowEntry;
 use crate::Result;
 
+const MAX_REDIRECTS: usize = 20;
+/// wait before retrying while a raft cluster elects its leader
+const REDIRECT_BACKOFF: Duration = Duration::from_millis(100);
+
 pub struct KvsClient {
     reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
@@ -25,54 +34,113 @@ impl KvsClient {
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Set { key, value })?;
-        self.writer.flush()?;
-
-        let mut de = Deserializer::from_reader(&mut self.reader);
-        let r = Response::deserialize(&mut de)?;
-        match r {
-            Response::Err(msg) => {
-                Err(KvsError::StringError(msg))
-            }
-            Response::Set(_) => {
-                Ok(())
-            }
-            _ => { Err(KvsError::UnexpectedCommandType) }
+        match self.request(&Request::Set { key, value })? {
+            Response::Set(_) => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
         }
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Get { key })?;
-        self.writer.flush()?;
+        match self.request(&Request::Get { key })? {
+            Response::Get(op) => Ok(op),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
 
-        let mut de = Deserializer::from_reader(&mut self.reader);
-        let r = Response::deserialize(&mut de)?;
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&Request::Remove { key })? {
+            Response::Remove => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
 
-        match r {
-            Response::Get(op) => {
-                Ok(op)
-            }
-            Response::Err(msg) => {
-                Err(KvsError::StringError(msg))
+    /// at most `limit` pairs in key order, starting from the first key >= `start`
+    pub fn scan(&mut self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        match self.request(&Request::Scan { start, limit })? {
+            Response::Scan(pairs) => Ok(pairs),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    pub fn replica_status(&mut self) -> Result<ReplicaStatus> {
+        match self.request(&Request::ReplicaStatus)? {
+            Response::ReplicaStatus(status) => Ok(status),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// turn a replica into a leader which accepts writes
+    pub fn promote(&mut self) -> Result<()> {
+        match self.request(&Request::Promote)? {
+            Response::Promote => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// back up the server's data into `dest`, relative to the server's backup directory
+    pub fn backup(&mut self, dest: String) -> Result<()> {
+        match self.request(&Request::Backup { dest })? {
+            Response::Backup => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// the `limit` latest requests the server logged as slow, newest first
+    pub fn slowlog(&mut self, limit: usize) -> Result<Vec<SlowEntry>> {
+        match self.request(&Request::SlowLog { limit })? {
+            Response::SlowLog(entries) => Ok(entries),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        match self.request(&Request::Info)? {
+            Response::Info(info) => Ok(info),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// compact the server's engine, returns once it is done
+    pub fn compact(&mut self) -> Result<()> {
+        match self.request(&Request::Compact)? {
+            Response::Compact => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    pub(crate) fn pull(&mut self, epoch: u64, since: u64, limit: usize) -> Result<Response> {
+        self.request(&Request::Pull { epoch, since, limit })
+    }
+
+    pub(crate) fn raft(&mut self, env: Envelope) -> Result<()> {
+        match self.request(&Request::Raft(env))? {
+            Response::Raft => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// send `req`, following raft redirects to the leader
+    fn request(&mut self, req: &Request) -> Result<Response> {
+        let mut leader = None;
+        for _ in 0..MAX_REDIRECTS {
+            match self.round_trip(req)? {
+                Response::NotLeader(Some(addr)) => {
+                    *self = KvsClient::connect(addr)?;
+                    leader = Some(addr);
+                }
+                Response::NotLeader(None) => thread::sleep(REDIRECT_BACKOFF),
+                Response::Err(msg) => return Err(KvsError::StringError(msg)),
+                r => return Ok(r),
             }
-            _ => { Err(KvsError::UnexpectedCommandType) }
         }
+        Err(KvsError::NotLeader(leader))
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Remove { key })?;
+    fn round_trip(&mut self, req: &Request) -> Result<Response> {
+        serde_json::to_writer(&mut self.writer, req)?;
         self.writer.flush()?;
 
         let mut de = Deserializer::from_reader(&mut self.reader);
-        let r = Response::deserialize(&mut de)?;
-        match r {
-            Response::Remove => {
-                Ok(())
-            }
-            Response::Err(msg) => {
-                Err(KvsError::StringError(msg))
-            }
-            _ => { Err(KvsError::UnexpectedCommandType) }
-        }
+        Ok(Response::deserialize(&mut de)?)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,119 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{KvsEngine, Result};
+use crate::dbengines::{blob, scan_each, Op, Record};
+use crate::dbengines::crypto::{self, Keyring};
+use crate::error::KvsError;
+use crate::utils::{format_path, ls_logs};
+
+const MANIFEST: &str = "MANIFEST";
+
+/// Lists the files of a backup with their checksum.
+///
+/// Every backup is a set of `N.log` files in the `KvStore` format, with the
+/// `N.blob` files they point into, so a backup of one engine can be restored
+/// into another.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// the engine the backup was taken from
+    pub engine: String,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    pub crc32: u32,
+}
+
+/// `dest` is created, it must not exist or be an empty directory
+pub fn prepare_dest(dest: &Path) -> Result<()> {
+    if dest.exists() && dest.read_dir()?.next().is_some() {
+        return Err(KvsError::StringError(format!("backup directory {} is not empty", dest.display())));
+    }
+    Ok(fs::create_dir_all(dest)?)
+}
+
+/// checksum the logs and blob files in `dest` and write the manifest, last,
+/// so a backup interrupted half way has no manifest and is never restored
+pub fn seal(dest: &Path, engine: &str) -> Result<()> {
+    let mut files = Vec::new();
+    let logs = ls_logs(dest).into_iter().map(|id| format_path(dest, id));
+    for path in logs.chain(blob::ls_blobs(dest)?.into_iter().map(|id| blob::blob_path(dest, id))) {
+        let (size, crc32) = checksum(&path)?;
+        files.push(BackupFile { name: path.file_name().unwrap().to_string_lossy().into_owned(), size, crc32 });
+    }
+    let manifest = Manifest { engine: engine.to_string(), files };
+    let tmp = dest.join(format!("{}.tmp", MANIFEST));
+    fs::write(&tmp, serde_json::to_vec_pretty(&manifest)?)?;
+    fs::rename(tmp, dest.join(MANIFEST))?;
+    Ok(())
+}
+
+/// back up an engine without snapshots by scanning it into a single log,
+/// so writes racing with the backup may or may not be in it
+pub fn write_engine<E: KvsEngine>(engine: &E, dest: &Path, name: &str) -> Result<()> {
+    prepare_dest(dest)?;
+    let mut writer = BufWriter::new(File::create(format_path(dest, 1))?);
+    scan_each(engine, |key, value| {
+        Ok(serde_json::to_writer(&mut writer, &Op::Set { key, value })?)
+    })?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    seal(dest, name)
+}
+
+/// check every file of the backup in `src` against its manifest
+pub fn verify(src: &Path) -> Result<Manifest> {
+    let manifest: Manifest = serde_json::from_slice(&fs::read(src.join(MANIFEST))?)?;
+    for file in manifest.files.iter() {
+        let (size, crc32) = checksum(&src.join(&file.name))?;
+        if size != file.size || crc32 != file.crc32 {
+            return Err(KvsError::StringError(format!("backup file {} is corrupted", file.name)));
+        }
+    }
+    Ok(manifest)
+}
+
+/// feed every op of a verified backup to `f`, oldest first,
+/// encrypted records are read with the keys in `KVS_ENCRYPTION_KEYS`.
+/// Sets pointing into a collected blob file are dead and skipped.
+pub fn replay<F>(src: &Path, mut f: F) -> Result<()> where F: FnMut(Op) -> Result<()> {
+    let keys = Keyring::from_env()?;
+    for id in ls_logs(src) {
+        let reader = BufReader::new(File::open(format_path(src, id))?);
+        for record in Deserializer::from_reader(reader).into_iter::<Record>() {
+            match blob::resolve(src, crypto::unseal(record?, keys.as_ref())?, keys.as_ref()) {
+                Ok(record) => f(record.into_op()?)?,
+                // a later record sets the key again, or removes it
+                Err(ref e) if blob::is_collected(e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// size and crc32 of a file
+fn checksum(path: &Path) -> Result<(u64, u32)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hasher.finalize()))
+}
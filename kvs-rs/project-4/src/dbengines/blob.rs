@@ -0,0 +1,145 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Deserializer;
+
+use crate::Result;
+use crate::dbengines::common::Record;
+use crate::dbengines::crypto::{self, Keyring};
+use crate::error::KvsError;
+
+const SUFFIX: &str = ".blob";
+
+pub fn blob_path(path: &Path, id: u32) -> PathBuf {
+    path.join(format!("{}{}", id, SUFFIX))
+}
+
+/// ids of the blob files in `path`, oldest first
+pub fn ls_blobs(path: &Path) -> Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(id) = name.strip_suffix(SUFFIX).and_then(|id| id.parse().ok()) {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// The blob files of a `KvStore`: the one values are appended to, created on
+/// the first large value, and the id of the next one.
+///
+/// A blob file holds the records of large values, each one referenced by a
+/// `Record::Blob` in the logs. Blob files are never compacted with the logs,
+/// the store collects a whole file once enough of it is garbage.
+#[derive(Debug)]
+pub struct Blobs {
+    active: Option<BlobWriter>,
+    next_id: u32,
+}
+
+#[derive(Debug)]
+struct BlobWriter {
+    id: u32,
+    writer: BufWriter<File>,
+    pos: u64,
+}
+
+impl Blobs {
+    pub fn open(path: &Path) -> Result<Blobs> {
+        Ok(Blobs { active: None, next_id: ls_blobs(path)?.last().map_or(1, |id| id + 1) })
+    }
+
+    /// Append `record`, the value of `key`, and return the reference to it.
+    /// Return true as well when this filled the active file, which is then sealed.
+    pub fn append(&mut self, path: &Path, key: String, record: &Record, file_size: u64) -> Result<(Record, bool)> {
+        if self.active.is_none() {
+            let file = OpenOptions::new().append(true).create_new(true).open(blob_path(path, self.next_id))?;
+            self.active = Some(BlobWriter { id: self.next_id, writer: BufWriter::new(file), pos: 0 });
+            self.next_id += 1;
+        }
+        let active = self.active.as_mut().unwrap();
+        let data = serde_json::to_vec(record)?;
+        active.writer.write_all(&data)?;
+        active.writer.flush()?;
+        let blob = Record::Blob { key, blob: active.id, off: active.pos, len: data.len() as u32 };
+        active.pos += data.len() as u64;
+        let full = active.pos >= file_size;
+        if full {
+            self.active = None;
+        }
+        Ok((blob, full))
+    }
+
+    /// make the values appended so far durable
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(ref mut active) = self.active {
+            active.writer.flush()?;
+            active.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// id and length of the file values are appended to, it is never collected
+    pub fn position(&self) -> Option<(u32, u64)> {
+        self.active.as_ref().map(|w| (w.id, w.pos))
+    }
+}
+
+/// the record at `off` of blob file `blob`, as it was written
+fn read(path: &Path, blob: u32, off: u64, len: u32) -> Result<Record> {
+    let mut file = File::open(blob_path(path, blob))?;
+    file.seek(SeekFrom::Start(off))?;
+    let mut data = vec![0; len as usize];
+    file.read_exact(&mut data)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// the record a `Record::Blob` points to, unsealed, other records as they are
+pub fn resolve(path: &Path, record: Record, keys: Option<&Keyring>) -> Result<Record> {
+    let (key, blob, off, len) = match record {
+        Record::Blob { key, blob, off, len } => (key, blob, off, len),
+        record => return Ok(record),
+    };
+    match crypto::unseal(read(path, blob, off, len)?, keys)? {
+        record @ Record::Set { .. } | record @ Record::Packed { .. } if record.key() == key => Ok(record),
+        _ => Err(KvsError::StringError(format!("{}.blob at {} does not hold the value of {}", blob, off, key))),
+    }
+}
+
+/// the id of the key the value a `Record::Blob` points to is sealed with,
+/// `None` for a plain value or another record
+pub fn key_id(path: &Path, record: &Record) -> Result<Option<u32>> {
+    match *record {
+        Record::Blob { blob, off, len, .. } => match read(path, blob, off, len)? {
+            Record::Sealed { key_id, .. } => Ok(Some(key_id)),
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// the error `resolve` returns for a reference into a collected blob file,
+/// only dead records point into one
+pub fn is_collected(e: &KvsError) -> bool {
+    matches!(e, KvsError::Io(e) if e.kind() == io::ErrorKind::NotFound)
+}
+
+/// the offset, length and unsealed key of every record of blob file `id`,
+/// a record cut short by a crash ends the file
+///
+/// The file is streamed, only one value is in memory at a time.
+pub fn entries(path: &Path, id: u32, keys: Option<&Keyring>) -> Result<Vec<(u64, u32, String)>> {
+    let reader = BufReader::new(File::open(blob_path(path, id))?);
+    let mut entries = Vec::new();
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Record>();
+    let mut start = 0;
+    while let Some(Ok(record)) = stream.next() {
+        let end = stream.byte_offset() as u64;
+        entries.push((start, (end - start) as u32, crypto::unseal(record, keys)?.key().to_owned()));
+        start = end;
+    }
+    Ok(entries)
+}
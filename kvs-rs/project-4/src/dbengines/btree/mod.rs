@@ -0,0 +1,417 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::{KvsEngine, Op, Result};
+use crate::dbengines::{backup, EngineInfo};
+use crate::error::KvsError;
+use crate::error::KvsError::KeyNotFound;
+
+use self::page::{leaf_entry_len, Page, Value, NONE, OVERFLOW_DATA, PAGE_SIZE};
+use self::pager::Pager;
+
+mod page;
+mod pager;
+
+/// entries up to this size stay in their leaf, larger values go to overflow pages
+const MAX_INLINE: usize = 1024;
+/// keys are always inline, so that a page holds at least 3 of them
+const MAX_KEY: usize = 1024;
+
+/// Tunables of a `BTreeEngine`, see `BTreeEngine::open_with`.
+#[derive(Debug, Clone)]
+pub struct BTreeOptions {
+    cache_pages: usize,
+    wal_size: u64,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        BTreeOptions { cache_pages: 1024, wal_size: 16 << 20 }
+    }
+}
+
+impl BTreeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// pages of 4 KiB kept in memory, half of them may be dirty before a checkpoint
+    pub fn cache_pages(mut self, pages: usize) -> Self {
+        self.cache_pages = pages;
+        self
+    }
+
+    /// bytes of write-ahead log allowed before a checkpoint
+    pub fn wal_size(mut self, bytes: u64) -> Self {
+        self.wal_size = bytes;
+        self
+    }
+}
+
+/// A B+tree of fixed-size pages in one file.
+///
+/// Leaves hold the entries in key order and link to the next leaf, so a
+/// scan walks the leaves. Every write is made crash safe by the pager's
+/// write-ahead log. Removes don't merge pages, a leaf emptied by removes
+/// stays in the tree. Lookups whose pages are all cached share the pager,
+/// anything else has it to itself.
+#[derive(Clone, Debug)]
+pub struct BTreeEngine {
+    pager: Arc<RwLock<Pager>>,
+}
+
+impl BTreeEngine {
+    pub fn open(path: &Path) -> Result<BTreeEngine> {
+        Self::open_with(path, BTreeOptions::default())
+    }
+
+    pub fn open_with(path: &Path, options: BTreeOptions) -> Result<BTreeEngine> {
+        fs::create_dir_all(path)?;
+        Ok(BTreeEngine { pager: Arc::new(RwLock::new(Pager::open(path, options)?)) })
+    }
+
+    /// pages in the file, free ones included
+    pub fn pages(&self) -> u32 {
+        self.pager.read().unwrap().pages()
+    }
+
+    /// write every change to the data file, so the log is empty
+    pub fn checkpoint(&self) -> Result<()> {
+        self.pager.write().unwrap().checkpoint()
+    }
+}
+
+impl KvsEngine for BTreeEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if key.len() > MAX_KEY {
+            return Err(KvsError::StringError(format!("key longer than {} bytes", MAX_KEY)));
+        }
+        let mut pager = self.pager.write().unwrap();
+        insert(&mut pager, key, value)?;
+        pager.commit()
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if let Some(value) = lookup(&mut &*self.pager.read().unwrap(), &key)? {
+            return Ok(value);
+        }
+        let mut pager = self.pager.write().unwrap();
+        Ok(lookup(&mut *pager, &key)?.expect("a pager loads every page"))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut pager = self.pager.write().unwrap();
+        let (_, leaf) = find_leaf(&mut pager, &key)?;
+        let page = pager.read(leaf)?;
+        let (entries, next) = as_leaf(&page, leaf)?;
+        let i = entries.binary_search_by(|(k, _)| k.as_str().cmp(&key)).map_err(|_| KeyNotFound)?;
+        let mut entries = entries.clone();
+        let (_, value) = entries.remove(i);
+        free_value(&mut pager, value)?;
+        pager.put(leaf, Page::Leaf { entries, next });
+        pager.commit()
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        if let Some(pairs) = scan_from(&mut &*self.pager.read().unwrap(), &start, limit)? {
+            return Ok(pairs);
+        }
+        let mut pager = self.pager.write().unwrap();
+        Ok(scan_from(&mut *pager, &start, limit)?.expect("a pager loads every page"))
+    }
+
+    /// like sled, writes racing with the backup may or may not be in it
+    fn backup(&self, dest: &Path) -> Result<()> {
+        backup::write_engine(self, dest, "btree")
+    }
+
+    fn restore(src: &Path, path: &Path) -> Result<BTreeEngine> {
+        backup::verify(src)?;
+        let engine = BTreeEngine::open(path)?;
+        if !engine.scan(String::new(), 1)?.is_empty() {
+            return Err(KvsError::StringError(format!("{} already holds data", path.display())));
+        }
+        backup::replay(src, |op| match op {
+            Op::Set { key, value } => engine.set(key, value),
+            Op::Remove { key } => engine.remove(key).or_else(|e| match e {
+                KeyNotFound => Ok(()),
+                e => Err(e),
+            }),
+        })?;
+        engine.checkpoint()?;
+        Ok(engine)
+    }
+
+    /// removed values free their pages as they go, this empties the log
+    fn compact(&self) -> Result<()> {
+        self.checkpoint()
+    }
+
+    /// keys are counted by walking the leaves
+    fn info(&self) -> Result<EngineInfo> {
+        let mut pager = self.pager.write().unwrap();
+        let (_, mut leaf) = find_leaf(&mut pager, "")?;
+        let mut keys = 0;
+        while leaf != NONE {
+            let page = pager.read(leaf)?;
+            let (entries, next) = as_leaf(&page, leaf)?;
+            keys += entries.len() as u64;
+            leaf = next;
+        }
+        Ok(EngineInfo { engine: "btree".to_owned(), keys, disk_bytes: pager.disk_size()?, ..EngineInfo::default() })
+    }
+}
+
+fn corrupted(id: u32) -> KvsError {
+    KvsError::StringError(format!("page {} of the tree is corrupted", id))
+}
+
+/// Where lookups get their pages from: `Pager` loads those it misses,
+/// while `&Pager` only has the cached ones, to read under a shared lock.
+trait Pages {
+    fn root(&self) -> u32;
+    fn page(&mut self, id: u32) -> Result<Option<Arc<Page>>>;
+}
+
+impl Pages for Pager {
+    fn root(&self) -> u32 {
+        Pager::root(self)
+    }
+
+    fn page(&mut self, id: u32) -> Result<Option<Arc<Page>>> {
+        self.read(id).map(Some)
+    }
+}
+
+impl Pages for &Pager {
+    fn root(&self) -> u32 {
+        Pager::root(self)
+    }
+
+    fn page(&mut self, id: u32) -> Result<Option<Arc<Page>>> {
+        Ok(self.cached(id))
+    }
+}
+
+/// page `id` of `pages`, the caller returns `Ok(None)` if it isn't there
+macro_rules! page {
+    ($pages:expr, $id:expr) => {
+        match $pages.page($id)? {
+            Some(page) => page,
+            None => return Ok(None),
+        }
+    };
+}
+
+/// the value of `key`, None if `pages` lacks a page on the way
+fn lookup(pages: &mut impl Pages, key: &str) -> Result<Option<Option<String>>> {
+    let leaf = match descend(pages, key)? {
+        Some((_, leaf)) => leaf,
+        None => return Ok(None),
+    };
+    let page = page!(pages, leaf);
+    let (entries, _) = as_leaf(&page, leaf)?;
+    match entries.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+        Ok(i) => Ok(load_value(pages, &entries[i].1)?.map(Some)),
+        Err(_) => Ok(Some(None)),
+    }
+}
+
+/// see `KvsEngine::scan`, None if `pages` lacks a page on the way
+fn scan_from(pages: &mut impl Pages, start: &str, limit: usize) -> Result<Option<Vec<(String, String)>>> {
+    let mut leaf = match descend(pages, start)? {
+        Some((_, leaf)) => leaf,
+        None => return Ok(None),
+    };
+    let mut pairs = Vec::new();
+    while leaf != NONE && pairs.len() < limit {
+        let page = page!(pages, leaf);
+        let (entries, next) = as_leaf(&page, leaf)?;
+        let from = entries.partition_point(|(k, _)| k.as_str() < start);
+        for (key, value) in entries[from..].iter().take(limit - pairs.len()) {
+            match load_value(pages, value)? {
+                Some(value) => pairs.push((key.clone(), value)),
+                None => return Ok(None),
+            }
+        }
+        leaf = next;
+    }
+    Ok(Some(pairs))
+}
+
+fn as_leaf(page: &Page, id: u32) -> Result<(&Vec<(String, Value)>, u32)> {
+    match page {
+        Page::Leaf { entries, next } => Ok((entries, *next)),
+        _ => Err(corrupted(id)),
+    }
+}
+
+/// branches from the root down, with the child taken in each
+type Branches = Vec<(u32, usize)>;
+
+/// the leaf that holds or would hold `key`, and the branches above it
+fn find_leaf(pager: &mut Pager, key: &str) -> Result<(Branches, u32)> {
+    Ok(descend(pager, key)?.expect("a pager loads every page"))
+}
+
+/// `find_leaf`, None if `pages` lacks a page on the way
+fn descend(pages: &mut impl Pages, key: &str) -> Result<Option<(Branches, u32)>> {
+    let mut path = Vec::new();
+    let mut id = pages.root();
+    loop {
+        match &*page!(pages, id) {
+            Page::Leaf { .. } => return Ok(Some((path, id))),
+            Page::Branch { keys, children } => {
+                let i = keys.partition_point(|k| k.as_str() <= key);
+                path.push((id, i));
+                id = children[i];
+            }
+            _ => return Err(corrupted(id)),
+        }
+    }
+}
+
+fn insert(pager: &mut Pager, key: String, value: String) -> Result<()> {
+    let (mut path, leaf) = find_leaf(pager, &key)?;
+    let page = pager.read(leaf)?;
+    let (entries, next) = as_leaf(&page, leaf)?;
+    let mut entries = entries.clone();
+    let value = store_value(pager, &key, value)?;
+    match entries.binary_search_by(|(k, _)| k.as_str().cmp(&key)) {
+        Ok(i) => {
+            let old = std::mem::replace(&mut entries[i].1, value);
+            free_value(pager, old)?;
+        }
+        Err(i) => entries.insert(i, (key, value)),
+    }
+    let page = Page::Leaf { entries, next };
+    if page.encoded_len() <= PAGE_SIZE {
+        pager.put(leaf, page);
+        return Ok(());
+    }
+
+    // move the upper half of the leaf to a new right sibling
+    let mut entries = match page {
+        Page::Leaf { entries, .. } => entries,
+        _ => unreachable!(),
+    };
+    let sizes: Vec<usize> = entries.iter().map(|(k, v)| leaf_entry_len(k, v)).collect();
+    let at = split_point(&sizes);
+    let right_entries = entries.split_off(at);
+    let right = pager.allocate()?;
+    let mut sep = right_entries[0].0.clone();
+    pager.put(leaf, Page::Leaf { entries, next: right });
+    pager.put(right, Page::Leaf { entries: right_entries, next });
+
+    // and add the separator to the parents, splitting them as needed
+    let (mut left, mut right) = (leaf, right);
+    while let Some((parent, i)) = path.pop() {
+        let page = pager.read(parent)?;
+        let (mut keys, mut children) = match &*page {
+            Page::Branch { keys, children } => (keys.clone(), children.clone()),
+            _ => return Err(corrupted(parent)),
+        };
+        keys.insert(i, sep);
+        children.insert(i + 1, right);
+        let page = Page::Branch { keys, children };
+        if page.encoded_len() <= PAGE_SIZE {
+            pager.put(parent, page);
+            return Ok(());
+        }
+        let (mut keys, mut children) = match page {
+            Page::Branch { keys, children } => (keys, children),
+            _ => unreachable!(),
+        };
+        let sizes: Vec<usize> = keys.iter().map(|k| 8 + k.len()).collect();
+        let mid = split_point(&sizes).min(keys.len() - 2);
+        let right_keys = keys.split_off(mid + 1);
+        let right_children = children.split_off(mid + 1);
+        sep = keys.pop().unwrap();
+        left = parent;
+        right = pager.allocate()?;
+        pager.put(left, Page::Branch { keys, children });
+        pager.put(right, Page::Branch { keys: right_keys, children: right_children });
+    }
+
+    // the root split, the tree grows a level
+    let root = pager.allocate()?;
+    pager.put(root, Page::Branch { keys: vec![sep], children: vec![left, right] });
+    pager.set_root(root);
+    Ok(())
+}
+
+/// the index splitting items of `sizes` into two halves of about the same bytes,
+/// both halves hold at least one item
+fn split_point(sizes: &[usize]) -> usize {
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut acc = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        acc += size;
+        if acc >= half {
+            return (i + 1).clamp(1, sizes.len() - 1);
+        }
+    }
+    sizes.len() - 1
+}
+
+/// keep `value` inline when the entry is small, else write it to a chain of overflow pages
+fn store_value(pager: &mut Pager, key: &str, value: String) -> Result<Value> {
+    let inline = Value::Inline(value);
+    if leaf_entry_len(key, &inline) <= MAX_INLINE {
+        return Ok(inline);
+    }
+    let value = match inline {
+        Value::Inline(value) => value.into_bytes(),
+        _ => unreachable!(),
+    };
+    let chunks: Vec<&[u8]> = value.chunks(OVERFLOW_DATA).collect();
+    let mut ids = Vec::with_capacity(chunks.len());
+    for _ in 0..chunks.len() {
+        ids.push(pager.allocate()?);
+    }
+    for (i, chunk) in chunks.iter().enumerate() {
+        let next = ids.get(i + 1).copied().unwrap_or(NONE);
+        pager.put(ids[i], Page::Overflow { next, data: chunk.to_vec() });
+    }
+    Ok(Value::Overflow { first: ids[0], len: value.len() as u32 })
+}
+
+/// the string `value` holds, None if `pages` lacks one of its overflow pages
+fn load_value(pages: &mut impl Pages, value: &Value) -> Result<Option<String>> {
+    let (mut id, len) = match value {
+        Value::Inline(value) => return Ok(Some(value.clone())),
+        Value::Overflow { first, len } => (*first, *len as usize),
+    };
+    let mut data = Vec::with_capacity(len);
+    while id != NONE {
+        match &*page!(pages, id) {
+            Page::Overflow { next, data: chunk } => {
+                data.extend_from_slice(chunk);
+                id = *next;
+            }
+            _ => return Err(corrupted(id)),
+        }
+    }
+    if data.len() != len {
+        return Err(KvsError::StringError(format!("an overflow chain holds {} bytes instead of {}", data.len(), len)));
+    }
+    String::from_utf8(data).map(Some).map_err(|e| KvsError::StringError(e.to_string()))
+}
+
+/// give the overflow pages of `value` back to the free list
+fn free_value(pager: &mut Pager, value: Value) -> Result<()> {
+    let mut id = match value {
+        Value::Inline(_) => return Ok(()),
+        Value::Overflow { first, .. } => first,
+    };
+    while id != NONE {
+        let next = match &*pager.read(id)? {
+            Page::Overflow { next, .. } => *next,
+            _ => return Err(corrupted(id)),
+        };
+        pager.release(id);
+        id = next;
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::convert::TryInto;
+
+pub const PAGE_SIZE: usize = 4096;
+/// the last 4 bytes of a page hold the crc32 of the rest
+const BODY_SIZE: usize = PAGE_SIZE - 4;
+/// bytes of an overflow page left for data, after the tag, next and length
+pub const OVERFLOW_DATA: usize = BODY_SIZE - 9;
+/// page ids start at 1, the meta page is never a child or a link
+pub const NONE: u32 = 0;
+
+const META: u8 = 1;
+const LEAF: u8 = 2;
+const BRANCH: u8 = 3;
+const OVERFLOW: u8 = 4;
+const FREE: u8 = 5;
+
+/// A value kept in its leaf, or in a chain of overflow pages when large.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Inline(String),
+    Overflow { first: u32, len: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Page {
+    /// page 0: the root, the number of pages in the file and the first free page
+    Meta { root: u32, pages: u32, free: u32 },
+    /// sorted entries and the next leaf in key order
+    Leaf { entries: Vec<(String, Value)>, next: u32 },
+    /// `children[i]` holds the keys below `keys[i]`, the last child the rest
+    Branch { keys: Vec<String>, children: Vec<u32> },
+    Overflow { next: u32, data: Vec<u8> },
+    Free { next: u32 },
+}
+
+impl Page {
+    /// bytes the page takes encoded, it fits when at most `PAGE_SIZE`
+    pub fn encoded_len(&self) -> usize {
+        let body = match self {
+            Page::Meta { .. } => 12,
+            Page::Leaf { entries, .. } => 8 + entries.iter().map(|(k, v)| leaf_entry_len(k, v)).sum::<usize>(),
+            Page::Branch { keys, children } => 4 + keys.iter().map(|k| 4 + k.len()).sum::<usize>() + 4 * children.len(),
+            Page::Overflow { data, .. } => 8 + data.len(),
+            Page::Free { .. } => 4,
+        };
+        1 + body + 4
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Page::Meta { root, pages, free } => {
+                buf.push(META);
+                put_u32(&mut buf, *root);
+                put_u32(&mut buf, *pages);
+                put_u32(&mut buf, *free);
+            }
+            Page::Leaf { entries, next } => {
+                buf.push(LEAF);
+                put_u32(&mut buf, *next);
+                put_u32(&mut buf, entries.len() as u32);
+                for (key, value) in entries {
+                    put_str(&mut buf, key);
+                    match value {
+                        Value::Inline(value) => {
+                            buf.push(0);
+                            put_str(&mut buf, value);
+                        }
+                        Value::Overflow { first, len } => {
+                            buf.push(1);
+                            put_u32(&mut buf, *first);
+                            put_u32(&mut buf, *len);
+                        }
+                    }
+                }
+            }
+            Page::Branch { keys, children } => {
+                buf.push(BRANCH);
+                put_u32(&mut buf, keys.len() as u32);
+                for key in keys {
+                    put_str(&mut buf, key);
+                }
+                for child in children {
+                    put_u32(&mut buf, *child);
+                }
+            }
+            Page::Overflow { next, data } => {
+                buf.push(OVERFLOW);
+                put_u32(&mut buf, *next);
+                put_u32(&mut buf, data.len() as u32);
+                buf.extend_from_slice(data);
+            }
+            Page::Free { next } => {
+                buf.push(FREE);
+                put_u32(&mut buf, *next);
+            }
+        }
+        assert!(buf.len() <= BODY_SIZE, "page of {} bytes", buf.len());
+        buf.resize(BODY_SIZE, 0);
+        let crc = crc32fast::hash(&buf);
+        put_u32(&mut buf, crc);
+        buf
+    }
+
+    /// `None` when the checksum or the layout is wrong
+    pub fn decode(data: &[u8]) -> Option<Page> {
+        if data.len() != PAGE_SIZE || crc32fast::hash(&data[..BODY_SIZE]) != u32::from_le_bytes(data[BODY_SIZE..].try_into().ok()?) {
+            return None;
+        }
+        let mut data = &data[..BODY_SIZE];
+        let page = match take(&mut data, 1)?[0] {
+            META => Page::Meta { root: u32(&mut data)?, pages: u32(&mut data)?, free: u32(&mut data)? },
+            LEAF => {
+                let next = u32(&mut data)?;
+                let n = u32(&mut data)?;
+                let mut entries = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    let key = string(&mut data)?;
+                    let value = match take(&mut data, 1)?[0] {
+                        0 => Value::Inline(string(&mut data)?),
+                        1 => Value::Overflow { first: u32(&mut data)?, len: u32(&mut data)? },
+                        _ => return None,
+                    };
+                    entries.push((key, value));
+                }
+                Page::Leaf { entries, next }
+            }
+            BRANCH => {
+                let n = u32(&mut data)?;
+                let mut keys = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    keys.push(string(&mut data)?);
+                }
+                let mut children = Vec::with_capacity(n as usize + 1);
+                for _ in 0..=n {
+                    children.push(u32(&mut data)?);
+                }
+                Page::Branch { keys, children }
+            }
+            OVERFLOW => {
+                let next = u32(&mut data)?;
+                let len = u32(&mut data)? as usize;
+                Page::Overflow { next, data: take(&mut data, len)?.to_vec() }
+            }
+            FREE => Page::Free { next: u32(&mut data)? },
+            _ => return None,
+        };
+        Some(page)
+    }
+}
+
+pub fn leaf_entry_len(key: &str, value: &Value) -> usize {
+    4 + key.len() + 1 + match value {
+        Value::Inline(value) => 4 + value.len(),
+        Value::Overflow { .. } => 8,
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if data.len() < n {
+        return None;
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Some(head)
+}
+
+fn u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?))
+}
+
+fn string(data: &mut &[u8]) -> Option<String> {
+    let len = u32(data)? as usize;
+    String::from_utf8(take(data, len)?.to_vec()).ok()
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+
+use crate::Result;
+use crate::dbengines::btree::BTreeOptions;
+use crate::dbengines::btree::page::{Page, NONE, PAGE_SIZE};
+use crate::error::KvsError;
+
+const DATA: &str = "btree.db";
+const WAL: &str = "btree.wal";
+
+#[derive(Debug)]
+struct Frame {
+    page: Arc<Page>,
+    /// the key of the frame in `lru`
+    queued: u64,
+    /// the last use, which may be after `queued` as hits don't requeue
+    used: AtomicU64,
+}
+
+impl Frame {
+    fn new(page: Arc<Page>, tick: u64) -> Frame {
+        Frame { page, queued: tick, used: AtomicU64::new(tick) }
+    }
+}
+
+/// The pages of the tree file behind a buffer pool with LRU eviction.
+///
+/// Pages changed by a write are appended to a write-ahead log as whole
+/// page images on `commit`, and only reach the data file at a checkpoint,
+/// so a dirty page is never evicted and a crash leaves the data file as of
+/// the last checkpoint plus the log to redo.
+///
+/// Cached pages can be read through a shared reference with `cached`, so
+/// lookups run in parallel; loading and evicting pages takes `&mut self`.
+#[derive(Debug)]
+pub struct Pager {
+    file: File,
+    wal: BufWriter<File>,
+    wal_size: u64,
+    options: BTreeOptions,
+    frames: HashMap<u32, Frame>,
+    /// frames by the tick they were queued at, see `evict`
+    lru: BTreeMap<u64, u32>,
+    tick: AtomicU64,
+    dirty: BTreeSet<u32>,
+    /// pages changed since the last commit
+    touched: BTreeSet<u32>,
+    root: u32,
+    pages: u32,
+    free: u32,
+}
+
+impl Pager {
+    pub fn open(path: &Path, options: BTreeOptions) -> Result<Pager> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path.join(DATA))?;
+        if file.metadata()?.len() == 0 {
+            write_page(&mut file, 0, &Page::Meta { root: 1, pages: 2, free: NONE })?;
+            write_page(&mut file, 1, &Page::Leaf { entries: Vec::new(), next: NONE })?;
+            file.sync_all()?;
+        }
+        redo(&mut file, &path.join(WAL))?;
+        let (root, pages, free) = match read_page(&mut file, 0)? {
+            Page::Meta { root, pages, free } => (root, pages, free),
+            _ => return Err(corrupted(0)),
+        };
+        let wal = BufWriter::new(OpenOptions::new().create(true).append(true).open(path.join(WAL))?);
+        Ok(Pager {
+            file,
+            wal,
+            wal_size: 0,
+            options,
+            frames: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: AtomicU64::new(0),
+            dirty: BTreeSet::new(),
+            touched: BTreeSet::new(),
+            root,
+            pages,
+            free,
+        })
+    }
+
+    pub fn root(&self) -> u32 {
+        self.root
+    }
+
+    pub fn set_root(&mut self, root: u32) {
+        self.root = root;
+        self.put_meta();
+    }
+
+    /// pages in the file, free ones included
+    pub fn pages(&self) -> u32 {
+        self.pages
+    }
+
+    /// bytes of the data file and the log
+    pub fn disk_size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len() + self.wal.get_ref().metadata()?.len())
+    }
+
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// page `id` if it is in the pool
+    pub fn cached(&self, id: u32) -> Option<Arc<Page>> {
+        let frame = self.frames.get(&id)?;
+        frame.used.store(self.next_tick(), Ordering::Relaxed);
+        Some(frame.page.clone())
+    }
+
+    pub fn read(&mut self, id: u32) -> Result<Arc<Page>> {
+        if let Some(page) = self.cached(id) {
+            return Ok(page);
+        }
+        if id >= self.pages {
+            return Err(corrupted(id));
+        }
+        let page = Arc::new(read_page(&mut self.file, id)?);
+        let tick = self.next_tick();
+        self.frames.insert(id, Frame::new(page.clone(), tick));
+        self.lru.insert(tick, id);
+        self.evict();
+        Ok(page)
+    }
+
+    /// replace page `id`, the change is logged by the next `commit`
+    pub fn put(&mut self, id: u32, page: Page) {
+        let tick = self.next_tick();
+        if let Some(old) = self.frames.insert(id, Frame::new(Arc::new(page), tick)) {
+            self.lru.remove(&old.queued);
+        }
+        self.lru.insert(tick, id);
+        self.dirty.insert(id);
+        self.touched.insert(id);
+    }
+
+    /// a page for the caller to `put`, taken from the free list or added to the file
+    pub fn allocate(&mut self) -> Result<u32> {
+        let id = if self.free != NONE {
+            let id = self.free;
+            self.free = match *self.read(id)? {
+                Page::Free { next } => next,
+                _ => return Err(corrupted(id)),
+            };
+            id
+        } else {
+            self.pages += 1;
+            self.pages - 1
+        };
+        self.put_meta();
+        Ok(id)
+    }
+
+    pub fn release(&mut self, id: u32) {
+        self.put(id, Page::Free { next: self.free });
+        self.free = id;
+        self.put_meta();
+    }
+
+    fn put_meta(&mut self) {
+        self.put(0, Page::Meta { root: self.root, pages: self.pages, free: self.free });
+    }
+
+    /// append the pages changed by a write to the log as one batch:
+    /// the page count, every page id and image, and a crc32 of all that
+    pub fn commit(&mut self) -> Result<()> {
+        if self.touched.is_empty() {
+            return Ok(());
+        }
+        let mut batch = Vec::with_capacity(4 + self.touched.len() * (4 + PAGE_SIZE) + 4);
+        batch.extend_from_slice(&(self.touched.len() as u32).to_le_bytes());
+        for id in std::mem::take(&mut self.touched) {
+            batch.extend_from_slice(&id.to_le_bytes());
+            batch.extend_from_slice(&self.frames[&id].page.encode());
+        }
+        let crc = crc32fast::hash(&batch);
+        batch.extend_from_slice(&crc.to_le_bytes());
+        self.wal.write_all(&batch)?;
+        self.wal.flush()?;
+        self.wal.get_ref().sync_data()?;
+        self.wal_size += batch.len() as u64;
+        if self.dirty.len() > self.options.cache_pages / 2 || self.wal_size >= self.options.wal_size {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// write the dirty pages to the data file and empty the log
+    pub fn checkpoint(&mut self) -> Result<()> {
+        self.wal.flush()?;
+        self.wal.get_ref().sync_data()?;
+        for id in std::mem::take(&mut self.dirty) {
+            write_page(&mut self.file, id, &self.frames[&id].page)?;
+        }
+        self.file.sync_data()?;
+        self.wal.get_ref().set_len(0)?;
+        self.wal_size = 0;
+        self.evict();
+        Ok(())
+    }
+
+    /// Drop the least recently used clean pages beyond the pool size. A
+    /// page used since it was queued goes to the back of the queue instead.
+    fn evict(&mut self) {
+        let mut excess = self.frames.len().saturating_sub(self.options.cache_pages);
+        let mut from = 0;
+        while excess > 0 {
+            let (tick, id) = match self.lru.range(from..).find(|(_, id)| !self.dirty.contains(id)) {
+                Some((tick, id)) => (*tick, *id),
+                None => return,
+            };
+            self.lru.remove(&tick);
+            let frame = self.frames.get_mut(&id).unwrap();
+            let used = *frame.used.get_mut();
+            if used > tick {
+                frame.queued = used;
+                self.lru.insert(used, id);
+            } else {
+                self.frames.remove(&id);
+                excess -= 1;
+            }
+            from = tick + 1;
+        }
+    }
+}
+
+fn corrupted(id: u32) -> KvsError {
+    KvsError::StringError(format!("page {} of {} is corrupted", id, DATA))
+}
+
+fn read_page(file: &mut File, id: u32) -> Result<Page> {
+    let mut data = vec![0; PAGE_SIZE];
+    file.seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))?;
+    file.read_exact(&mut data)?;
+    Page::decode(&data).ok_or_else(|| corrupted(id))
+}
+
+fn write_page(file: &mut File, id: u32, page: &Page) -> Result<()> {
+    file.seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))?;
+    file.write_all(&page.encode())?;
+    Ok(())
+}
+
+/// write the pages of every whole batch in the log to the data file,
+/// a torn or corrupted batch ends the log
+fn redo(file: &mut File, wal: &Path) -> Result<()> {
+    let data = match std::fs::read(wal) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut off = 0;
+    let mut batches = 0;
+    while off + 4 <= data.len() {
+        let n = u32::from_le_bytes(data[off..off + 4].try_into().unwrap()) as usize;
+        let end = off + 4 + n * (4 + PAGE_SIZE);
+        if n == 0 || end + 4 > data.len()
+            || crc32fast::hash(&data[off..end]) != u32::from_le_bytes(data[end..end + 4].try_into().unwrap()) {
+            warn!("ignore the end of {} at byte {}", WAL, off);
+            break;
+        }
+        for i in 0..n {
+            let at = off + 4 + i * (4 + PAGE_SIZE);
+            let id = u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            file.seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))?;
+            file.write_all(&data[at + 4..at + 4 + PAGE_SIZE])?;
+        }
+        off = end + 4;
+        batches += 1;
+    }
+    if batches > 0 {
+        file.sync_data()?;
+    }
+    OpenOptions::new().write(true).open(wal)?.set_len(0)?;
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use crate::dbengines::common::Pos;
+use crate::utils::fnv1a;
+
+const SHARDS: usize = 16;
+/// bytes counted for an entry on top of its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Hits and misses of a `KvStore` value cache since it was opened.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// bytes held, an estimate
+    pub bytes: u64,
+}
+
+impl CacheStats {
+    /// hits over lookups, 0 before the first lookup
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// A size-bounded LRU of values by key, shared by every clone of a store.
+///
+/// An entry remembers the position its value was read at and only hits
+/// while the index still points there, so a get racing with a set can never
+/// bring back the old value. Keys are spread over shards, each with its own
+/// lock and a share of the capacity.
+#[derive(Debug)]
+pub struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+    /// keys by last use, the first is evicted first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+    capacity: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    pos: Pos,
+    value: String,
+    tick: u64,
+}
+
+impl Shard {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            let key = self.lru.remove(&entry.tick).unwrap();
+            entry.tick = tick;
+            self.lru.insert(tick, key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.bytes -= size(key, &entry.value);
+        }
+    }
+}
+
+fn size(key: &str, value: &str) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> ValueCache {
+        let capacity = capacity / SHARDS;
+        ValueCache {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard { capacity, ..Shard::default() })).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        &self.shards[fnv1a(key.as_bytes()) as usize % SHARDS]
+    }
+
+    /// the value of `key`, if it was cached when read at `pos`
+    pub fn get(&self, key: &str, pos: &Pos) -> Option<String> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let value = match shard.entries.get(key) {
+            Some(entry) if entry.pos == *pos => Some(entry.value.clone()),
+            _ => None,
+        };
+        match value {
+            Some(_) => {
+                shard.touch(key);
+                self.hits.fetch_add(1, Relaxed);
+            }
+            None => {
+                self.misses.fetch_add(1, Relaxed);
+            }
+        }
+        value
+    }
+
+    /// cache `value`, read at `pos`, evicting the least recently used
+    /// values of the shard past its capacity
+    pub fn insert(&self, key: String, pos: Pos, value: String) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        let bytes = size(&key, &value);
+        if bytes > shard.capacity {
+            return;
+        }
+        shard.remove(&key);
+        while shard.bytes + bytes > shard.capacity {
+            let (_, oldest) = shard.lru.pop_first().unwrap();
+            let entry = shard.entries.remove(&oldest).unwrap();
+            shard.bytes -= size(&oldest, &entry.value);
+        }
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.lru.insert(tick, key.clone());
+        shard.entries.insert(key, Entry { pos, value, tick });
+        shard.bytes += bytes;
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            shard.entries.clear();
+            shard.lru.clear();
+            shard.bytes = 0;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            bytes: self.shards.iter().map(|s| s.lock().unwrap().bytes as u64).sum(),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::dbengines::common::Pos;
+use crate::dbengines::index::IndexMode;
+
+const CHECKPOINT: &str = "index.checkpoint";
+
+/// The whole index of a `KvStore` as of a position in its active log.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// the active log when the checkpoint was taken
+    pub id: u16,
+    /// bytes of the active log covered, older logs are covered entirely
+    pub file_pos: u32,
+    pub un_compact: u64,
+    #[serde(default)]
+    pub mode: IndexMode,
+    pub entries: Vec<(String, Pos)>,
+    /// entries by key hash, in the hashed index mode
+    #[serde(default)]
+    pub hashes: Vec<(u64, Pos)>,
+}
+
+pub fn checkpoint_path(path: &Path) -> PathBuf {
+    path.join(CHECKPOINT)
+}
+
+/// Written aside, synced and renamed, then the directory is synced, so a
+/// crash leaves either the previous checkpoint or the new one.
+pub fn write(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let tmp = path.join(format!("{}.tmp", CHECKPOINT));
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, checkpoint)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp, checkpoint_path(path))?;
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// the checkpoint of `path`, `None` if there is none or it can't be read
+pub fn read(path: &Path) -> Option<Checkpoint> {
+    let data = fs::read(checkpoint_path(path)).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(checkpoint) => Some(checkpoint),
+        Err(e) => {
+            warn!("ignore unreadable index checkpoint: {}", e);
+            None
+        }
+    }
+}
+
+pub fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(checkpoint_path(path)) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
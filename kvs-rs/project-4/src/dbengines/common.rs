@@ -1,22 +1,93 @@
 use serde::{Deserialize, Serialize};
 
-/// to write in file
-#[derive(Serialize, Deserialize)]
+use crate::Result;
+use crate::dbengines::compress::Codec;
+use crate::error::KvsError;
+
+/// a mutation, as written in the log file
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Op {
     Set { key: String, value: String },
     Remove { key: String },
 }
 
+/// A `KvStore` log record: a set or remove written exactly as its `Op`,
+/// or a set whose value is compressed or kept in a blob file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Record {
+    Set { key: String, value: String },
+    Remove { key: String },
+    /// `value` is compressed with `codec`, see `Codec::compress`
+    Packed { key: String, codec: Codec, value: String },
+    /// the value is in the record of `len` bytes at `off` of blob file `blob`,
+    /// see `blob::resolve`
+    Blob { key: String, blob: u32, off: u64, len: u32 },
+    /// another record encrypted with the key `key_id`, see `Keyring`.
+    /// Records are unsealed as they are read, the methods below expect that.
+    Sealed { key_id: u32, nonce: String, data: String },
+}
+
+impl Record {
+    /// the record of `op`, with the value compressed when it is at least
+    /// `threshold` bytes and compressing makes the record smaller
+    pub fn pack(op: Op, codec: Option<Codec>, threshold: usize) -> Result<Record> {
+        match (op, codec) {
+            (Op::Set { key, value }, Some(codec)) if value.len() >= threshold => {
+                let packed = codec.compress(value.as_bytes())?;
+                if packed.len() < value.len() {
+                    Ok(Record::Packed { key, codec, value: packed })
+                } else {
+                    Ok(Record::Set { key, value })
+                }
+            }
+            (op, _) => Ok(op.into()),
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Record::Set { key, .. } | Record::Remove { key } | Record::Packed { key, .. } | Record::Blob { key, .. } => key,
+            Record::Sealed { .. } => unreachable!("sealed records are unsealed when read"),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        !matches!(self, Record::Remove { .. })
+    }
+
+    /// the op of the record, decompressing its value.
+    /// Blob records must be resolved first.
+    pub fn into_op(self) -> Result<Op> {
+        Ok(match self {
+            Record::Set { key, value } => Op::Set { key, value },
+            Record::Remove { key } => Op::Remove { key },
+            Record::Packed { key, codec, value } => Op::Set { key, value: String::from_utf8(codec.decompress(&value)?)? },
+            Record::Blob { key, blob, .. } => return Err(KvsError::StringError(format!("value of {} is in {}.blob", key, blob))),
+            Record::Sealed { key_id, .. } => return Err(KvsError::WrongKey(key_id)),
+        })
+    }
+}
+
+impl From<Op> for Record {
+    fn from(op: Op) -> Record {
+        match op {
+            Op::Set { key, value } => Record::Set { key, value },
+            Op::Remove { key } => Record::Remove { key },
+        }
+    }
+}
+
 /// one line data index, TODO to in u64 ,key v spit to store
-#[derive(Debug)]
+/// the size is u32 like the offset, a sealed or compressed record can pass 64 KiB
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Pos {
     pub id: u16,
     pub off: u32,
-    pub size: u16,
+    pub size: u32,
 }
 
 impl Pos {
-    pub fn new(id: u16, off: u32, size: u16) -> Self {
+    pub fn new(id: u16, off: u32, size: u32) -> Self {
         Pos { id, off, size }
     }
 }
@@ -0,0 +1,115 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// the dead bytes of the default policy, the threshold `KvStore` always had
+const DEFAULT_DEAD_BYTES: u64 = 1024 * 1024;
+/// a ratio trigger waits for this many dead bytes, so a small store is not
+/// rewritten on every overwrite
+const MIN_RATIO_DEAD_BYTES: u64 = 64 * 1024;
+
+/// When a `KvStore` compacts its logs by itself, see `KvStoreOptions::compaction`.
+///
+/// `new` has no trigger, so the store only compacts on `KvsEngine::compact`.
+/// With several triggers the store compacts once any of them fires, and with
+/// a window only inside it. The default compacts past 1 MiB of dead bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPolicy {
+    dead_bytes: Option<u64>,
+    dead_ratio: Option<f64>,
+    every: Option<Duration>,
+    window: Option<(u8, u8)>,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::new().dead_bytes(DEFAULT_DEAD_BYTES)
+    }
+}
+
+impl CompactionPolicy {
+    pub fn new() -> Self {
+        CompactionPolicy { dead_bytes: None, dead_ratio: None, every: None, window: None }
+    }
+
+    /// compact once the dead records add up to more than `bytes`
+    pub fn dead_bytes(mut self, bytes: u64) -> Self {
+        self.dead_bytes = Some(bytes);
+        self
+    }
+
+    /// compact once dead records are more than `ratio` of the log bytes,
+    /// and at least 64 KiB
+    pub fn dead_ratio(mut self, ratio: f64) -> Self {
+        self.dead_ratio = Some(ratio);
+        self
+    }
+
+    /// compact `interval` after the last compaction, if anything is dead
+    pub fn every(mut self, interval: Duration) -> Self {
+        self.every = Some(interval);
+        self
+    }
+
+    /// Only compact by itself from `start` to `end` o'clock UTC, the window
+    /// wraps around midnight when `end` is before `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an hour is past 23.
+    pub fn window(mut self, start: u8, end: u8) -> Self {
+        assert!(start < 24 && end < 24, "hours of the compaction window must be below 24");
+        self.window = Some((start, end));
+        self
+    }
+
+    /// whether the triggers can fire without a write, so the store
+    /// has to check on a timer
+    pub(crate) fn timed(&self) -> bool {
+        self.every.is_some() || self.window.is_some()
+    }
+
+    /// whether a store with `dead` of its `total` log bytes dead, compacted
+    /// `since` ago, should compact at `now`
+    pub(crate) fn due(&self, dead: u64, total: u64, since: Duration, now: SystemTime) -> bool {
+        if let Some((start, end)) = self.window {
+            let hour = (now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 3600 % 24) as u8;
+            let inside = match start <= end {
+                true => start <= hour && hour < end,
+                false => hour >= start || hour < end,
+            };
+            if !inside {
+                return false;
+            }
+        }
+        self.dead_bytes.is_some_and(|bytes| dead > bytes)
+            || self.dead_ratio.is_some_and(|ratio| dead >= MIN_RATIO_DEAD_BYTES && dead as f64 > ratio * total as f64)
+            || self.every.is_some_and(|every| dead > 0 && since >= every)
+    }
+}
+
+/// Keeps a writer at `rate` bytes per second on average, by sleeping.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    rate: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    /// `None` never sleeps
+    pub fn new(rate: Option<u64>) -> Throttle {
+        Throttle { rate, start: Instant::now(), bytes: 0 }
+    }
+
+    /// account for `bytes` written, sleeping until they are due
+    pub fn consume(&mut self, bytes: u64) {
+        if let Some(rate) = self.rate {
+            self.bytes += bytes;
+            let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
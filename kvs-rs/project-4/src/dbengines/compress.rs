@@ -0,0 +1,54 @@
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::error::KvsError;
+
+/// A compression codec for the values in `KvStore` logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    Lz4,
+    Snappy,
+    Zstd,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Codec, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "lz4" => Ok(Codec::Lz4),
+            "snappy" => Ok(Codec::Snappy),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(format!("unknown codec {}, expected lz4, snappy or zstd", s)),
+        }
+    }
+}
+
+impl Codec {
+    /// `data` compressed, in base64 so it can be a JSON string
+    pub fn compress(self, data: &[u8]) -> Result<String> {
+        let packed = match self {
+            Codec::Lz4 => lz4_flex::compress_prepend_size(data),
+            Codec::Snappy => snap::raw::Encoder::new().compress_vec(data).map_err(corrupted)?,
+            Codec::Zstd => zstd::bulk::compress(data, 3)?,
+        };
+        Ok(STANDARD.encode(packed))
+    }
+
+    pub fn decompress(self, packed: &str) -> Result<Vec<u8>> {
+        let packed = STANDARD.decode(packed).map_err(corrupted)?;
+        match self {
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(&packed).map_err(corrupted),
+            Codec::Snappy => snap::raw::Decoder::new().decompress_vec(&packed).map_err(corrupted),
+            Codec::Zstd => Ok(zstd::stream::decode_all(packed.as_slice())?),
+        }
+    }
+}
+
+fn corrupted<E: std::fmt::Display>(e: E) -> KvsError {
+    KvsError::StringError(format!("corrupted compressed value: {}", e))
+}
@@ -0,0 +1,116 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use crate::Result;
+use crate::dbengines::common::Record;
+use crate::error::KvsError;
+
+/// where `Keyring::from_env` looks for keys
+pub const KEYS_ENV: &str = "KVS_ENCRYPTION_KEYS";
+
+/// The AES-256-GCM keys of a store, by id.
+///
+/// Records are sealed with the active key, the one with the highest id,
+/// and opened with the key they name. To rotate, add a key with a higher
+/// id: compaction seals every live record again with it, after which the
+/// older keys can be dropped. Values in blob files are only read back and
+/// sealed again while the keyring holds more than one key.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<(u32, Aes256Gcm)>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring").field("ids", &self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>()).finish()
+    }
+}
+
+impl Keyring {
+    /// `ID:KEY` entries separated by commas or new lines, each key is 64 hex digits
+    pub fn parse(s: &str) -> Result<Keyring> {
+        let mut keys = Vec::new();
+        for entry in s.split([',', '\n']).map(str::trim).filter(|e| !e.is_empty()) {
+            let invalid = || KvsError::StringError("invalid encryption key entry, expected ID:KEY with a key of 64 hex digits".to_owned());
+            let mut parts = entry.splitn(2, ':');
+            let id: u32 = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+            let bytes = hex::decode(parts.next().ok_or_else(invalid)?).map_err(|_| invalid())?;
+            if bytes.len() != 32 {
+                return Err(invalid());
+            }
+            keys.push((id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes))));
+        }
+        if keys.is_empty() {
+            return Err(KvsError::StringError("no encryption key given".to_owned()));
+        }
+        keys.sort_by_key(|(id, _)| *id);
+        Ok(Keyring { keys })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Keyring> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// the keys in `KVS_ENCRYPTION_KEYS`, if it is set
+    pub fn from_env() -> Result<Option<Keyring>> {
+        match env::var(KEYS_ENV) {
+            Ok(s) => Ok(Some(Self::parse(&s)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// whether older keys are kept next to the active one
+    pub(crate) fn rotating(&self) -> bool {
+        self.keys.len() > 1
+    }
+
+    /// id of the key new records are sealed with
+    pub fn active(&self) -> u32 {
+        self.keys.last().unwrap().0
+    }
+
+    pub(crate) fn seal(&self, record: &Record) -> Result<Record> {
+        let (id, cipher) = self.keys.last().unwrap();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plain = serde_json::to_vec(record)?;
+        let data = cipher.encrypt(&nonce, Payload { msg: &plain, aad: &id.to_le_bytes() })
+            .map_err(|_| KvsError::StringError("encryption failed".to_owned()))?;
+        Ok(Record::Sealed { key_id: *id, nonce: STANDARD.encode(nonce), data: STANDARD.encode(data) })
+    }
+
+    fn open(&self, key_id: u32, nonce: &str, data: &str) -> Result<Record> {
+        let cipher = match self.keys.iter().find(|(id, _)| *id == key_id) {
+            Some((_, cipher)) => cipher,
+            None => return Err(KvsError::WrongKey(key_id)),
+        };
+        let nonce = STANDARD.decode(nonce).map_err(|_| KvsError::WrongKey(key_id))?;
+        let data = STANDARD.decode(data).map_err(|_| KvsError::WrongKey(key_id))?;
+        if nonce.len() != 12 {
+            return Err(KvsError::WrongKey(key_id));
+        }
+        let plain = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &data, aad: &key_id.to_le_bytes() })
+            .map_err(|_| KvsError::WrongKey(key_id))?;
+        match serde_json::from_slice(&plain)? {
+            Record::Sealed { .. } => Err(KvsError::WrongKey(key_id)),
+            record => Ok(record),
+        }
+    }
+}
+
+/// `record` decrypted if it is sealed, which needs the key it was sealed with
+pub(crate) fn unseal(record: Record, keys: Option<&Keyring>) -> Result<Record> {
+    match record {
+        Record::Sealed { key_id, nonce, data } => match keys {
+            Some(keys) => keys.open(key_id, &nonce, &data),
+            None => Err(KvsError::WrongKey(key_id)),
+        },
+        record => Ok(record),
+    }
+}
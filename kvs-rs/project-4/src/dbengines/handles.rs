@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::Result;
+use crate::utils::format_path;
+
+/// Open log files of a `KvStore`, shared by every clone and at most
+/// `capacity` of them, the least recently used is closed first.
+///
+/// Reads are positional, so any number of threads read through the same
+/// handle without seeking it. A handle evicted or released while a read
+/// uses it is closed once that read is done.
+#[derive(Debug)]
+pub struct HandlePool {
+    capacity: usize,
+    inner: Mutex<Handles>,
+}
+
+#[derive(Debug, Default)]
+struct Handles {
+    files: HashMap<u16, (Arc<File>, u64)>,
+    tick: u64,
+}
+
+impl HandlePool {
+    pub fn new(capacity: usize) -> HandlePool {
+        HandlePool { capacity: capacity.max(1), inner: Mutex::new(Handles::default()) }
+    }
+
+    /// the handle of log `id` of `path`, opened if it is not in the pool
+    pub fn get(&self, path: &Path, id: u16) -> Result<Arc<File>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((file, used)) = inner.files.get_mut(&id) {
+            *used = tick;
+            return Ok(file.clone());
+        }
+        if inner.files.len() >= self.capacity {
+            let oldest = inner.files.iter().min_by_key(|(_, (_, used))| *used).map(|(id, _)| *id).unwrap();
+            inner.files.remove(&oldest);
+        }
+        let file = Arc::new(File::open(format_path(path, id))?);
+        inner.files.insert(id, (file.clone(), tick));
+        Ok(file)
+    }
+
+    /// close the handles of the logs before `id`, once compaction deleted them
+    pub fn release_before(&self, id: u16) {
+        self.inner.lock().unwrap().files.retain(|file_id, _| *file_id >= id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().files.len()
+    }
+}
+
+/// fill `buf` from `off` of `file`, leaving its cursor alone
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], off: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, off)
+}
+
+/// fill `buf` from `off` of `file`, the cursor moves but nothing relies on it
+#[cfg(windows)]
+pub fn read_at(file: &File, mut buf: &mut [u8], mut off: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, off) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                off += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::dbengines::common::Pos;
+
+/// The index of the first `data_len` bytes of a log, so `open` can skip
+/// decoding every value of a compacted generation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hint {
+    /// bytes of the log covered, writes after them are replayed as usual
+    pub data_len: u32,
+    pub entries: Vec<HintEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HintEntry {
+    pub key: String,
+    pub off: u32,
+    pub size: u32,
+    pub tombstone: bool,
+}
+
+impl HintEntry {
+    pub fn pos(&self, id: u16) -> Pos {
+        Pos::new(id, self.off, self.size)
+    }
+}
+
+pub fn hint_path(path: &Path, id: u16) -> PathBuf {
+    path.join(format!("{}.hint", id))
+}
+
+/// written aside and renamed, a hint file is either whole or missing
+pub fn write(path: &Path, id: u16, hint: &Hint) -> Result<()> {
+    let tmp = path.join(format!("{}.hint.tmp", id));
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, hint)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp, hint_path(path, id))?;
+    Ok(())
+}
+
+/// the hint of log `id`, `None` if there is none or it can't be read
+pub fn read(path: &Path, id: u16) -> Option<Hint> {
+    let data = fs::read(hint_path(path, id)).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(hint) => Some(hint),
+        Err(e) => {
+            warn!("ignore unreadable hint of {}.log: {}", id, e);
+            None
+        }
+    }
+}
+
+pub fn remove(path: &Path, id: u16) -> Result<()> {
+    match fs::remove_file(hint_path(path, id)) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
@@ -0,0 +1,151 @@
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::dbengines::common::Pos;
+use crate::utils::fnv1a;
+
+/// How `KvStore` keeps its index in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum IndexMode {
+    /// every key in a skip map, in key order
+    #[default]
+    Ordered,
+    /// A 64-bit hash per key, so memory no longer grows with key length.
+    /// Records are read back to tell apart keys sharing a hash.
+    ///
+    /// Range scans are expensive: there is no key order to seek in, so every
+    /// scan reads the record of every live key, however small its `limit`.
+    /// Paging through the whole keyspace reads the store once per page, so
+    /// use this mode for stores which are rarely or never scanned.
+    Hashed,
+}
+
+/// the entries of an index by key and by hash
+pub type Entries = (Vec<(String, Pos)>, Vec<(u64, Pos)>);
+
+/// Where the latest record of every live key is.
+///
+/// In hashed mode a key whose hash is already taken by another key is kept
+/// by name in `keys`, so lookups check `keys` first. Methods that may have
+/// to compare keys take `key_at`, reading the key of a record.
+#[derive(Debug)]
+pub struct Index {
+    mode: IndexMode,
+    keys: SkipMap<String, Pos>,
+    hashes: SkipMap<u64, Pos>,
+}
+
+impl Index {
+    pub fn new(mode: IndexMode) -> Self {
+        Index { mode, keys: SkipMap::new(), hashes: SkipMap::new() }
+    }
+
+    pub fn mode(&self) -> IndexMode {
+        self.mode
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len() + self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the position of `key`, in hashed mode the record may hold another key
+    pub fn get(&self, key: &str) -> Option<Pos> {
+        if let Some(entry) = self.keys.get(key) {
+            return Some(entry.value().clone());
+        }
+        match self.mode {
+            IndexMode::Ordered => None,
+            IndexMode::Hashed => self.hashes.get(&fnv1a(key.as_bytes())).map(|e| e.value().clone()),
+        }
+    }
+
+    /// point `key` at `pos`, return where it was
+    pub fn insert<F>(&self, key: String, pos: Pos, key_at: F) -> Result<Option<Pos>>
+        where F: Fn(&Pos) -> Result<String> {
+        if self.mode == IndexMode::Ordered || self.keys.contains_key(&key) {
+            let old = self.keys.get(&key).map(|e| e.value().clone());
+            self.keys.insert(key, pos);
+            return Ok(old);
+        }
+        let hash = fnv1a(key.as_bytes());
+        match self.hashes.get(&hash) {
+            Some(entry) if key_at(entry.value())? != key => {
+                self.keys.insert(key, pos);
+                Ok(None)
+            }
+            old => {
+                let old = old.map(|e| e.value().clone());
+                self.hashes.insert(hash, pos);
+                Ok(old)
+            }
+        }
+    }
+
+    /// forget `key`, return where it was
+    pub fn remove<F>(&self, key: &str, key_at: F) -> Result<Option<Pos>>
+        where F: Fn(&Pos) -> Result<String> {
+        if let Some(entry) = self.keys.remove(key) {
+            return Ok(Some(entry.value().clone()));
+        }
+        if self.mode == IndexMode::Ordered {
+            return Ok(None);
+        }
+        let hash = fnv1a(key.as_bytes());
+        match self.hashes.get(&hash) {
+            Some(entry) if key_at(entry.value())? == key => Ok(self.hashes.remove(&hash).map(|e| e.value().clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// point `key` at its copy `to` if it is still at `from`, return whether
+    /// it was, a write since the copy wins
+    pub fn relocate(&self, key: &str, from: &Pos, to: Pos) -> bool {
+        if self.get(key).as_ref() != Some(from) {
+            return false;
+        }
+        if self.mode == IndexMode::Ordered || self.keys.contains_key(key) {
+            self.keys.insert(key.to_owned(), to);
+        } else {
+            self.hashes.insert(fnv1a(key.as_bytes()), to);
+        }
+        true
+    }
+
+    /// visit every entry, with its key when the index keeps it
+    pub fn for_each<F>(&self, mut f: F) -> Result<()> where F: FnMut(Option<&str>, &Pos) -> Result<()> {
+        for entry in self.keys.iter() {
+            f(Some(entry.key()), entry.value())?;
+        }
+        for entry in self.hashes.iter() {
+            f(None, entry.value())?;
+        }
+        Ok(())
+    }
+
+    /// at most `limit` entries from the first key >= `start`, ordered mode only
+    pub fn range(&self, start: String, limit: usize) -> Vec<(String, Pos)> {
+        self.keys.range(start..).take(limit).map(|e| (e.key().clone(), e.value().clone())).collect()
+    }
+
+    /// the entries by key and by hash, for a checkpoint
+    pub fn entries(&self) -> Entries {
+        let keys = self.keys.iter().map(|e| (e.key().clone(), e.value().clone())).collect();
+        let hashes = self.hashes.iter().map(|e| (*e.key(), e.value().clone())).collect();
+        (keys, hashes)
+    }
+
+    /// add entries taken by `entries` from an index of the same mode
+    pub fn load(&self, keys: Vec<(String, Pos)>, hashes: Vec<(u64, Pos)>) {
+        for (key, pos) in keys {
+            self.keys.insert(key, pos);
+        }
+        for (hash, pos) in hashes {
+            self.hashes.insert(hash, pos);
+        }
+    }
+}
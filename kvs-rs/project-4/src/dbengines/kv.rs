@@ -1,39 +1,240 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File, OpenOptions};
+use std::collections::BTreeMap;
+use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicU64, Ordering, AtomicU16};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering, AtomicU16};
 use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 
-use crossbeam_skiplist::SkipMap;
+use log::{error, info, warn};
 use failure::_core::sync::atomic::AtomicU32;
 use serde_json::Deserializer;
 
 use crate::{KvsEngine, Result};
+use crate::dbengines::{backup, blob, checkpoint, hint, EngineInfo};
+use crate::dbengines::blob::Blobs;
+use crate::dbengines::cache::{CacheStats, ValueCache};
+use crate::dbengines::handles::{self, HandlePool};
+use crate::dbengines::mapped::Mappings;
+use crate::dbengines::checkpoint::Checkpoint;
+use crate::dbengines::hint::{Hint, HintEntry};
+use crate::dbengines::index::{Index, IndexMode};
 use crate::dbengines::common::*;
 use crate::dbengines::common::Pos;
+use crate::dbengines::compaction::{CompactionPolicy, Throttle};
+use crate::dbengines::compress::Codec;
+use crate::dbengines::crypto::{self, Keyring};
 use crate::dbengines::kv::Op::{Remove, Set};
+use crate::error::KvsError;
 use crate::error::KvsError::KeyNotFound;
-use crate::utils::{del_file, format_path, ls_logs};
+use crate::metrics::{self, Counter, Histogram, LATENCY_BUCKETS};
+use crate::utils::{del_file, dir_size, fnv1a, format_path, ls_logs};
 
 /// set 1.add index 2.append log
 /// get 1.read index 2.read file
 /// remove 1.remove index 2.append log 3.add unCompact count 4.if ness compact 5.add cur gen
 
-const MAX_UN_COMPACT: u64 = 1024 * 1024;
+const DEFAULT_COMPRESS_THRESHOLD: usize = 128;
+const DEFAULT_BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_BLOB_GC_RATIO: f64 = 0.5;
+const DEFAULT_MAX_OPEN_FILES: usize = 64;
+/// how often a store with a timed compaction policy checks it
+const COMPACTION_CHECK_EVERY: Duration = Duration::from_secs(1);
+
+/// Tunables of a `KvStore`, see `KvStore::open_with`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    checkpoint_every: u64,
+    index_mode: IndexMode,
+    compression: Option<Codec>,
+    compress_threshold: usize,
+    keys: Option<Keyring>,
+    blob_threshold: Option<usize>,
+    blob_file_size: u64,
+    blob_gc_ratio: f64,
+    cache_capacity: usize,
+    mmap: bool,
+    max_open_files: usize,
+    compaction: CompactionPolicy,
+    compaction_rate: Option<u64>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            checkpoint_every: 0,
+            index_mode: IndexMode::Ordered,
+            compression: None,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            keys: None,
+            blob_threshold: None,
+            blob_file_size: DEFAULT_BLOB_FILE_SIZE,
+            blob_gc_ratio: DEFAULT_BLOB_GC_RATIO,
+            cache_capacity: 0,
+            mmap: true,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            compaction: CompactionPolicy::default(),
+            compaction_rate: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checkpoint the index every `writes` sets and removes, 0 never does
+    /// and is the default.
+    ///
+    /// A checkpoint serializes and syncs the whole index while writes wait,
+    /// so it trades write latency spikes for a faster `open`.
+    pub fn checkpoint_every(mut self, writes: u64) -> Self {
+        self.checkpoint_every = writes;
+        self
+    }
+
+    /// how the index is kept in memory, the logs are the same in every mode
+    pub fn index_mode(mut self, mode: IndexMode) -> Self {
+        self.index_mode = mode;
+        self
+    }
+
+    /// compress the values written from now on, logs may mix codecs
+    pub fn compression(mut self, codec: Codec) -> Self {
+        self.compression = Some(codec);
+        self
+    }
+
+    /// values shorter than `bytes` are written raw
+    pub fn compress_threshold(mut self, bytes: usize) -> Self {
+        self.compress_threshold = bytes;
+        self
+    }
+
+    /// Encrypt the records written from now on, and compacted ones, with the
+    /// active key. Hints and checkpoints are neither written nor read, they
+    /// would hold the keys in clear. Values in blob files stay sealed with
+    /// their key until the blob GC moves them.
+    pub fn encryption(mut self, keys: Keyring) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Keep values of at least `bytes` in blob files, the logs only point at
+    /// them, so compaction copies a small record instead of the value.
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// start a new blob file once the current one holds `bytes`
+    pub fn blob_file_size(mut self, bytes: u64) -> Self {
+        self.blob_file_size = bytes;
+        self
+    }
+
+    /// Collect a blob file once this fraction of it is garbage, its live
+    /// values are copied to the current blob file and the file deleted.
+    /// Files are checked whenever one fills up and on `KvStore::gc_blobs`.
+    pub fn blob_gc_ratio(mut self, ratio: f64) -> Self {
+        self.blob_gc_ratio = ratio;
+        self
+    }
+
+    /// cache up to about `bytes` of recently read values, shared by every
+    /// clone of the store, 0 caches nothing
+    pub fn cache_capacity(mut self, bytes: usize) -> Self {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// read sealed generations, the logs of earlier opens, through a memory
+    /// mapping instead of a seek and read, on by default
+    pub fn mmap(mut self, enabled: bool) -> Self {
+        self.mmap = enabled;
+        self
+    }
+
+    /// keep at most `files` logs open for reads, for all clones together
+    pub fn max_open_files(mut self, files: usize) -> Self {
+        self.max_open_files = files;
+        self
+    }
+
+    /// when the store compacts its logs by itself, on a background thread,
+    /// on top of `KvsEngine::compact`
+    pub fn compaction(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction = policy;
+        self
+    }
+
+    /// Write the compactions the policy starts at about `bytes` per second,
+    /// 0 does not limit. They run on a background thread, which writes do
+    /// not wait for; `KvsEngine::compact` is never limited.
+    pub fn compaction_rate(mut self, bytes: u64) -> Self {
+        self.compaction_rate = if bytes == 0 { None } else { Some(bytes) };
+        self
+    }
+}
+
+/// counters of every `KvStore` of the process, in `metrics::global()`
+#[derive(Debug)]
+struct StoreMetrics {
+    bytes_read: Arc<Counter>,
+    bytes_written: Arc<Counter>,
+    compactions: Arc<Counter>,
+    compaction_time: Arc<Histogram>,
+}
+
+impl StoreMetrics {
+    fn new() -> StoreMetrics {
+        let registry = metrics::global();
+        StoreMetrics {
+            bytes_read: registry.counter("kvs_engine_bytes_read_total", "Bytes of records read from logs and blob files", &[]),
+            bytes_written: registry.counter("kvs_engine_bytes_written_total", "Bytes of records written to logs and blob files", &[]),
+            compactions: registry.counter("kvs_compactions_total", "Log compactions run", &[]),
+            compaction_time: registry.histogram("kvs_compaction_duration_seconds", "Time a log compaction takes", &[], LATENCY_BUCKETS),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct KvStore {
     path: Arc<PathBuf>,
     // pub for debug
-    pub index: Arc<SkipMap<String, Pos>>,
-    reader: RefCell<HashMap<u16, BufferReader>>,
+    pub index: Arc<Index>,
+    handles: Arc<HandlePool>,
     writer: Arc<Mutex<RefCell<BufferWriter>>>,
     cur_file_id: Arc<AtomicU16>,
     un_compact_size: Arc<AtomicU64>,
+    /// bytes of the logs before the active one
+    sealed_bytes: Arc<AtomicU64>,
+    last_compaction: Arc<Mutex<Instant>>,
+    /// held by the clones handed out, the compaction timer stops with the last
+    users: Arc<()>,
+    options: KvStoreOptions,
+    /// sets and removes since the last checkpoint
+    writes: Arc<AtomicU64>,
+    /// taken after the writer when both are
+    blobs: Arc<Mutex<Blobs>>,
+    /// a blob file filled up since the last blob GC
+    gc_due: Arc<AtomicBool>,
+    /// a background blob GC thread is running
+    gc_running: Arc<AtomicBool>,
+    /// held by a blob GC, so only one runs at a time
+    gc_lock: Arc<Mutex<()>>,
+    /// held by a compaction, so only one runs at a time, taken before the writer
+    compaction_lock: Arc<Mutex<()>>,
+    /// a compaction the policy started is running
+    compacting: Arc<AtomicBool>,
+    cache: Option<Arc<ValueCache>>,
+    maps: Option<Arc<Mappings>>,
+    metrics: Arc<StoreMetrics>,
 }
 
 impl Clone for KvStore {
@@ -41,10 +242,36 @@ impl Clone for KvStore {
         Self {
             path: self.path.clone(),
             index: self.index.clone(),
-            reader: RefCell::new(HashMap::new()),
+            handles: self.handles.clone(),
             writer: self.writer.clone(),
             cur_file_id: self.cur_file_id.clone(),
             un_compact_size: self.un_compact_size.clone(),
+            sealed_bytes: self.sealed_bytes.clone(),
+            last_compaction: self.last_compaction.clone(),
+            users: self.users.clone(),
+            options: self.options.clone(),
+            writes: self.writes.clone(),
+            blobs: self.blobs.clone(),
+            gc_due: self.gc_due.clone(),
+            gc_running: self.gc_running.clone(),
+            gc_lock: self.gc_lock.clone(),
+            compaction_lock: self.compaction_lock.clone(),
+            compacting: self.compacting.clone(),
+            cache: self.cache.clone(),
+            maps: self.maps.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// the last user waits for a compaction the policy started, so the store
+/// can be opened again once it is dropped
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.users) == 1 {
+            while self.compacting.load(SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
         }
     }
 }
@@ -113,137 +340,807 @@ impl Seek for BufferReader {
 
 impl KvStore {
     pub fn open(path: &Path) -> Result<KvStore> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with(path: &Path, options: KvStoreOptions) -> Result<KvStore> {
         create_dir_all(path)?;
-        let index = SkipMap::new();
-        let mut readers = HashMap::new();
+        let index = Index::new(options.index_mode);
         let log_ids = ls_logs(path);
         //println!("{:?}",log_ids);
         let mut un_compact = 0;
+        let keys = options.keys.as_ref();
+        let covered = match keys {
+            Some(_) => None,
+            None => Self::load_checkpoint(path, &log_ids, &index)?,
+        };
+        if let Some((_, _, size)) = covered {
+            un_compact += size;
+        }
+        for id in log_ids.iter() {
+            un_compact += match covered {
+                Some((cp_id, _, _)) if *id < cp_id => 0,
+                // only the writes after the checkpoint are decoded
+                Some((cp_id, file_pos, _)) if *id == cp_id => Self::load_file(path, &index, *id, file_pos, keys)?,
+                _ if keys.is_some() => Self::load_file(path, &index, *id, 0, keys)?,
+                _ => match Self::load_hint(path, &index, *id)? {
+                // only the writes after the hinted part are decoded
+                Some(data_len) => Self::load_file(path, &index, *id, data_len, keys)?,
+                    None => Self::load_file(path, &index, *id, 0, keys)?,
+                },
+            };
+        }
+        let mut sealed = 0;
         for id in log_ids.iter() {
-            un_compact += Self::load_file(path, &index, &mut readers, *id)? as u64;
+            sealed += fs::metadata(format_path(path, *id))?.len();
         }
-        let cur_file_id = log_ids.last().unwrap_or(&0) + 1;
+        let cur_file_id = next_id(*log_ids.last().unwrap_or(&0))?;
         let writer = BufferWriter::new(format_path(path, cur_file_id))?;
-        readers.insert(cur_file_id, BufferReader::new(format_path(path, cur_file_id))?);
-        Ok(Self {
+        let store = Self {
             path: Arc::new(path.to_path_buf()),
             index: Arc::new(index),
-            reader: RefCell::new(readers),
+            handles: Arc::new(HandlePool::new(options.max_open_files)),
             writer: Arc::new(Mutex::new(RefCell::new(writer))),
             cur_file_id: Arc::new(AtomicU16::new(cur_file_id)),
             un_compact_size: Arc::new(AtomicU64::new(un_compact)),
-        })
+            sealed_bytes: Arc::new(AtomicU64::new(sealed)),
+            last_compaction: Arc::new(Mutex::new(Instant::now())),
+            users: Arc::new(()),
+            writes: Arc::new(AtomicU64::new(0)),
+            blobs: Arc::new(Mutex::new(Blobs::open(path)?)),
+            gc_due: Arc::new(AtomicBool::new(false)),
+            gc_running: Arc::new(AtomicBool::new(false)),
+            gc_lock: Arc::new(Mutex::new(())),
+            compaction_lock: Arc::new(Mutex::new(())),
+            compacting: Arc::new(AtomicBool::new(false)),
+            cache: match options.cache_capacity {
+                0 => None,
+                capacity => Some(Arc::new(ValueCache::new(capacity))),
+            },
+            maps: if options.mmap { Some(Arc::new(Mappings::default())) } else { None },
+            metrics: Arc::new(StoreMetrics::new()),
+            options,
+        };
+        // the gauges follow the store opened last, a server opens one
+        let (index, un_compact) = (Arc::downgrade(&store.index), Arc::downgrade(&store.un_compact_size));
+        metrics::global().gauge_fn("kvs_index_keys", "Keys in the KvStore index", &[], move || {
+            index.upgrade().map(|index| index.len() as i64)
+        });
+        metrics::global().gauge_fn("kvs_uncompacted_bytes", "Bytes of dead records in the KvStore logs", &[], move || {
+            un_compact.upgrade().map(|size| size.load(Relaxed) as i64)
+        });
+        if store.options.compaction.timed() {
+            let (mut timer, users) = (store.clone(), Arc::downgrade(&store.users));
+            timer.users = Arc::new(());
+            thread::Builder::new().name("kvs-compaction".to_string()).spawn(move || timer.compaction_timer(users))?;
+        }
+        Ok(store)
     }
-    fn compact(&self) -> Result<BufferWriter> {
-        let cur_file_id = 1 + self.cur_file_id.load(Relaxed);
+
+    /// Like `KvsEngine::restore`, opening the restored store with `options`,
+    /// which must hold the keys of an encrypted backup.
+    pub fn restore_with(src: &Path, path: &Path, options: KvStoreOptions) -> Result<KvStore> {
+        let manifest = backup::verify(src)?;
+        create_dir_all(path)?;
+        if !ls_logs(path).is_empty() {
+            return Err(KvsError::StringError(format!("{} already holds data", path.display())));
+        }
+        for file in manifest.files.iter() {
+            fs::copy(src.join(&file.name), path.join(&file.name))?;
+        }
+        KvStore::open_with(path, options)
+    }
+
+    /// Write the whole index to disk, so `open` only replays the logs
+    /// written after it. Called every `checkpoint_every` writes.
+    pub fn checkpoint(&self) -> Result<()> {
+        let writer_ref = self.writer.lock().unwrap();
+        let writer = writer_ref.borrow();
+        self.write_checkpoint(&writer)
+    }
+
+    /// the writer lock must be held, so the index matches the log position
+    fn write_checkpoint(&self, writer: &BufferWriter) -> Result<()> {
+        let (entries, hashes) = self.index.entries();
+        let checkpoint = Checkpoint {
+            id: self.cur_file_id.load(SeqCst),
+            file_pos: writer.file_pos,
+            un_compact: self.un_compact_size.load(SeqCst),
+            mode: self.index.mode(),
+            entries,
+            hashes,
+        };
+        checkpoint::write(&self.path, &checkpoint)?;
+        self.writes.store(0, SeqCst);
+        Ok(())
+    }
+
+    /// count a write and checkpoint if it is time to
+    fn after_write(&self, writer: &BufferWriter) -> Result<()> {
+        let every = self.options.checkpoint_every;
+        if every > 0 && self.options.keys.is_none() && self.writes.fetch_add(1, SeqCst) + 1 >= every {
+            self.write_checkpoint(writer)?;
+        }
+        Ok(())
+    }
+
+    /// load the index from the checkpoint, if there is a valid one
+    /// return the log and offset it covers with its un compact size
+    fn load_checkpoint(path: &Path, log_ids: &[u16], index: &Index) -> Result<Option<(u16, u32, u64)>> {
+        let cp = match checkpoint::read(path) {
+            Some(cp) => cp,
+            None => return Ok(None),
+        };
+        if cp.mode != index.mode() {
+            info!("ignore index checkpoint of the {:?} index mode", cp.mode);
+            return Ok(None);
+        }
+        if !Self::checkpoint_matches(path, log_ids, &cp)? {
+            warn!("ignore stale index checkpoint");
+            return Ok(None);
+        }
+        let (id, file_pos, un_compact) = (cp.id, cp.file_pos, cp.un_compact);
+        index.load(cp.entries, cp.hashes);
+        Ok(Some((id, file_pos, un_compact)))
+    }
+
+    /// every log the checkpoint points into is still there, none was
+    /// compacted away, and its latest record is where it says
+    fn checkpoint_matches(path: &Path, log_ids: &[u16], cp: &Checkpoint) -> Result<bool> {
+        let positions = || cp.entries.iter().map(|(_, pos)| pos).chain(cp.hashes.iter().map(|(_, pos)| pos));
+        if !log_ids.contains(&cp.id) || positions().any(|pos| !log_ids.contains(&pos.id)) {
+            return Ok(false);
+        }
+        if fs::metadata(format_path(path, cp.id))?.len() < cp.file_pos as u64 {
+            return Ok(false);
+        }
+        let last = match positions().max_by_key(|pos| (pos.id, pos.off)) {
+            Some(last) => last,
+            None => return Ok(true),
+        };
+        let found = match read_op(path, last, None) {
+            Ok(record) if record.is_set() => record.key().to_owned(),
+            _ => return Ok(false),
+        };
+        Ok(cp.entries.iter().any(|(key, pos)| pos == last && *key == found)
+            || cp.hashes.iter().any(|(hash, pos)| pos == last && *hash == fnv1a(found.as_bytes())))
+    }
+    /// check the compaction policy every second until every user clone is dropped
+    fn compaction_timer(&self, users: Weak<()>) {
+        loop {
+            thread::sleep(COMPACTION_CHECK_EVERY);
+            if users.upgrade().is_none() {
+                return;
+            }
+            if !self.compacting.swap(true, SeqCst) {
+                self.run_compaction();
+            }
+        }
+    }
+
+    /// compact on a background thread unless a compaction is running already
+    fn spawn_compaction(&self) {
+        if self.compacting.swap(true, SeqCst) {
+            return;
+        }
+        // not a user, so dropping the last one waits for it
+        let mut store = self.clone();
+        store.users = Arc::new(());
+        let spawned = thread::Builder::new().name("kvs-compactor".to_string()).spawn(move || store.run_compaction());
+        if let Err(e) = spawned {
+            error!("failed to spawn the compaction thread: {}", e);
+            self.compacting.store(false, SeqCst);
+        }
+    }
+
+    /// compact if the policy says so, the caller set `compacting`
+    fn run_compaction(&self) {
+        if let Err(e) = self.compact_logs(true) {
+            error!("Compaction failed: {}", e);
+        }
+        self.compacting.store(false, SeqCst);
+    }
+
+    /// whether the policy says to compact, `writer` is the locked writer
+    fn compaction_due(&self, writer: &BufferWriter) -> bool {
+        let dead = self.un_compact_size.load(Relaxed);
+        let total = self.sealed_bytes.load(Relaxed) + writer.file_pos as u64;
+        let since = self.last_compaction.lock().unwrap().elapsed();
+        self.options.compaction.due(dead, total, since, SystemTime::now())
+    }
+
+    /// Rewrite the live records of the logs into a new one. `background`
+    /// compactions are the policy's: they skip if one is running or it is
+    /// no longer due, and keep to `compaction_rate`.
+    ///
+    /// The writer lock is only held to seal the active log, writes go on
+    /// in the next one meanwhile, and to point the index at the copies.
+    /// The copy is written aside and only named a log once complete, its id
+    /// sorts it before the log of the writes made meanwhile.
+    fn compact_logs(&self, background: bool) -> Result<()> {
+        let _compaction = match background {
+            true => match self.compaction_lock.try_lock() {
+                Ok(guard) => guard,
+                Err(_) => return Ok(()),
+            },
+            false => self.compaction_lock.lock().unwrap(),
+        };
+        let start = Instant::now();
         let path = self.path.as_path();
-        let mut new_writer = BufferWriter::new(format_path(path, cur_file_id))?;
-        for entry in self.index.iter() {
-            //println!("{:?}",pos);
-            let (key, pos) = (entry.key(), entry.value());
-            let mut reader_map = self.reader.borrow_mut();
-            let reader = reader_map.entry(pos.id).or_insert(BufferReader::new(format_path(&self.path, pos.id))?);
-            reader.seek(SeekFrom::Start(pos.off as u64))?;
-            let mut reader = reader.take(pos.size  as u64);
-            if let Set { value, .. } = serde_json::from_reader(&mut reader)? {
-                let op = Set { key: key.clone(), value };
-                let off = new_writer.file_pos;
-                serde_json::to_writer(&mut new_writer, &op)?;
-                self.index.insert(key.clone(), Pos { id: cur_file_id, off, size: (new_writer.file_pos - off) as u16 });
+        let (compacted_id, dead) = {
+            let writer_ref = self.writer.lock().unwrap();
+            let mut writer = writer_ref.borrow_mut();
+            if background && !self.compaction_due(&writer) {
+                return Ok(());
             }
+            writer.flush()?;
+            let cur_file_id = self.cur_file_id.load(SeqCst);
+            // the compacted log and the next active one
+            next_id(next_id(cur_file_id)?)?;
+            let compacted_id = cur_file_id + 1;
+            let sealed = writer.file_pos as u64;
+            *writer = BufferWriter::new(format_path(path, compacted_id + 1))?;
+            self.sealed_bytes.fetch_add(sealed, Relaxed);
+            self.cur_file_id.store(compacted_id + 1, SeqCst);
+            (compacted_id, self.un_compact_size.load(SeqCst))
+        };
+
+        let rate = if background { self.options.compaction_rate } else { None };
+        let mut throttle = Throttle::new(rate);
+        let tmp = format_path(path, compacted_id).with_extension("log.tmp");
+        if tmp.exists() {
+            fs::remove_file(&tmp)?;
         }
+        let mut new_writer = BufferWriter::new(tmp.clone())?;
+        let mut moved = Vec::new();
+        let mut entries = Vec::with_capacity(self.index.len());
+        self.index.for_each(|_, pos| {
+            // written after the seal
+            if pos.id > compacted_id {
+                return Ok(());
+            }
+            let (key, record) = match self.read_record(pos)? {
+                // the value moves to a new blob file if its key is being rotated out
+                record @ Record::Blob { .. } if self.stale_blob(&record)? => match self.resolve(record) {
+                    Ok(record) => match record.into_op()? {
+                        Set { key, value } => (key.clone(), self.pack(Set { key, value })?),
+                        Remove { .. } => return Ok(()),
+                    },
+                    // the blob GC moved the value since, after the seal
+                    Err(e) if blob::is_collected(&e) => return Ok(()),
+                    Err(e) => return Err(e),
+                },
+                // only the reference is copied, the value stays in its blob file
+                record @ Record::Blob { .. } => (record.key().to_owned(), self.seal(record)?),
+                record => match record.into_op()? {
+                    // values are compressed and sealed again as the options say now,
+                    // this is how a new key or codec reaches the old records
+                    Set { key, value } => (key.clone(), self.pack(Set { key, value })?),
+                    Remove { .. } => return Ok(()),
+                },
+            };
+            let off = new_writer.file_pos;
+            serde_json::to_writer(&mut new_writer, &record)?;
+            let size = new_writer.file_pos - off;
+            throttle.consume(size as u64);
+            moved.push((key.clone(), pos.clone(), Pos { id: compacted_id, off, size }));
+            entries.push(HintEntry { key, off, size, tombstone: false });
+            Ok(())
+        })?;
         new_writer.flush()?;
+        fs::rename(&tmp, format_path(path, compacted_id))?;
+        if self.options.keys.is_none() {
+            hint::write(path, compacted_id, &Hint { data_len: new_writer.file_pos, entries })?;
+        }
+
+        let writer_ref = self.writer.lock().unwrap();
+        let writer = writer_ref.borrow();
+        // the copy of a key written meanwhile is dead, that write counted
+        // the record it replaced
+        for (key, from, to) in moved {
+            self.index.relocate(&key, &from, to);
+        }
         // del old file
-        let log_ids = ls_logs(path);
-        for id in log_ids.iter() {
-            if *id < cur_file_id {
-                del_file(format_path(&self.path, *id))?;
+        for id in ls_logs(path) {
+            if id < compacted_id {
+                del_file(format_path(&self.path, id))?;
+                hint::remove(path, id)?;
+            }
+        }
+        self.un_compact_size.fetch_sub(dead, SeqCst);
+        self.sealed_bytes.store(new_writer.file_pos as u64, Relaxed);
+        *self.last_compaction.lock().unwrap() = Instant::now();
+        if let Some(ref maps) = self.maps {
+            maps.release_before(compacted_id);
+        }
+        self.handles.release_before(compacted_id);
+        self.metrics.compactions.inc();
+        self.metrics.bytes_written.add(new_writer.file_pos as u64);
+        self.metrics.compaction_time.observe(start.elapsed());
+        // every value moved, no entry would hit again
+        if let Some(ref cache) = self.cache {
+            cache.clear();
+        }
+        // the previous checkpoint points into the logs just deleted
+        if self.options.checkpoint_every > 0 && self.options.keys.is_none() {
+            self.write_checkpoint(&writer)?;
+        }
+        Ok(())
+    }
+
+    /// the record of `op` as the options say to write it,
+    /// a large value is appended to a blob file right away
+    fn pack(&self, op: Op) -> Result<Record> {
+        let (codec, threshold) = (self.options.compression, self.options.compress_threshold);
+        let record = match (op, self.options.blob_threshold) {
+            (Set { key, value }, Some(blob_threshold)) if value.len() >= blob_threshold => {
+                let record = self.seal(Record::pack(Set { key: key.clone(), value }, codec, threshold)?)?;
+                let (blob, full) = self.blobs.lock().unwrap().append(&self.path, key, &record, self.options.blob_file_size)?;
+                if let Record::Blob { len, .. } = blob {
+                    self.metrics.bytes_written.add(len as u64);
+                }
+                if full {
+                    self.gc_due.store(true, SeqCst);
+                }
+                blob
+            }
+            (op, _) => Record::pack(op, codec, threshold)?,
+        };
+        self.seal(record)
+    }
+
+    fn seal(&self, record: Record) -> Result<Record> {
+        match self.options.keys {
+            Some(ref keys) => keys.seal(&record),
+            None => Ok(record),
+        }
+    }
+
+    /// append the set of `key` and point the index at it
+    fn append_set(&self, writer: &mut BufferWriter, key: String, record: &Record) -> Result<()> {
+        let off = writer.file_pos;
+        serde_json::to_writer(&mut *writer, record)?;
+        writer.flush()?;
+        self.metrics.bytes_written.add((writer.file_pos - off) as u64);
+        let pos = Pos { id: self.cur_file_id.load(Ordering::Relaxed), off, size: writer.file_pos - off };
+        if let Some(ref cache) = self.cache {
+            cache.remove(&key);
+        }
+        if let Some(old) = self.index.insert(key, pos, |pos| self.record_key(pos))? {
+            self.un_compact_size.fetch_add(old.size as u64, SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Collect the blob files that are garbage for at least `blob_gc_ratio`
+    /// of their size and return how many were. Runs on a background thread
+    /// whenever a blob file fills up.
+    pub fn gc_blobs(&self) -> Result<usize> {
+        let _gc = self.gc_lock.lock().unwrap();
+        self.gc_due.store(false, SeqCst);
+        self.collect_blobs()
+    }
+
+    /// start a blob GC thread if one is due and none is running, a running
+    /// one looks again for files filled meanwhile before it exits
+    fn spawn_gc(&self) {
+        if !self.gc_due.load(SeqCst) || self.gc_running.swap(true, SeqCst) {
+            return;
+        }
+        let store = self.clone();
+        let spawned = thread::Builder::new().name("kvs-blob-gc".to_string()).spawn(move || loop {
+            {
+                let _gc = store.gc_lock.lock().unwrap();
+                if store.gc_due.swap(false, SeqCst) {
+                    if let Err(e) = store.collect_blobs() {
+                        error!("blob GC failed: {}", e);
+                    }
+                }
+            }
+            store.gc_running.store(false, SeqCst);
+            if !store.gc_due.load(SeqCst) || store.gc_running.swap(true, SeqCst) {
+                return;
+            }
+        });
+        if let Err(e) = spawned {
+            error!("failed to spawn the blob GC thread: {}", e);
+            self.gc_running.store(false, SeqCst);
+        }
+    }
+
+    /// The blob files are read and the live values copied without the
+    /// writer lock. It is only taken to point a key at its copy, if the key
+    /// still references the collected file, so sets and compactions racing
+    /// the copy win.
+    fn collect_blobs(&self) -> Result<usize> {
+        let path = self.path.as_path();
+        let keys = self.options.keys.as_ref();
+        let active = self.blobs.lock().unwrap().position().map(|(id, _)| id);
+        let mut collected = 0;
+        for id in blob::ls_blobs(path)? {
+            if Some(id) == active {
+                continue;
+            }
+            let size = fs::metadata(blob::blob_path(path, id))?.len();
+            let mut live = Vec::new();
+            let mut live_bytes = 0;
+            for (off, len, key) in blob::entries(path, id, keys)? {
+                if self.references(&key, id, off)? {
+                    live_bytes += len as u64;
+                    live.push((key, off));
+                }
             }
+            if ((size - live_bytes) as f64) < size as f64 * self.options.blob_gc_ratio {
+                continue;
+            }
+            for (key, off) in live {
+                let record = match self.index.get(&key) {
+                    Some(pos) => self.read_record(&pos)?,
+                    None => continue,
+                };
+                if let Set { key, value } = self.resolve(record)?.into_op()? {
+                    let record = self.pack(Set { key: key.clone(), value })?;
+                    let writer_ref = self.writer.lock().unwrap();
+                    let mut writer = writer_ref.borrow_mut();
+                    if self.references(&key, id, off)? {
+                        self.append_set(&mut writer, key, &record)?;
+                    }
+                }
+            }
+            // the moved values and references must outlive the file
+            let writer_ref = self.writer.lock().unwrap();
+            let writer = writer_ref.borrow();
+            self.blobs.lock().unwrap().sync()?;
+            writer.file_writer.get_ref().sync_data()?;
+            fs::remove_file(blob::blob_path(path, id))?;
+            info!("collected {}.blob", id);
+            collected += 1;
+        }
+        Ok(collected)
+    }
+
+    /// whether the value blob reference `record` points to has to be sealed
+    /// again with the active key
+    fn stale_blob(&self, record: &Record) -> Result<bool> {
+        match self.options.keys {
+            Some(ref keys) if keys.rotating() => match blob::key_id(&self.path, record) {
+                // the blob GC moved the value since, the copy is dead anyway
+                Err(ref e) if blob::is_collected(e) => Ok(false),
+                key_id => Ok(key_id? != Some(keys.active())),
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /// whether the latest record of `key` is its value at `off` of blob file `id`
+    fn references(&self, key: &str, id: u32, off: u64) -> Result<bool> {
+        let pos = match self.index.get(key) {
+            Some(pos) => pos,
+            None => return Ok(false),
+        };
+        Ok(matches!(self.read_record(&pos)?, Record::Blob { key: ref at, blob, off: blob_off, .. }
+            if at == key && blob == id && blob_off == off))
+    }
+
+    /// read the record at `pos`, its value may still be compressed
+    fn read_record(&self, pos: &Pos) -> Result<Record> {
+        self.metrics.bytes_read.add(pos.size as u64);
+        match self.maps {
+            // the active log is still written, only older ones are mapped
+            Some(ref maps) if pos.id < self.cur_file_id.load(Relaxed) => {
+                let map = maps.get(&self.path, pos.id)?;
+                let data = map.get(pos.off as usize..pos.off as usize + pos.size as usize).ok_or_else(|| {
+                    KvsError::StringError(format!("record at {} is past the end of {}.log", pos.off, pos.id))
+                })?;
+                return crypto::unseal(serde_json::from_slice(data)?, self.options.keys.as_ref());
+            }
+            _ => {}
+        }
+        let file = self.handles.get(&self.path, pos.id)?;
+        let mut data = vec![0; pos.size as usize];
+        handles::read_at(&file, &mut data, pos.off as u64)?;
+        crypto::unseal(serde_json::from_slice(&data)?, self.options.keys.as_ref())
+    }
+
+    /// the record a blob reference points to, other records as they are
+    fn resolve(&self, record: Record) -> Result<Record> {
+        if let Record::Blob { len, .. } = record {
+            self.metrics.bytes_read.add(len as u64);
+        }
+        blob::resolve(&self.path, record, self.options.keys.as_ref())
+    }
+
+    /// the value at `pos`, if the record there sets `key`, from the cache if it can
+    fn read_value(&self, key: &str, pos: &Pos) -> Result<Option<String>> {
+        if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(key, pos)) {
+            return Ok(Some(value));
+        }
+        let record = match self.read_record(pos) {
+            Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => match self.index.get(key) {
+                // a compaction moved the record and deleted its log since `pos` was looked up
+                Some(moved) if moved != *pos => return self.read_value(key, &moved),
+                None => return Ok(None),
+                Some(_) => return Err(KvsError::Io(e)),
+            },
+            record => record?,
+        };
+        let value = self.value_of(key, pos, record)?;
+        if let (Some(cache), Some(value)) = (self.cache.as_ref(), value.as_ref()) {
+            cache.insert(key.to_owned(), pos.clone(), value.clone());
+        }
+        Ok(value)
+    }
+
+    /// logs held open for reads by the store and its clones
+    pub fn open_files(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// hits and misses of the value cache, `None` without one
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// the value of `record`, read at `pos`, if it sets `key`
+    fn value_of(&self, key: &str, pos: &Pos, record: Record) -> Result<Option<String>> {
+        if !record.is_set() || record.key() != key {
+            return Ok(None);
+        }
+        let record = match self.resolve(record) {
+            Ok(record) => record,
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => match self.index.get(key) {
+                // the blob GC moved the value since `pos` was looked up
+                Some(moved) if moved != *pos => return self.read_value(key, &moved),
+                _ => return Err(KvsError::StringError(format!("blob file of {} is missing", key))),
+            },
+            Err(e) => return Err(e),
+        };
+        match record.into_op()? {
+            Set { value, .. } => Ok(Some(value)),
+            Remove { .. } => Ok(None),
         }
-        self.cur_file_id.store(cur_file_id, Relaxed);
-        Ok(new_writer)
     }
 
-    /// load file to index
+    fn record_key(&self, pos: &Pos) -> Result<String> {
+        Ok(self.read_record(pos)?.key().to_owned())
+    }
+
+    /// keys are not in order, so every record is read, keeping the `limit` smallest keys,
+    /// which makes a scan cost as much as reading the whole store, see `IndexMode::Hashed`
+    fn scan_hashed(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = BTreeMap::new();
+        self.index.for_each(|_, pos| {
+            let record = self.read_record(pos)?;
+            if record.is_set() && record.key() >= start.as_str() {
+                let key = record.key().to_owned();
+                if let Some(value) = self.value_of(&key, pos, record)? {
+                    pairs.insert(key, value);
+                    if pairs.len() > limit {
+                        pairs.pop_last();
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(pairs.into_iter().collect())
+    }
+
+    /// load the index of log `id` from its hint file, if it has a valid one
+    /// return the length of the log the hint covers
+    fn load_hint(path: &Path, index: &Index, id: u16) -> Result<Option<u32>> {
+        let hint = match hint::read(path, id) {
+            Some(hint) => hint,
+            None => return Ok(None),
+        };
+        if !Self::hint_matches(path, id, &hint)? {
+            warn!("ignore stale hint of {}.log", id);
+            return Ok(None);
+        }
+        let key_at = |pos: &Pos| Ok(read_op(path, pos, None)?.key().to_owned());
+        for entry in hint.entries.iter() {
+            if entry.tombstone {
+                index.remove(&entry.key, key_at)?;
+            } else {
+                index.insert(entry.key.clone(), entry.pos(id), key_at)?;
+            }
+        }
+        Ok(Some(hint.data_len))
+    }
+
+    /// the log holds at least the hinted bytes and the last hinted record is where the hint says
+    fn hint_matches(path: &Path, id: u16, hint: &Hint) -> Result<bool> {
+        let mut file = File::open(format_path(path, id))?;
+        if file.metadata()?.len() < hint.data_len as u64 {
+            return Ok(false);
+        }
+        let last = match hint.entries.last() {
+            Some(last) => last,
+            None => return Ok(true),
+        };
+        file.seek(SeekFrom::Start(last.off as u64))?;
+        Ok(match serde_json::from_reader::<_, Record>(file.take(last.size as u64)) {
+            Ok(Record::Sealed { .. }) | Err(_) => false,
+            Ok(record) => record.is_set() != last.tombstone && record.key() == last.key,
+        })
+    }
+
+    /// load file to index, from offset `from`
     /// return the un compact size
-    fn load_file(path: &Path, index: &SkipMap<String, Pos>, readers: &mut HashMap<u16, BufferReader>, id: u16) -> Result<u64> {
+    fn load_file(path: &Path, index: &Index, id: u16, from: u32, keys: Option<&Keyring>) -> Result<u64> {
         let mut reader = BufferReader::new(format_path(path, id))?;
-        reader.seek(SeekFrom::Start(0))?;
-        let mut start :u32 = 0;
+        reader.seek(SeekFrom::Start(from as u64))?;
+        let mut start :u32 = from;
         let mut un_compact : u64 = 0;
-        let mut stream = Deserializer::from_reader(&mut reader).into_iter::<Op>();
-        while let Some(op) = stream.next() {
-            let off = stream.byte_offset() as u32;
-            match op? {
-                Set { key, .. } => {
-                    index.insert(key, Pos::new(id, start, (off - start) as u16));
+        let key_at = |pos: &Pos| Ok(read_op(path, pos, keys)?.key().to_owned());
+        let mut stream = Deserializer::from_reader(&mut reader).into_iter::<Record>();
+        while let Some(record) = stream.next() {
+            let off = from + stream.byte_offset() as u32;
+            match crypto::unseal(record?, keys)? {
+                Record::Set { key, .. } | Record::Packed { key, .. } | Record::Blob { key, .. } => {
+                    index.insert(key, Pos::new(id, start, off - start), key_at)?;
                 }
-                Remove { key } => {
-                    index.remove(&key);
+                Record::Remove { key } => {
+                    index.remove(&key, key_at)?;
                     un_compact += (off - start) as u64;
                 }
+                Record::Sealed { .. } => unreachable!(),
             }
             start = off;
         }
         //println!("load file is {:?}",index);
-        readers.insert(id, reader);
         Ok(un_compact)
     }
 }
 
 impl KvsEngine for KvStore {
     fn set(&self, key: String, value: String) -> Result<()> {
-        let op = Set { key: key.clone(), value };
+        let record = self.pack(Set { key: key.clone(), value })?;
         // lock
-        let writer_ref = self.writer.lock().unwrap();
-        let mut writer = writer_ref.borrow_mut();
-
-        let off = writer.file_pos;
-        serde_json::to_writer(&mut *writer, &op)?;
-        writer.flush()?;
-        if let Some(old) = self.index.get(&key) {
-            self.un_compact_size.fetch_add(old.value().size as u64, SeqCst);
-        }
-        self.index.insert(key, Pos { id: self.cur_file_id.load(Ordering::Relaxed), off, size: (writer.file_pos - off) as u16 });
-        if self.un_compact_size.load(Ordering::Relaxed) > MAX_UN_COMPACT {
-            *writer = self.compact()?;
+        let due = {
+            let writer_ref = self.writer.lock().unwrap();
+            let mut writer = writer_ref.borrow_mut();
+            self.append_set(&mut writer, key, &record)?;
+            self.after_write(&writer)?;
+            self.compaction_due(&writer)
+        };
+        self.spawn_gc();
+        if due {
+            self.spawn_compaction();
         }
         Ok(())
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(entry) = self.index.get(&key) {
-            let pos = entry.value();
-            let mut reader_map = self.reader.borrow_mut();
-            let reader = reader_map.entry(pos.id).or_insert(BufferReader::new(format_path(&self.path, pos.id))?);
-            reader.seek(SeekFrom::Start(pos.off as u64))?;
-            let mut reader = reader.take(pos.size as u64);
-            if let Set { value, .. } = serde_json::from_reader(&mut reader)? {
-                return Ok(Some(value));
-            }
+        match self.index.get(&key) {
+            Some(pos) => self.read_value(&key, &pos),
+            None => Ok(None),
         }
-        Ok(None)
     }
 
     fn remove(&self, key: String) -> Result<()> {
         //println!("{:?}",&self.index);
-        let writer_ref = self.writer.lock().unwrap();
-        let mut writer = writer_ref.borrow_mut();
-        if let Some(entry) = self.index.remove(&key) {
+        let due = {
+            let writer_ref = self.writer.lock().unwrap();
+            let mut writer = writer_ref.borrow_mut();
+            if let Some(ref cache) = self.cache {
+                cache.remove(&key);
+            }
+            let old = self.index.remove(&key, |pos| self.record_key(pos))?.ok_or(KeyNotFound)?;
             // rm index
-            self.un_compact_size.fetch_add(entry.value().size as u64, Ordering::SeqCst);
+            self.un_compact_size.fetch_add(old.size as u64, Ordering::SeqCst);
             // append rm log
-            let op = Remove { key };
-            serde_json::to_writer(&mut *writer, &op)?;
+            let record = self.pack(Remove { key })?;
+            let off = writer.file_pos;
+            serde_json::to_writer(&mut *writer, &record)?;
             writer.flush()?;
-            if self.un_compact_size.load(Ordering::Relaxed) > MAX_UN_COMPACT {
-                *writer = self.compact()?;
-            }
+            self.metrics.bytes_written.add((writer.file_pos - off) as u64);
 
             //println!("{:?}",&self.index);
-            Ok(())
-        } else {
-            Err(KeyNotFound)
+            self.after_write(&writer)?;
+            self.compaction_due(&writer)
+        };
+        if due {
+            self.spawn_compaction();
+        }
+        Ok(())
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        if self.index.mode() == IndexMode::Hashed {
+            return self.scan_hashed(start, limit);
+        }
+        let mut pairs = Vec::new();
+        for (key, pos) in self.index.range(start, limit) {
+            if let Some(value) = self.read_value(&key, &pos)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// sealed generations and blob files are hard linked, the active ones are
+    /// copied up to the offset they had when the backup started, so writes
+    /// never block for long
+    fn backup(&self, dest: &Path) -> Result<()> {
+        backup::prepare_dest(dest)?;
+        let (cur, active, len, active_blob) = {
+            let writer_ref = self.writer.lock().unwrap();
+            let mut writer = writer_ref.borrow_mut();
+            writer.flush()?;
+            let mut blobs = self.blobs.lock().unwrap();
+            blobs.sync()?;
+            let position = blobs.position();
+            for id in blob::ls_blobs(&self.path)? {
+                if position.map(|(active, _)| active) != Some(id) {
+                    let (from, to) = (blob::blob_path(&self.path, id), blob::blob_path(dest, id));
+                    if fs::hard_link(&from, &to).is_err() {
+                        fs::copy(&from, &to)?;
+                    }
+                }
+            }
+            let active_blob = match position {
+                Some((id, len)) => Some((id, File::open(blob::blob_path(&self.path, id))?, len)),
+                None => None,
+            };
+            let cur = self.cur_file_id.load(SeqCst);
+            for id in ls_logs(&self.path) {
+                if id < cur {
+                    let (from, to) = (format_path(&self.path, id), format_path(dest, id));
+                    if fs::hard_link(&from, &to).is_err() {
+                        fs::copy(&from, &to)?;
+                    }
+                }
+            }
+            // the handle keeps the file readable even if a compaction deletes it
+            (cur, File::open(format_path(&self.path, cur))?, writer.file_pos, active_blob)
+        };
+        let mut out = File::create(format_path(dest, cur))?;
+        io::copy(&mut active.take(len as u64), &mut out)?;
+        out.sync_all()?;
+        if let Some((id, file, len)) = active_blob {
+            let mut out = File::create(blob::blob_path(dest, id))?;
+            io::copy(&mut file.take(len), &mut out)?;
+            out.sync_all()?;
         }
+        backup::seal(dest, "kvs")
     }
-}
\ No newline at end of file
+
+    /// the keys of an encrypted backup come from `KVS_ENCRYPTION_KEYS`, as
+    /// for the other engines, `restore_with` takes them from the options
+    fn restore(src: &Path, path: &Path) -> Result<KvStore> {
+        let mut options = KvStoreOptions::new();
+        if let Some(keys) = Keyring::from_env()? {
+            options = options.encryption(keys);
+        }
+        KvStore::restore_with(src, path, options)
+    }
+
+    /// rewrite the live records into a new log whatever the policy says,
+    /// at full speed, after any compaction already running
+    fn compact(&self) -> Result<()> {
+        self.compact_logs(false)
+    }
+
+    fn info(&self) -> Result<EngineInfo> {
+        Ok(EngineInfo {
+            engine: "kvs".to_owned(),
+            keys: self.index.len() as u64,
+            disk_bytes: dir_size(&self.path)?,
+            dead_bytes: Some(self.un_compact_size.load(Relaxed)),
+            generation: Some(self.cur_file_id.load(Relaxed) as u64),
+        })
+    }
+}
+
+/// read the record at `pos` of the logs in `path`, without the handle pool
+fn read_op(path: &Path, pos: &Pos, keys: Option<&Keyring>) -> Result<Record> {
+    let mut file = File::open(format_path(path, pos.id))?;
+    file.seek(SeekFrom::Start(pos.off as u64))?;
+    crypto::unseal(serde_json::from_reader(file.take(pos.size as u64))?, keys)
+}
+
+
+/// the log id after `id`, an error once the ids run out
+fn next_id(id: u16) -> Result<u16> {
+    id.checked_add(1).ok_or_else(|| KvsError::StringError(format!("no log id left after {}.log", id)))
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::fnv1a;
+
+const BITS_PER_KEY: usize = 10;
+/// about the best number of probes for 10 bits per key, 1% false positives
+const PROBES: u32 = 7;
+
+/// A bloom filter over the keys of a table, so most lookups of a missing key
+/// don't read any block.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    /// a filter sized for the keys hashed by `hash`
+    pub fn new(hashes: &[u64]) -> Self {
+        let words = (hashes.len() * BITS_PER_KEY).div_ceil(64).max(1);
+        let mut bloom = Bloom { bits: vec![0; words] };
+        for h in hashes {
+            for bit in bloom.probes(*h) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    pub fn hash(key: &str) -> u64 {
+        fnv1a(key.as_bytes())
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.probes(Self::hash(key)).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// double hashing, the second hash is derived from the first
+    fn probes(&self, h: u64) -> impl Iterator<Item = usize> {
+        let n = (self.bits.len() * 64) as u64;
+        let delta = h.rotate_right(17) | 1;
+        (0..PROBES as u64).map(move |i| (h.wrapping_add(i.wrapping_mul(delta)) % n) as usize)
+    }
+}
@@ -0,0 +1,57 @@
+use crate::Result;
+use crate::dbengines::lsm::sstable::Entry;
+
+pub type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// Merges sorted sources into one, newest source first: when several hold
+/// a key, the entry of the first one wins and the others are skipped.
+pub struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    heads: Vec<Option<Entry>>,
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(mut sources: Vec<Source<'a>>) -> Result<Merge<'a>> {
+        let mut heads = Vec::with_capacity(sources.len());
+        for source in sources.iter_mut() {
+            heads.push(source.next().transpose()?);
+        }
+        Ok(Merge { sources, heads })
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let mut min: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                if min.is_none_or(|m| *key < self.heads[m].as_ref().unwrap().0) {
+                    min = Some(i);
+                }
+            }
+        }
+        let winner = match min {
+            Some(winner) => winner,
+            None => return Ok(None),
+        };
+        let entry = self.heads[winner].take().unwrap();
+        for i in winner + 1..self.heads.len() {
+            if self.heads[i].as_ref().is_some_and(|(key, _)| *key == entry.0) {
+                self.advance(i)?;
+            }
+        }
+        self.advance(winner)?;
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        self.next_entry().transpose()
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{KvsEngine, Op, Result};
+use crate::dbengines::{backup, scan_each, EngineInfo};
+use crate::error::KvsError;
+use crate::error::KvsError::KeyNotFound;
+use crate::utils::dir_size;
+
+use self::merge::{Merge, Source};
+use self::sstable::{table_path, SsTable, TableBuilder};
+
+mod bloom;
+mod merge;
+mod sstable;
+
+const WAL: &str = "lsm.wal";
+const MANIFEST: &str = "LSM_MANIFEST";
+
+/// Tunables of a `LsmEngine`, see `LsmEngine::open_with`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    memtable_size: usize,
+    level0_tables: usize,
+    table_size: u64,
+    level1_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 << 20,
+            level0_tables: 4,
+            table_size: 2 << 20,
+            level1_size: 10 << 20,
+        }
+    }
+}
+
+impl LsmOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// bytes of keys and values buffered in memory before they are written to a table
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// level 0 tables, which overlap each other, allowed before they are merged into level 1
+    pub fn level0_tables(mut self, tables: usize) -> Self {
+        self.level0_tables = tables;
+        self
+    }
+
+    /// size of the tables written by compaction
+    pub fn table_size(mut self, bytes: u64) -> Self {
+        self.table_size = bytes;
+        self
+    }
+
+    /// size of level 1, every deeper level may be 10 times larger than the one above
+    pub fn level1_size(mut self, bytes: u64) -> Self {
+        self.level1_size = bytes;
+        self
+    }
+}
+
+/// The tables of every level, saved after each flush and compaction.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+#[derive(Debug)]
+struct State {
+    mem: BTreeMap<String, Option<String>>,
+    mem_size: usize,
+    wal: BufWriter<File>,
+    /// level 0 newest first, deeper levels sorted by key without overlap
+    levels: Vec<Vec<Arc<SsTable>>>,
+    next_id: u64,
+}
+
+/// A log-structured merge tree.
+///
+/// Writes go to a write-ahead log and a sorted memtable. A full memtable is
+/// written to a level 0 table, and leveled compaction merges tables down
+/// into larger levels where no two tables overlap.
+#[derive(Clone, Debug)]
+pub struct LsmEngine {
+    path: Arc<PathBuf>,
+    options: LsmOptions,
+    state: Arc<RwLock<State>>,
+}
+
+impl LsmEngine {
+    pub fn open(path: &Path) -> Result<LsmEngine> {
+        Self::open_with(path, LsmOptions::default())
+    }
+
+    pub fn open_with(path: &Path, options: LsmOptions) -> Result<LsmEngine> {
+        fs::create_dir_all(path)?;
+        let manifest: Manifest = match fs::read(path.join(MANIFEST)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut levels = Vec::new();
+        for ids in manifest.levels.iter() {
+            let mut level = Vec::new();
+            for id in ids {
+                level.push(Arc::new(SsTable::open(path, *id)?));
+            }
+            levels.push(level);
+        }
+        remove_unused_tables(path, &manifest)?;
+
+        // replay the writes not in a table yet, a torn last record is dropped
+        let mut mem = BTreeMap::new();
+        let mut mem_size = 0;
+        if let Ok(file) = File::open(path.join(WAL)) {
+            for op in Deserializer::from_reader(BufReader::new(file)).into_iter::<Op>() {
+                let (key, value) = match op {
+                    Ok(Op::Set { key, value }) => (key, Some(value)),
+                    Ok(Op::Remove { key }) => (key, None),
+                    Err(e) => {
+                        warn!("ignore the end of {}: {}", WAL, e);
+                        break;
+                    }
+                };
+                insert_mem(&mut mem, &mut mem_size, key, value);
+            }
+        }
+        let wal = BufWriter::new(OpenOptions::new().create(true).append(true).open(path.join(WAL))?);
+        let state = State { mem, mem_size, wal, levels, next_id: manifest.next_id.max(1) };
+        Ok(LsmEngine { path: Arc::new(path.to_path_buf()), options, state: Arc::new(RwLock::new(state)) })
+    }
+
+    /// number of tables in each level
+    pub fn level_tables(&self) -> Vec<usize> {
+        self.state.read().unwrap().levels.iter().map(|l| l.len()).collect()
+    }
+
+    fn write(&self, key: String, value: Option<String>) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        let op = match value {
+            Some(ref value) => Op::Set { key: key.clone(), value: value.clone() },
+            None => Op::Remove { key: key.clone() },
+        };
+        serde_json::to_writer(&mut state.wal, &op)?;
+        state.wal.flush()?;
+        let State { mem, mem_size, .. } = &mut *state;
+        insert_mem(mem, mem_size, key, value);
+        if state.mem_size >= self.options.memtable_size {
+            self.flush(&mut state)?;
+            self.compact_levels(&mut state)?;
+        }
+        Ok(())
+    }
+
+    /// write the memtable to a level 0 table and start a new log
+    fn flush(&self, state: &mut State) -> Result<()> {
+        if !state.mem.is_empty() {
+            let mut builder = TableBuilder::new(&self.path, state.next_id)?;
+            state.next_id += 1;
+            for (key, value) in state.mem.iter() {
+                builder.add(key, value.as_deref())?;
+            }
+            let table = Arc::new(builder.finish()?);
+            if state.levels.is_empty() {
+                state.levels.push(Vec::new());
+            }
+            state.levels[0].insert(0, table);
+            self.save_manifest(state)?;
+        }
+        state.mem.clear();
+        state.mem_size = 0;
+        state.wal = BufWriter::new(File::create(self.path.join(WAL))?);
+        Ok(())
+    }
+
+    /// merge levels down until every level is within its size
+    fn compact_levels(&self, state: &mut State) -> Result<()> {
+        loop {
+            let level = if state.levels.first().is_some_and(|l| l.len() > self.options.level0_tables) {
+                0
+            } else {
+                match (1..state.levels.len()).find(|n| level_size(&state.levels[*n]) > self.max_level_size(*n)) {
+                    Some(n) => n,
+                    None => return Ok(()),
+                }
+            };
+            self.compact_level(state, level)?;
+        }
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        self.options.level1_size.saturating_mul(10u64.saturating_pow(level as u32 - 1))
+    }
+
+    /// merge level 0, or the first table of a deeper level, with the tables
+    /// of the next level it overlaps
+    fn compact_level(&self, state: &mut State, level: usize) -> Result<()> {
+        if state.levels.len() <= level + 1 {
+            state.levels.push(Vec::new());
+        }
+        let upper: Vec<Arc<SsTable>> = if level == 0 {
+            std::mem::take(&mut state.levels[0])
+        } else {
+            vec![state.levels[level].remove(0)]
+        };
+        let first = upper.iter().map(|t| t.first_key()).min().unwrap().to_owned();
+        let last = upper.iter().map(|t| t.last_key()).max().unwrap().to_owned();
+        let (lower, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut state.levels[level + 1])
+            .into_iter()
+            .partition(|t| t.last_key() >= first.as_str() && t.first_key() <= last.as_str());
+
+        // tombstones can go once nothing older lies below the output level
+        let bottom = state.levels[level + 2..].iter().all(|l| l.is_empty());
+        let sources: Vec<Source> = upper.iter().chain(lower.iter())
+            .map(|t| Box::new(t.iter_from("")) as Source)
+            .collect();
+        let mut output = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in Merge::new(sources)? {
+            let (key, value) = entry?;
+            if value.is_none() && bottom {
+                continue;
+            }
+            if builder.is_none() {
+                builder = Some(TableBuilder::new(&self.path, state.next_id)?);
+                state.next_id += 1;
+            }
+            let b = builder.as_mut().unwrap();
+            b.add(&key, value.as_deref())?;
+            if b.size() >= self.options.table_size {
+                output.push(Arc::new(builder.take().unwrap().finish()?));
+            }
+        }
+        if let Some(b) = builder {
+            output.push(Arc::new(b.finish()?));
+        }
+
+        let mut next = kept;
+        next.extend(output);
+        next.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        state.levels[level + 1] = next;
+        self.save_manifest(state)?;
+        for table in upper.iter().chain(lower.iter()) {
+            fs::remove_file(table_path(&self.path, table.id()))?;
+        }
+        Ok(())
+    }
+
+    fn save_manifest(&self, state: &State) -> Result<()> {
+        let manifest = Manifest {
+            next_id: state.next_id,
+            levels: state.levels.iter().map(|l| l.iter().map(|t| t.id()).collect()).collect(),
+        };
+        let tmp = self.path.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&manifest)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.path.join(MANIFEST))?;
+        Ok(())
+    }
+
+    fn lookup(state: &State, key: &str) -> Result<Option<String>> {
+        if let Some(value) = state.mem.get(key) {
+            return Ok(value.clone());
+        }
+        for (n, level) in state.levels.iter().enumerate() {
+            let candidates: &[Arc<SsTable>] = if n == 0 {
+                level
+            } else {
+                let i = level.partition_point(|t| t.last_key() < key);
+                &level[i..level.len().min(i + 1)]
+            };
+            for table in candidates {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl KvsEngine for LsmEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(key, Some(value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Self::lookup(&self.state.read().unwrap(), &key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        // the check and the tombstone are not atomic, a concurrent remove
+        // of the same key just writes a second tombstone
+        if Self::lookup(&self.state.read().unwrap(), &key)?.is_none() {
+            return Err(KeyNotFound);
+        }
+        self.write(key, None)
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let state = self.state.read().unwrap();
+        let mut sources: Vec<Source> = vec![Box::new(state.mem.range(start.clone()..).map(|(k, v)| Ok((k.clone(), v.clone()))))];
+        for table in state.levels.iter().flatten() {
+            if table.last_key() >= start.as_str() {
+                sources.push(Box::new(table.iter_from(&start)));
+            }
+        }
+        let mut pairs = Vec::new();
+        for entry in Merge::new(sources)? {
+            if pairs.len() >= limit {
+                break;
+            }
+            if let (key, Some(value)) = entry? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// like sled, writes racing with the backup may or may not be in it
+    fn backup(&self, dest: &Path) -> Result<()> {
+        backup::write_engine(self, dest, "lsm")
+    }
+
+    fn restore(src: &Path, path: &Path) -> Result<LsmEngine> {
+        backup::verify(src)?;
+        let engine = LsmEngine::open(path)?;
+        if !engine.scan(String::new(), 1)?.is_empty() {
+            return Err(KvsError::StringError(format!("{} already holds data", path.display())));
+        }
+        backup::replay(src, |op| match op {
+            Op::Set { key, value } => engine.set(key, value),
+            Op::Remove { key } => engine.remove(key).or_else(|e| match e {
+                KeyNotFound => Ok(()),
+                e => Err(e),
+            }),
+        })?;
+        Ok(engine)
+    }
+
+    /// flush the memtable and merge every level into the deepest, which
+    /// drops overwritten values and tombstones
+    fn compact(&self) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        self.flush(&mut state)?;
+        let deepest = match state.levels.iter().rposition(|l| !l.is_empty()) {
+            Some(deepest) => deepest.max(1),
+            None => return Ok(()),
+        };
+        for level in 0..deepest {
+            while !state.levels[level].is_empty() {
+                self.compact_level(&mut state, level)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// keys are counted by a full scan, tombstones hide keys in older tables
+    fn info(&self) -> Result<EngineInfo> {
+        let mut keys = 0;
+        scan_each(self, |_, _| {
+            keys += 1;
+            Ok(())
+        })?;
+        Ok(EngineInfo { engine: "lsm".to_owned(), keys, disk_bytes: dir_size(&self.path)?, ..EngineInfo::default() })
+    }
+}
+
+/// put a write in the memtable, `mem_size` counts the entries it holds
+/// now, an overwritten one no longer
+fn insert_mem(mem: &mut BTreeMap<String, Option<String>>, mem_size: &mut usize, key: String, value: Option<String>) {
+    *mem_size += entry_size(&key, &value);
+    if let Some(old) = mem.get(&key) {
+        *mem_size -= entry_size(&key, old);
+    }
+    mem.insert(key, value);
+}
+
+/// bytes a memtable entry accounts for
+fn entry_size(key: &str, value: &Option<String>) -> usize {
+    key.len() + value.as_ref().map_or(0, |v| v.len()) + 16
+}
+
+fn level_size(level: &[Arc<SsTable>]) -> u64 {
+    level.iter().map(|t| t.size()).sum()
+}
+
+/// tables left by a flush or compaction that crashed before the manifest was saved
+fn remove_unused_tables(path: &Path, manifest: &Manifest) -> Result<()> {
+    let used: HashSet<String> = manifest.levels.iter().flatten().map(|id| format!("{}.sst", id)).collect();
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if (name.ends_with(".sst") || name.ends_with(".sst.tmp")) && !used.contains(&name) {
+            fs::remove_file(path.join(name))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::dbengines::lsm::bloom::Bloom;
+use crate::error::KvsError;
+
+/// a key and its value, `None` for a tombstone
+pub type Entry = (String, Option<String>);
+
+const BLOCK_SIZE: usize = 4096;
+const MAGIC: u64 = 0x6b76_735f_6c73_6d31;
+/// meta length and magic
+const FOOTER_SIZE: u64 = 16;
+
+pub fn table_path(path: &Path, id: u64) -> PathBuf {
+    path.join(format!("{}.sst", id))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: String,
+    off: u64,
+    len: u32,
+    crc32: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Meta {
+    first_key: String,
+    last_key: String,
+    blocks: Vec<BlockHandle>,
+    bloom: Bloom,
+}
+
+/// An immutable sorted table.
+///
+/// Entries are packed into checksummed blocks of about `BLOCK_SIZE` bytes,
+/// followed by the block index and the bloom filter as JSON, and a footer
+/// with the length of that meta data.
+#[derive(Debug)]
+pub struct SsTable {
+    id: u64,
+    file: Mutex<File>,
+    size: u64,
+    meta: Meta,
+}
+
+impl SsTable {
+    pub fn open(path: &Path, id: u64) -> Result<SsTable> {
+        let mut file = File::open(table_path(path, id))?;
+        let size = file.metadata()?.len();
+        let corrupted = || KvsError::StringError(format!("{}.sst is corrupted", id));
+        if size < FOOTER_SIZE {
+            return Err(corrupted());
+        }
+        let mut footer = [0; FOOTER_SIZE as usize];
+        read_at(&mut file, &mut footer, size - FOOTER_SIZE)?;
+        let meta_len = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        if u64::from_le_bytes(footer[8..].try_into().unwrap()) != MAGIC || meta_len > size - FOOTER_SIZE {
+            return Err(corrupted());
+        }
+        let mut meta = vec![0; meta_len as usize];
+        read_at(&mut file, &mut meta, size - FOOTER_SIZE - meta_len)?;
+        let meta = serde_json::from_slice(&meta)?;
+        Ok(SsTable { id, file: Mutex::new(file), size, meta })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn first_key(&self) -> &str {
+        &self.meta.first_key
+    }
+
+    pub fn last_key(&self) -> &str {
+        &self.meta.last_key
+    }
+
+    /// the entry of `key` if the table has one, it may be a tombstone
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key() || key > self.last_key() || !self.meta.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let i = self.meta.blocks.partition_point(|b| b.last_key.as_str() < key);
+        if i == self.meta.blocks.len() {
+            return Ok(None);
+        }
+        let block = self.read_block(i)?;
+        Ok(block.into_iter().find(|(k, _)| k == key).map(|(_, v)| v))
+    }
+
+    /// the entries from the first key >= `start`, in order
+    pub fn iter_from(self: &Arc<Self>, start: &str) -> TableIter {
+        let block = self.meta.blocks.partition_point(|b| b.last_key.as_str() < start);
+        TableIter { table: self.clone(), block, entries: VecDeque::new(), start: start.to_owned() }
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<Entry>> {
+        let handle = &self.meta.blocks[i];
+        let mut data = vec![0; handle.len as usize];
+        read_at(&mut self.file.lock().unwrap(), &mut data, handle.off)?;
+        if crc32fast::hash(&data) != handle.crc32 {
+            return Err(KvsError::StringError(format!("block {} of {}.sst is corrupted", i, self.id)));
+        }
+        decode_block(&data).ok_or_else(|| KvsError::StringError(format!("block {} of {}.sst is corrupted", i, self.id)))
+    }
+}
+
+/// Reads the blocks of a table one at a time.
+pub struct TableIter {
+    table: Arc<SsTable>,
+    block: usize,
+    entries: VecDeque<Entry>,
+    start: String,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        while self.entries.is_empty() {
+            if self.block >= self.table.meta.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => {
+                    let start = &self.start;
+                    self.entries = entries.into_iter().filter(|(k, _)| k >= start).collect();
+                }
+                Err(e) => {
+                    self.block = self.table.meta.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.block += 1;
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+/// Writes the entries of a new table, given in key order.
+pub struct TableBuilder {
+    path: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    block: Vec<u8>,
+    off: u64,
+    first_key: Option<String>,
+    last_key: String,
+    blocks: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    /// the table is written aside and renamed by `finish`
+    pub fn new(path: &Path, id: u64) -> Result<TableBuilder> {
+        let tmp = path.join(format!("{}.sst.tmp", id));
+        Ok(TableBuilder {
+            path: path.to_path_buf(),
+            id,
+            writer: BufWriter::new(File::create(tmp)?),
+            block: Vec::with_capacity(BLOCK_SIZE * 2),
+            off: 0,
+            first_key: None,
+            last_key: String::new(),
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_owned());
+        }
+        self.hashes.push(Bloom::hash(key));
+        encode_entry(&mut self.block, key, value);
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// bytes written so far
+    pub fn size(&self) -> u64 {
+        self.off + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.blocks.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            off: self.off,
+            len: self.block.len() as u32,
+            crc32: crc32fast::hash(&self.block),
+        });
+        self.off += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SsTable> {
+        self.finish_block()?;
+        let meta = Meta {
+            first_key: self.first_key.take().unwrap_or_default(),
+            last_key: std::mem::take(&mut self.last_key),
+            blocks: std::mem::take(&mut self.blocks),
+            bloom: Bloom::new(&self.hashes),
+        };
+        let meta = serde_json::to_vec(&meta)?;
+        self.writer.write_all(&meta)?;
+        self.writer.write_all(&(meta.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&MAGIC.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(self.path.join(format!("{}.sst.tmp", self.id)), table_path(&self.path, self.id))?;
+        SsTable::open(&self.path, self.id)
+    }
+}
+
+fn read_at(file: &mut File, buf: &mut [u8], off: u64) -> Result<()> {
+    file.seek(SeekFrom::Start(off))?;
+    file.read_exact(buf)?;
+    Ok(())
+}
+
+/// key length, key, a tag byte, then value length and value for a set
+fn encode_entry(buf: &mut Vec<u8>, key: &str, value: Option<&str>) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    match value {
+        Some(value) => {
+            buf.push(1);
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value.as_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn decode_block(mut data: &[u8]) -> Option<Vec<Entry>> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        if data.len() < n {
+            return None;
+        }
+        let (head, tail) = data.split_at(n);
+        *data = tail;
+        Some(head)
+    }
+    fn string(data: &mut &[u8]) -> Option<String> {
+        let len = u32::from_le_bytes(take(data, 4)?.try_into().ok()?) as usize;
+        String::from_utf8(take(data, len)?.to_vec()).ok()
+    }
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let key = string(&mut data)?;
+        let value = match take(&mut data, 1)?[0] {
+            1 => Some(string(&mut data)?),
+            0 => None,
+            _ => return None,
+        };
+        entries.push((key, value));
+    }
+    Some(entries)
+}
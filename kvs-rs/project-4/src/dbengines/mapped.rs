@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use memmap2::Mmap;
+
+use crate::Result;
+use crate::utils::format_path;
+
+/// Read-only mappings of the sealed generations of a `KvStore`, made on
+/// first read and shared by every clone. Only logs that are never written
+/// again are mapped, a mapping outlives its file being deleted by compaction.
+#[derive(Debug, Default)]
+pub struct Mappings {
+    maps: RwLock<HashMap<u16, Arc<Mmap>>>,
+}
+
+impl Mappings {
+    /// the mapping of log `id` of `path`, which must be sealed
+    pub fn get(&self, path: &Path, id: u16) -> Result<Arc<Mmap>> {
+        if let Some(map) = self.maps.read().unwrap().get(&id) {
+            return Ok(map.clone());
+        }
+        let mut maps = self.maps.write().unwrap();
+        if let Some(map) = maps.get(&id) {
+            return Ok(map.clone());
+        }
+        let file = File::open(format_path(path, id))?;
+        // SAFETY: sealed logs are never written or truncated while the store
+        // is open, `kvs-admin repair` requires the server to be stopped
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        maps.insert(id, map.clone());
+        Ok(map)
+    }
+
+    /// drop the mappings of the logs before `id`, once compaction deleted them
+    pub fn release_before(&self, id: u16) {
+        self.maps.write().unwrap().retain(|map_id, _| *map_id >= id);
+    }
+}
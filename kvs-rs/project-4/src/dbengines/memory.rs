@@ -0,0 +1,176 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_skiplist::SkipMap;
+use log::{error, info};
+use serde_json::Deserializer;
+
+use crate::{KvsEngine, Op, Result};
+use crate::dbengines::{backup, EngineInfo};
+use crate::error::KvsError;
+use crate::error::KvsError::KeyNotFound;
+
+const SNAPSHOT: &str = "memory.snapshot";
+
+/// Tunables of a `MemoryEngine`, see `MemoryEngine::open_with`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOptions {
+    snapshot_every: Option<Duration>,
+}
+
+impl MemoryOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// write a snapshot from a background thread at this interval
+    pub fn snapshot_every(mut self, interval: Duration) -> Self {
+        self.snapshot_every = Some(interval);
+        self
+    }
+}
+
+/// Every pair in a concurrent skip map.
+///
+/// Made by `new`, nothing ever touches disk. Opened on a directory, it
+/// loads the snapshot found there and `snapshot` replaces it, so writes
+/// since the last snapshot are lost when the process dies.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryEngine {
+    map: Arc<SkipMap<String, String>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> MemoryEngine {
+        Self::default()
+    }
+
+    pub fn open(path: &Path) -> Result<MemoryEngine> {
+        Self::open_with(path, MemoryOptions::default())
+    }
+
+    pub fn open_with(path: &Path, options: MemoryOptions) -> Result<MemoryEngine> {
+        fs::create_dir_all(path)?;
+        let map = SkipMap::new();
+        if let Ok(file) = File::open(path.join(SNAPSHOT)) {
+            // a snapshot is renamed into place once whole, any error is corruption
+            for op in Deserializer::from_reader(BufReader::new(file)).into_iter::<Op>() {
+                match op? {
+                    Op::Set { key, value } => map.insert(key, value),
+                    Op::Remove { .. } => return Err(KvsError::StringError(format!("{} holds a remove", SNAPSHOT))),
+                };
+            }
+            info!("Loaded {} keys from {}", map.len(), SNAPSHOT);
+        }
+        let engine = MemoryEngine { map: Arc::new(map), path: Some(Arc::new(path.to_path_buf())) };
+        if let Some(interval) = options.snapshot_every {
+            let map = Arc::downgrade(&engine.map);
+            let path = path.to_path_buf();
+            thread::Builder::new().name("memory-snapshot".to_string()).spawn(move || snapshot_loop(map, path, interval))?;
+        }
+        Ok(engine)
+    }
+
+    /// Replace the snapshot with the current pairs, a no-op without a directory.
+    /// Writes racing with it may or may not be in it.
+    pub fn snapshot(&self) -> Result<()> {
+        match self.path {
+            Some(ref path) => write_snapshot(&self.map, path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).map(|e| e.value().clone()))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.map.remove(&key).map(|_| ()).ok_or(KeyNotFound)
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        Ok(self.map.range(start..).take(limit).map(|e| (e.key().clone(), e.value().clone())).collect())
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        backup::write_engine(self, dest, "memory")
+    }
+
+    /// the restored pairs are snapshotted into `path` right away
+    fn restore(src: &Path, path: &Path) -> Result<MemoryEngine> {
+        backup::verify(src)?;
+        let engine = MemoryEngine::open(path)?;
+        if !engine.is_empty() {
+            return Err(KvsError::StringError(format!("{} already holds data", path.display())));
+        }
+        backup::replay(src, |op| match op {
+            Op::Set { key, value } => engine.set(key, value),
+            Op::Remove { key } => engine.remove(key).or_else(|e| match e {
+                KeyNotFound => Ok(()),
+                e => Err(e),
+            }),
+        })?;
+        engine.snapshot()?;
+        Ok(engine)
+    }
+
+    /// nothing to reclaim, removed pairs are gone from the map
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// the disk bytes are those of the snapshot
+    fn info(&self) -> Result<EngineInfo> {
+        let disk_bytes = match self.path {
+            Some(ref path) => fs::metadata(path.join(SNAPSHOT)).map_or(0, |m| m.len()),
+            None => 0,
+        };
+        Ok(EngineInfo { engine: "memory".to_owned(), keys: self.map.len() as u64, disk_bytes, ..EngineInfo::default() })
+    }
+}
+
+/// snapshot until every engine sharing the map is dropped
+fn snapshot_loop(map: Weak<SkipMap<String, String>>, path: PathBuf, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let map = match map.upgrade() {
+            Some(map) => map,
+            None => return,
+        };
+        if let Err(e) = write_snapshot(&map, &path) {
+            error!("Snapshot failed: {}", e);
+        }
+    }
+}
+
+fn write_snapshot(map: &SkipMap<String, String>, path: &Path) -> Result<()> {
+    let tmp = path.join(format!("{}.tmp", SNAPSHOT));
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for entry in map.iter() {
+        serde_json::to_writer(&mut writer, &Op::Set { key: entry.key().clone(), value: entry.value().clone() })?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp, path.join(SNAPSHOT))?;
+    Ok(())
+}
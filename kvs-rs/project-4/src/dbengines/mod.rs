@@ -1,16 +1,115 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
 use crate::Result;
+use crate::error::KvsError;
 
-pub use self::kv::KvStore;
+pub use self::btree::{BTreeEngine, BTreeOptions};
+pub use self::cache::CacheStats;
+pub use self::compaction::CompactionPolicy;
+pub use self::compress::Codec;
+pub use self::crypto::Keyring;
+pub use self::index::IndexMode;
+pub use self::kv::{KvStore, KvStoreOptions};
+pub use self::lsm::{LsmEngine, LsmOptions};
+pub use self::memory::{MemoryEngine, MemoryOptions};
 pub use self::sled::SledKvsEngine;
 
 mod kv;
 mod sled;
+mod lsm;
+mod btree;
+mod memory;
 mod common;
+mod compress;
+mod cache;
+mod compaction;
+mod mapped;
+mod handles;
+pub(crate) mod blob;
+pub(crate) mod crypto;
+mod backup;
+pub(crate) mod hint;
+pub(crate) mod checkpoint;
+mod index;
+
+pub use self::common::Op;
+pub(crate) use self::common::Record;
 
 
 pub trait KvsEngine: Clone + Send {
     fn set(&self, key: String, value: String) -> Result<()>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
+    /// return at most `limit` pairs in key order, starting from the first key >= `start`
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>>;
+    /// copy a consistent, checksummed snapshot of the data into the empty directory `dest`,
+    /// unsupported unless the engine implements it
+    fn backup(&self, _dest: &Path) -> Result<()> {
+        Err(KvsError::StringError("backup is not supported by this engine".to_string()))
+    }
+    /// verify the backup in `src` and load it into a new engine in `path`, which must hold no data,
+    /// unsupported unless the engine implements it
+    fn restore(_src: &Path, _path: &Path) -> Result<Self> where Self: Sized {
+        Err(KvsError::StringError("restore is not supported by this engine".to_string()))
+    }
+    /// reclaim the space of overwritten and removed pairs now, engines which
+    /// reclaim it on their own just make their writes durable
+    fn compact(&self) -> Result<()>;
+    /// what the engine reports about itself, for `Request::Info`, by default
+    /// its type name and the keys a full scan counts
+    fn info(&self) -> Result<EngineInfo> {
+        let mut keys = 0;
+        scan_each(self, |_, _| {
+            keys += 1;
+            Ok(())
+        })?;
+        Ok(EngineInfo { engine: std::any::type_name::<Self>().to_owned(), keys, ..EngineInfo::default() })
+    }
+}
+
+/// The state of an engine, as `kvs-client info` shows it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EngineInfo {
+    /// the `--engine` name
+    pub engine: String,
+    pub keys: u64,
+    /// bytes of the engine's files, 0 for an engine without a directory
+    pub disk_bytes: u64,
+    /// bytes of dead records awaiting compaction, for engines tracking them
+    pub dead_bytes: Option<u64>,
+    /// the id of the file new records go to, for log-structured engines
+    pub generation: Option<u64>,
 }
 
+impl EngineInfo {
+    /// dead bytes over disk bytes, if the engine tracks dead bytes
+    pub fn dead_ratio(&self) -> Option<f64> {
+        self.dead_bytes.map(|dead| match self.disk_bytes {
+            0 => 0.0,
+            disk => dead as f64 / disk as f64,
+        })
+    }
+}
+
+
+/// visit every pair of `engine` in key order, a page at a time
+pub(crate) fn scan_each<E, F>(engine: &E, mut f: F) -> Result<()>
+    where E: KvsEngine, F: FnMut(String, String) -> Result<()> {
+    const PAGE: usize = 1000;
+    let mut start = String::new();
+    loop {
+        let page = engine.scan(start, PAGE)?;
+        let done = page.len() < PAGE;
+        let last = page.last().map(|(k, _)| k.clone());
+        for (key, value) in page {
+            f(key, value)?;
+        }
+        match last {
+            // the smallest key greater than the last one
+            Some(last) if !done => start = last + "\0",
+            _ => return Ok(()),
+        }
+    }
+}
@@ -2,7 +2,9 @@ use std::path::Path;
 
 use sled::Db;
 
-use crate::{KvsEngine, Result};
+use crate::{KvsEngine, Op, Result};
+use crate::dbengines::{backup, EngineInfo};
+use crate::error::KvsError;
 use crate::error::KvsError::KeyNotFound;
 
 #[derive(Clone, Debug)]
@@ -16,6 +18,12 @@ impl SledKvsEngine {
     pub fn open(p: &Path) -> Result<Self> {
         Ok(Self(sled::open(p)?))
     }
+
+    /// make every write so far durable
+    pub fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
@@ -36,4 +44,48 @@ impl KvsEngine for SledKvsEngine {
         self.0.flush()?;
         Ok(())
     }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for kv in self.0.range(start..).take(limit) {
+            let (k, v) = kv?;
+            pairs.push((String::from_utf8(k.to_vec())?, String::from_utf8(v.to_vec())?));
+        }
+        Ok(pairs)
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        backup::write_engine(self, dest, "sled")
+    }
+
+    fn restore(src: &Path, path: &Path) -> Result<SledKvsEngine> {
+        backup::verify(src)?;
+        let db = sled::open(path)?;
+        if !db.is_empty() {
+            return Err(KvsError::StringError(format!("{} already holds data", path.display())));
+        }
+        backup::replay(src, |op| {
+            match op {
+                Op::Set { key, value } => db.insert(key, value.into_bytes())?,
+                Op::Remove { key } => db.remove(key)?,
+            };
+            Ok(())
+        })?;
+        db.flush()?;
+        Ok(SledKvsEngine(db))
+    }
+
+    /// sled reclaims space by itself
+    fn compact(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn info(&self) -> Result<EngineInfo> {
+        Ok(EngineInfo {
+            engine: "sled".to_owned(),
+            keys: self.0.len() as u64,
+            disk_bytes: self.0.size_on_disk()?,
+            ..EngineInfo::default()
+        })
+    }
 }
@@ -1,4 +1,5 @@
 use std::io;
+use std::net::SocketAddr;
 use std::string::FromUtf8Error;
 
 use failure::Fail;
@@ -25,6 +26,12 @@ pub enum KvsError {
     Utf8(#[cause] FromUtf8Error),
     #[fail(display = "{}", _0)]
     StringError(String),
+    /// A record is sealed with a key that was not given, or a different key under its id.
+    #[fail(display = "Wrong or missing encryption key {}", _0)]
+    WrongKey(u32),
+    /// The node is not the raft leader, the leader is given if known.
+    #[fail(display = "Not leader, leader is {:?}", _0)]
+    NotLeader(Option<SocketAddr>),
 }
 
 impl From<io::Error> for KvsError {
@@ -1,9 +1,15 @@
 pub use client::KvsClient;
 pub use dbengines::KvsEngine;
-pub use dbengines::KvStore;
+pub use dbengines::{EngineInfo, Op};
+pub use dbengines::{CacheStats, Codec, CompactionPolicy, IndexMode, Keyring, KvStore, KvStoreOptions};
+pub use dbengines::{BTreeEngine, BTreeOptions};
+pub use dbengines::{LsmEngine, LsmOptions};
+pub use dbengines::{MemoryEngine, MemoryOptions};
 pub use dbengines::SledKvsEngine;
 pub use error::Result;
-pub use server::KvsServer;
+pub use replication::ReplicaStatus;
+pub use server::{KvsServer, ServerInfo};
+pub use slowlog::SlowEntry;
 
 mod error;
 mod utils;
@@ -11,5 +17,11 @@ mod client;
 mod dbengines;
 mod server;
 mod msg;
+mod replication;
+mod slowlog;
+pub mod admin;
+pub mod metrics;
+pub mod raft;
+pub mod shard;
 pub mod thread_pool;
 
@@ -0,0 +1,266 @@
+//! Counters, gauges and histograms, rendered in the Prometheus text format.
+//!
+//! Metrics live in a `Registry`, usually the process wide `global()` one,
+//! and are looked up once by name and labels, then updated lock free.
+//! `serve` answers `GET /metrics` with every metric of a registry.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering::Relaxed};
+use std::thread;
+use std::time::Duration;
+
+use log::error;
+
+use crate::Result;
+
+/// upper bounds of the latency buckets, in seconds
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Relaxed);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Relaxed)
+    }
+}
+
+/// Counts observations in buckets by upper bound, plus their count and sum.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// one per bound and one past the last, not cumulative
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let i = self.bounds.iter().position(|b| secs <= *b).unwrap_or(self.bounds.len());
+        self.buckets[i].fetch_add(1, Relaxed);
+        self.count.fetch_add(1, Relaxed);
+        self.sum_nanos.fetch_add(d.as_nanos() as u64, Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Relaxed)
+    }
+}
+
+type GaugeFn = Box<dyn Fn() -> Option<i64> + Send + Sync>;
+
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    GaugeFn(GaugeFn),
+    Histogram(Arc<Histogram>),
+}
+
+impl Metric {
+    fn kind(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) | Metric::GaugeFn(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Family {
+    help: String,
+    /// by rendered labels, `command="get"`
+    series: BTreeMap<String, Metric>,
+}
+
+/// Metrics by name, each name a family of series told apart by labels.
+#[derive(Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<String, Family>>,
+}
+
+/// the registry of the process, the one `kvs-server` exports
+pub fn global() -> &'static Registry {
+    static GLOBAL: OnceLock<Registry> = OnceLock::new();
+    GLOBAL.get_or_init(Registry::default)
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Self::default()
+    }
+
+    /// the series `name{labels}`, made by `make` if it does not exist yet,
+    /// and handed to `get`
+    fn series<T, F, G>(&self, name: &str, help: &str, labels: &[(&str, &str)], make: F, get: G) -> T
+        where F: FnOnce() -> Metric, G: FnOnce(&Metric) -> Option<T> {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name.to_owned()).or_insert_with(|| Family { help: help.to_owned(), ..Family::default() });
+        let metric = family.series.entry(render_labels(labels)).or_insert_with(make);
+        get(metric).unwrap_or_else(|| panic!("metric {} is a {}", name, metric.kind()))
+    }
+
+    pub fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Counter> {
+        self.series(name, help, labels, || Metric::Counter(Arc::default()), |m| match m {
+            Metric::Counter(c) => Some(c.clone()),
+            _ => None,
+        })
+    }
+
+    pub fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Gauge> {
+        self.series(name, help, labels, || Metric::Gauge(Arc::default()), |m| match m {
+            Metric::Gauge(g) => Some(g.clone()),
+            _ => None,
+        })
+    }
+
+    pub fn histogram(&self, name: &str, help: &str, labels: &[(&str, &str)], bounds: &'static [f64]) -> Arc<Histogram> {
+        self.series(name, help, labels, || Metric::Histogram(Arc::new(Histogram::new(bounds))), |m| match m {
+            Metric::Histogram(h) => Some(h.clone()),
+            _ => None,
+        })
+    }
+
+    /// A gauge read by calling `f` at each render, which replaces any
+    /// previous one of that name and labels. `None` leaves the series out.
+    pub fn gauge_fn<F>(&self, name: &str, help: &str, labels: &[(&str, &str)], f: F)
+        where F: Fn() -> Option<i64> + Send + Sync + 'static {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name.to_owned()).or_insert_with(|| Family { help: help.to_owned(), ..Family::default() });
+        family.series.insert(render_labels(labels), Metric::GaugeFn(Box::new(f)));
+    }
+
+    /// every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.series.values().next() {
+                Some(metric) => metric.kind(),
+                None => continue,
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, metric) in family.series.iter() {
+                let braced = |extra: &str| match (labels.is_empty(), extra.is_empty()) {
+                    (true, true) => String::new(),
+                    (true, false) => format!("{{{}}}", extra),
+                    (false, true) => format!("{{{}}}", labels),
+                    (false, false) => format!("{{{},{}}}", labels, extra),
+                };
+                match metric {
+                    Metric::Counter(c) => {
+                        let _ = writeln!(out, "{}{} {}", name, braced(""), c.get());
+                    }
+                    Metric::Gauge(g) => {
+                        let _ = writeln!(out, "{}{} {}", name, braced(""), g.get());
+                    }
+                    Metric::GaugeFn(f) => {
+                        if let Some(v) = f() {
+                            let _ = writeln!(out, "{}{} {}", name, braced(""), v);
+                        }
+                    }
+                    Metric::Histogram(h) => {
+                        let mut cumulative = 0;
+                        for (i, bound) in h.bounds.iter().map(|b| b.to_string()).chain(Some("+Inf".to_owned())).enumerate() {
+                            cumulative += h.buckets[i].load(Relaxed);
+                            let _ = writeln!(out, "{}_bucket{} {}", name, braced(&format!("le=\"{}\"", bound)), cumulative);
+                        }
+                        let sum = h.sum_nanos.load(Relaxed) as f64 / 1e9;
+                        let _ = writeln!(out, "{}_sum{} {}", name, braced(""), sum);
+                        let _ = writeln!(out, "{}_count{} {}", name, braced(""), h.count());
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Answer `GET /metrics` on `addr` with the metrics of `registry`, from a
+/// background thread. Each request is served on its own connection.
+pub fn serve(addr: impl ToSocketAddrs, registry: &'static Registry) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.map_err(Into::into).and_then(|s| respond(s, registry)) {
+                error!("Metrics request failed: {}", e);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn respond(stream: TcpStream, registry: &Registry) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers are read and ignored
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", registry.render()),
+        _ => ("404 Not Found", "try GET /metrics\n".to_owned()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
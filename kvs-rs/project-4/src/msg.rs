@@ -1,10 +1,65 @@
+use std::net::SocketAddr;
+
 use serde::{Deserialize, Serialize};
 
+use crate::dbengines::Op;
+use crate::raft::Envelope;
+use crate::replication::ReplicaStatus;
+use crate::server::ServerInfo;
+use crate::slowlog::SlowEntry;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
+    Scan { start: String, limit: usize },
+    /// tail the replication log after `since`
+    Pull { epoch: u64, since: u64, limit: usize },
+    ReplicaStatus,
+    Promote,
+    Raft(Envelope),
+    /// back up the engine into a directory on the server
+    Backup { dest: String },
+    /// the `limit` latest slow requests, newest first
+    SlowLog { limit: usize },
+    Info,
+    /// compact the engine now, whatever its policy
+    Compact,
+}
+
+impl Request {
+    /// the key the request is about, empty if it has none
+    pub fn key(&self) -> &str {
+        match self {
+            Request::Get { key } | Request::Set { key, .. } | Request::Remove { key } => key,
+            Request::Scan { start, .. } => start,
+            _ => "",
+        }
+    }
+
+    /// bytes of the key the request is about, 0 if it has none
+    pub fn key_bytes(&self) -> usize {
+        self.key().len()
+    }
+
+    /// the name of the command, as metrics and logs label it
+    pub fn command(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "remove",
+            Request::Scan { .. } => "scan",
+            Request::Pull { .. } => "pull",
+            Request::ReplicaStatus => "replica_status",
+            Request::Promote => "promote",
+            Request::Raft(_) => "raft",
+            Request::Backup { .. } => "backup",
+            Request::SlowLog { .. } => "slowlog",
+            Request::Info => "info",
+            Request::Compact => "compact",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,5 +67,18 @@ pub enum Response {
     Get(Option<String>),
     Set(String),
     Remove,
+    Scan(Vec<(String, String)>),
+    Pull { epoch: u64, head: u64, ops: Vec<(u64, Op)> },
+    /// the requested position is gone, the follower must copy the whole data set
+    Resync { epoch: u64, head: u64 },
+    ReplicaStatus(ReplicaStatus),
+    Promote,
+    Raft,
+    Backup,
+    SlowLog(Vec<SlowEntry>),
+    Info(ServerInfo),
+    Compact,
+    /// retry on the raft leader, if known
+    NotLeader(Option<SocketAddr>),
     Err(String),
-}
\ No newline at end of file
+}
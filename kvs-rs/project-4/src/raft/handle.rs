@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::error;
+
+use crate::{KvsClient, KvsEngine, Op, Result};
+use crate::error::KvsError;
+
+use super::{Envelope, NodeId, RaftNode};
+
+const TICK: Duration = Duration::from_millis(50);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// messages queued for a peer, more are dropped until it catches up
+const PEER_QUEUE: usize = 1024;
+/// wait after a failed connect to a peer, doubled up to `MAX_BACKOFF` on each failure
+const MIN_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+/// gets the term and outcome of the entry applied at the proposed index
+type Waiter = Sender<(u64, Result<()>)>;
+/// gets whether the read may be served
+type Reader = Sender<bool>;
+
+/// Drives a `RaftNode` inside `KvsServer`: a ticker thread, one sender
+/// thread per peer, and proposals and reads waiting for the node.
+pub(crate) struct RaftHandle<E: KvsEngine> {
+    node: Mutex<RaftNode<E>>,
+    addrs: HashMap<NodeId, SocketAddr>,
+    senders: HashMap<NodeId, Sender<Envelope>>,
+    waiters: Mutex<HashMap<u64, Waiter>>,
+    readers: Mutex<HashMap<u64, Reader>>,
+}
+
+impl<E: KvsEngine + 'static> RaftHandle<E> {
+    /// `addrs` holds the server address of every member, this node included.
+    pub fn start(id: NodeId, addrs: HashMap<NodeId, SocketAddr>, engine: E, dir: PathBuf) -> Result<Arc<Self>> {
+        let peers: Vec<NodeId> = addrs.keys().filter(|p| **p != id).cloned().collect();
+        let node = RaftNode::open(id, peers.clone(), engine, dir)?;
+        let mut senders = HashMap::new();
+        for peer in peers {
+            let (tx, rx) = channel::bounded(PEER_QUEUE);
+            let addr = addrs[&peer];
+            thread::Builder::new().name(format!("raft-peer-{}", peer)).spawn(move || send_loop(addr, rx))?;
+            senders.insert(peer, tx);
+        }
+        let handle = Arc::new(RaftHandle {
+            node: Mutex::new(node),
+            addrs,
+            senders,
+            waiters: Mutex::new(HashMap::new()),
+            readers: Mutex::new(HashMap::new()),
+        });
+        let h = handle.clone();
+        thread::Builder::new().name("raft-ticker".to_string()).spawn(move || loop {
+            thread::sleep(TICK);
+            if let Err(e) = h.with_node(|node| node.tick()) {
+                error!("raft tick failed: {}", e);
+            }
+        })?;
+        Ok(handle)
+    }
+
+    pub fn step(&self, env: Envelope) -> Result<()> {
+        self.with_node(|node| node.step(env))
+    }
+
+    /// Wait until the engine may serve a read, see `RaftNode::read_index`.
+    /// Fails with the leader address unless this node is the leader.
+    pub fn read_index(&self) -> Result<()> {
+        let (tx, rx) = channel::bounded(1);
+        let id = self.with_node(|node| {
+            let id = node.read_index().map_err(|_| self.not_leader(node))?;
+            self.readers.lock().unwrap().insert(id, tx);
+            Ok(id)
+        })?;
+        match rx.recv_timeout(PROPOSE_TIMEOUT) {
+            Ok(true) => Ok(()),
+            Ok(false) => self.with_node(|node| Err(self.not_leader(node))),
+            Err(_) => {
+                self.readers.lock().unwrap().remove(&id);
+                Err(KvsError::StringError("read timed out".to_string()))
+            }
+        }
+    }
+
+    /// Replicate `op` and wait until it is applied to the engine.
+    pub fn propose(&self, op: Op) -> Result<()> {
+        let (tx, rx) = channel::bounded(1);
+        let (index, term) = self.with_node(|node| {
+            let index = node.propose(op).map_err(|_| self.not_leader(node))?;
+            self.waiters.lock().unwrap().insert(index, tx.clone());
+            Ok((index, node.term()))
+        })?;
+        match rx.recv_timeout(PROPOSE_TIMEOUT) {
+            Ok((t, res)) if t == term => res,
+            Ok(_) => Err(KvsError::StringError("proposal lost to a leader change".to_string())),
+            Err(_) => {
+                // unless a later proposal took the index over
+                let mut waiters = self.waiters.lock().unwrap();
+                if waiters.get(&index).is_some_and(|w| w.same_channel(&tx)) {
+                    waiters.remove(&index);
+                }
+                Err(KvsError::StringError("proposal timed out".to_string()))
+            }
+        }
+    }
+
+    fn not_leader(&self, node: &RaftNode<E>) -> KvsError {
+        KvsError::NotLeader(node.leader().and_then(|id| self.addrs.get(&id)).cloned())
+    }
+
+    /// run `f` then ship the messages and applied entries it produced
+    fn with_node<T, F>(&self, f: F) -> Result<T> where F: FnOnce(&mut RaftNode<E>) -> Result<T> {
+        let mut node = self.node.lock().unwrap();
+        let res = f(&mut node);
+        let msgs = node.take_messages();
+        let applied = node.take_applied();
+        let reads = node.take_reads();
+        drop(node);
+        for env in msgs {
+            if let Some(tx) = self.senders.get(&env.to) {
+                let _ = tx.try_send(env);
+            }
+        }
+        if !applied.is_empty() {
+            let mut waiters = self.waiters.lock().unwrap();
+            for (entry, res) in applied {
+                if let Some(waiter) = waiters.remove(&entry.index) {
+                    let _ = waiter.send((entry.term, res));
+                }
+            }
+        }
+        if !reads.is_empty() {
+            let mut readers = self.readers.lock().unwrap();
+            for (id, ok) in reads {
+                if let Some(reader) = readers.remove(&id) {
+                    let _ = reader.send(ok);
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Messages are dropped while the peer is unreachable, raft retries them.
+/// It is connected to again after a backoff, not for every message.
+fn send_loop(addr: SocketAddr, rx: Receiver<Envelope>) {
+    let mut client: Option<KvsClient> = None;
+    let mut backoff = MIN_BACKOFF;
+    let mut retry_at = Instant::now();
+    for env in rx.iter() {
+        if client.is_none() && Instant::now() >= retry_at {
+            match KvsClient::connect(addr) {
+                Ok(c) => {
+                    client = Some(c);
+                    backoff = MIN_BACKOFF;
+                }
+                Err(_) => {
+                    retry_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        if let Some(c) = client.as_mut() {
+            if c.raft(env).is_err() {
+                client = None;
+            }
+        }
+    }
+}
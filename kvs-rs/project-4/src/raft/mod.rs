@@ -0,0 +1,6 @@
+pub use self::node::{Entry, Envelope, Message, NodeId, RaftNode, Role, Snapshot};
+pub(crate) use self::handle::RaftHandle;
+
+mod handle;
+mod node;
+mod storage;
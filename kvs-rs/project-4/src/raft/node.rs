@@ -0,0 +1,653 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsEngine, Op, Result};
+use crate::dbengines::scan_each;
+use crate::error::KvsError;
+
+use super::storage::{HardState, Storage};
+
+pub type NodeId = u64;
+
+/// ticks between heartbeats of a leader
+const HEARTBEAT_TICKS: u32 = 2;
+/// a follower campaigns after a random timeout in [ELECTION_TICKS, 2 * ELECTION_TICKS)
+const ELECTION_TICKS: u32 = 10;
+/// max entries per append message
+const MAX_BATCH: usize = 100;
+const DEFAULT_SNAPSHOT_THRESHOLD: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    /// None for the no-op a new leader appends to commit older terms
+    pub op: Option<Op>,
+}
+
+/// The engine content at `index`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub data: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    RequestVote { term: u64, last_index: u64, last_term: u64 },
+    Vote { term: u64, granted: bool },
+    /// `read` is the last read round of the leader, see `RaftNode::read_index`
+    Append { term: u64, prev_index: u64, prev_term: u64, entries: Vec<Entry>, commit: u64, read: u64 },
+    /// on failure `match_index` is the last index of the follower, a hint for the leader;
+    /// `read` echoes the round of the append answered
+    AppendResp { term: u64, success: bool, match_index: u64, read: u64 },
+    InstallSnapshot { term: u64, snapshot: Snapshot },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::Append { term, .. }
+            | Message::AppendResp { term, .. }
+            | Message::InstallSnapshot { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub msg: Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A Raft node whose state machine is a `KvsEngine`.
+///
+/// The node does no IO besides the engine and its storage: time passes
+/// through `tick`, messages come in through `step` and go out through
+/// `take_messages`, so a cluster can be driven deterministically.
+pub struct RaftNode<E: KvsEngine> {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    engine: E,
+    storage: Option<Storage>,
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    /// `log[i]` has index `snapshot_index + 1 + i`
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_threshold: u64,
+    commit: u64,
+    applied: u64,
+    votes: HashSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    elapsed: u32,
+    election_timeout: u32,
+    /// peers heard from as leader in the last `ELECTION_TICKS`, and the ticks of that
+    heard: HashSet<NodeId>,
+    quorum_elapsed: u32,
+    /// the last read round started, and the last one each peer echoed this term
+    read_round: u64,
+    read_acks: HashMap<NodeId, u64>,
+    /// rounds of the reads waiting for a quorum, with their read index
+    reads: Vec<(u64, u64)>,
+    rng: u64,
+    outbox: Vec<Envelope>,
+    applied_out: Vec<(Entry, Result<()>)>,
+    reads_out: Vec<(u64, bool)>,
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    /// `peers` are the other members of the cluster.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, engine: E) -> Self {
+        let mut node = RaftNode {
+            id,
+            peers,
+            engine,
+            storage: None,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: Vec::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot_threshold: DEFAULT_SNAPSHOT_THRESHOLD,
+            commit: 0,
+            applied: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            elapsed: 0,
+            election_timeout: 0,
+            heard: HashSet::new(),
+            quorum_elapsed: 0,
+            read_round: 0,
+            read_acks: HashMap::new(),
+            reads: Vec::new(),
+            rng: id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            outbox: Vec::new(),
+            applied_out: Vec::new(),
+            reads_out: Vec::new(),
+        };
+        node.reset_election_timeout();
+        node
+    }
+
+    /// Persist the hard state and the log in `dir`, reloading them if present.
+    ///
+    /// Entries up to the persisted commit index are re-applied to the engine,
+    /// which is harmless as applying a log prefix again is idempotent.
+    pub fn open(id: NodeId, peers: Vec<NodeId>, engine: E, dir: PathBuf) -> Result<Self> {
+        let mut node = Self::new(id, peers, engine);
+        let (storage, state, log) = Storage::open(&dir)?;
+        node.term = state.term;
+        node.voted_for = state.voted_for;
+        node.snapshot_index = state.snapshot_index;
+        node.snapshot_term = state.snapshot_term;
+        node.log = log;
+        node.applied = state.snapshot_index;
+        node.commit = state.commit.max(state.snapshot_index).min(node.last_index());
+        node.storage = Some(storage);
+        node.apply()?;
+        Ok(node)
+    }
+
+    /// Compact the log once this many entries are applied past the last snapshot.
+    pub fn snapshot_threshold(mut self, entries: u64) -> Self {
+        self.snapshot_threshold = entries;
+        self
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Messages to deliver to other nodes.
+    pub fn take_messages(&mut self) -> Vec<Envelope> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Entries applied to the engine since the last call, with their outcome.
+    pub fn take_applied(&mut self) -> Vec<(Entry, Result<()>)> {
+        std::mem::take(&mut self.applied_out)
+    }
+
+    /// Reads started by `read_index` since the last call, with whether they
+    /// may be served: false if this node lost the lead first.
+    pub fn take_reads(&mut self) -> Vec<(u64, bool)> {
+        std::mem::take(&mut self.reads_out)
+    }
+
+    /// Start a read if leader, returning its id for `take_reads`.
+    ///
+    /// A leader cut off from the others may not know it was replaced, so the
+    /// read waits until a quorum answered an append sent after it, and until
+    /// the engine has every entry committed before it.
+    pub fn read_index(&mut self) -> Result<u64> {
+        if !self.is_leader() {
+            return Err(KvsError::NotLeader(None));
+        }
+        // entries of older terms may be committed past `commit` until one of this term is
+        let index = match self.term_at(self.commit) == Some(self.term) {
+            true => self.commit,
+            false => self.last_index(),
+        };
+        self.read_round += 1;
+        self.reads.push((self.read_round, index));
+        self.broadcast_append();
+        self.release_reads();
+        Ok(self.read_round)
+    }
+
+    /// Append `op` to the log if leader, returning its index.
+    pub fn propose(&mut self, op: Op) -> Result<u64> {
+        if !self.is_leader() {
+            return Err(KvsError::NotLeader(None));
+        }
+        let index = self.append(Some(op))?;
+        self.broadcast_append();
+        self.advance_commit()?;
+        Ok(index)
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        self.elapsed += 1;
+        if self.is_leader() {
+            // a leader the others no longer answer may have been replaced
+            self.quorum_elapsed += 1;
+            if self.quorum_elapsed >= ELECTION_TICKS {
+                if self.heard.len() + 1 < self.quorum() {
+                    info!("raft node {} lost the quorum in term {}", self.id, self.term);
+                    return self.become_follower(self.term, None);
+                }
+                self.heard.clear();
+                self.quorum_elapsed = 0;
+            }
+            if self.elapsed >= HEARTBEAT_TICKS {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+        } else if self.elapsed >= self.election_timeout {
+            self.campaign()?;
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self, env: Envelope) -> Result<()> {
+        let from = env.from;
+        if env.msg.term() > self.term {
+            self.become_follower(env.msg.term(), None)?;
+        }
+        match env.msg {
+            Message::RequestVote { term, last_index, last_term } => {
+                let (my_index, my_term) = (self.last_index(), self.last_term());
+                let up_to_date = last_term > my_term || (last_term == my_term && last_index >= my_index);
+                let granted = term == self.term
+                    && self.voted_for.is_none_or(|v| v == from)
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.elapsed = 0;
+                    self.save_state()?;
+                }
+                self.send(from, Message::Vote { term: self.term, granted });
+            }
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            Message::Append { term, prev_index, prev_term, entries, commit, read } => {
+                if term < self.term {
+                    let resp = Message::AppendResp { term: self.term, success: false, match_index: self.last_index(), read };
+                    self.send(from, resp);
+                    return Ok(());
+                }
+                self.follow(from)?;
+                if prev_index > self.last_index()
+                    || (prev_index >= self.snapshot_index && self.term_at(prev_index) != Some(prev_term)) {
+                    let hint = self.last_index().min(prev_index.saturating_sub(1)).max(self.commit);
+                    let resp = Message::AppendResp { term: self.term, success: false, match_index: hint, read };
+                    self.send(from, resp);
+                    return Ok(());
+                }
+                let last_new = prev_index + entries.len() as u64;
+                self.merge(entries)?;
+                if commit > self.commit {
+                    self.commit = commit.min(last_new).max(self.commit);
+                    self.apply()?;
+                }
+                self.send(from, Message::AppendResp { term: self.term, success: true, match_index: last_new, read });
+            }
+            Message::AppendResp { term, success, match_index, read } => {
+                if self.is_leader() && term == self.term {
+                    // answered at all, the peer follows this node
+                    self.heard.insert(from);
+                    let acked = self.read_acks.entry(from).or_insert(0);
+                    *acked = (*acked).max(read);
+                    if success {
+                        let m = self.match_index.entry(from).or_insert(0);
+                        *m = (*m).max(match_index);
+                        let next = *m + 1;
+                        self.next_index.insert(from, next);
+                        self.advance_commit()?;
+                        if next <= self.last_index() {
+                            self.send_append(from);
+                        }
+                    } else {
+                        let next = self.next_index.get(&from).cloned().unwrap_or(1);
+                        self.next_index.insert(from, (next.saturating_sub(1)).min(match_index + 1).max(1));
+                        self.send_append(from);
+                    }
+                    self.release_reads();
+                }
+            }
+            Message::InstallSnapshot { term, snapshot } => {
+                if term < self.term {
+                    let resp = Message::AppendResp { term: self.term, success: false, match_index: self.last_index(), read: 0 };
+                    self.send(from, resp);
+                    return Ok(());
+                }
+                self.follow(from)?;
+                let index = snapshot.index;
+                if index > self.commit {
+                    self.install(snapshot)?;
+                }
+                self.send(from, Message::AppendResp { term: self.term, success: true, match_index: index, read: 0 });
+            }
+        }
+        Ok(())
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.log.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    fn send(&mut self, to: NodeId, msg: Message) {
+        self.outbox.push(Envelope { from: self.id, to, msg });
+    }
+
+    /// xorshift, seeded by the node id so runs are reproducible
+    fn reset_election_timeout(&mut self) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.election_timeout = ELECTION_TICKS + (self.rng % ELECTION_TICKS as u64) as u32;
+        self.elapsed = 0;
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term != self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_state()?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_election_timeout();
+        self.reads_out.extend(self.reads.drain(..).map(|(round, _)| (round, false)));
+        Ok(())
+    }
+
+    /// accept `leader` as the leader of the current term
+    fn follow(&mut self, leader: NodeId) -> Result<()> {
+        if self.role != Role::Follower || self.leader != Some(leader) {
+            self.become_follower(self.term, Some(leader))?;
+        }
+        self.elapsed = 0;
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes = HashSet::new();
+        self.votes.insert(self.id);
+        self.reset_election_timeout();
+        self.save_state()?;
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let (last_index, last_term) = (self.last_index(), self.last_term());
+        for peer in self.peers.clone() {
+            self.send(peer, Message::RequestVote { term: self.term, last_index, last_term });
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("raft node {} is leader of term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.heard.clear();
+        self.quorum_elapsed = 0;
+        self.read_acks.clear();
+        let next = self.last_index() + 1;
+        for peer in self.peers.iter() {
+            self.next_index.insert(*peer, next);
+            self.match_index.insert(*peer, 0);
+        }
+        self.append(None)?;
+        self.broadcast_append();
+        self.advance_commit()
+    }
+
+    fn append(&mut self, op: Option<Op>) -> Result<u64> {
+        let index = self.last_index() + 1;
+        self.log.push(Entry { term: self.term, index, op });
+        if let Some(storage) = self.storage.as_mut() {
+            storage.append(&self.log[self.log.len() - 1..])?;
+        }
+        Ok(index)
+    }
+
+    /// add entries from the leader, dropping conflicting ones we have
+    fn merge(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let mut first_new = None;
+        for entry in entries {
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    let keep = (entry.index - self.snapshot_index - 1) as usize;
+                    self.log.truncate(keep);
+                    if let Some(storage) = self.storage.as_mut() {
+                        storage.truncate(keep)?;
+                    }
+                }
+                None => {}
+            }
+            first_new.get_or_insert(self.log.len());
+            self.log.push(entry);
+        }
+        if let (Some(first), Some(storage)) = (first_new, self.storage.as_mut()) {
+            storage.append(&self.log[first..])?;
+        }
+        Ok(())
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let next = self.next_index.get(&peer).cloned().unwrap_or(1);
+        if next <= self.snapshot_index {
+            match self.snapshot() {
+                Ok(snapshot) => {
+                    self.next_index.insert(peer, snapshot.index + 1);
+                    self.send(peer, Message::InstallSnapshot { term: self.term, snapshot });
+                }
+                Err(e) => error!("raft node {} can not build a snapshot: {}", self.id, e),
+            }
+            return;
+        }
+        let prev_index = next - 1;
+        let prev_term = self.term_at(prev_index).unwrap_or(0);
+        let entries = self.log.iter()
+            .skip((next - self.snapshot_index - 1) as usize)
+            .take(MAX_BATCH)
+            .cloned()
+            .collect();
+        let msg = Message::Append {
+            term: self.term,
+            prev_index,
+            prev_term,
+            entries,
+            commit: self.commit,
+            read: self.read_round,
+        };
+        self.send(peer, msg);
+    }
+
+    /// hand out the reads a quorum confirmed, once their index is applied
+    fn release_reads(&mut self) {
+        let quorum = self.quorum();
+        for (round, index) in std::mem::take(&mut self.reads) {
+            let acks = 1 + self.read_acks.values().filter(|r| **r >= round).count();
+            if acks >= quorum && index <= self.applied {
+                self.reads_out.push((round, true));
+            } else {
+                self.reads.push((round, index));
+            }
+        }
+    }
+
+    /// the engine content is the state at `applied`, every entry up to it is committed
+    fn snapshot(&self) -> Result<Snapshot> {
+        let mut data = Vec::new();
+        scan_each(&self.engine, |k, v| {
+            data.push((k, v));
+            Ok(())
+        })?;
+        Ok(Snapshot { index: self.applied, term: self.term_at(self.applied).unwrap_or(0), data })
+    }
+
+    fn install(&mut self, snapshot: Snapshot) -> Result<()> {
+        let mut stale = Vec::new();
+        {
+            let keys: HashSet<&String> = snapshot.data.iter().map(|(k, _)| k).collect();
+            scan_each(&self.engine, |k, _| {
+                if !keys.contains(&k) {
+                    stale.push(k);
+                }
+                Ok(())
+            })?;
+        }
+        for key in stale {
+            self.engine.remove(key)?;
+        }
+        for (key, value) in snapshot.data {
+            self.engine.set(key, value)?;
+        }
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            let keep = (snapshot.index - self.snapshot_index) as usize;
+            self.log.drain(..keep);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot_index = snapshot.index;
+        self.snapshot_term = snapshot.term;
+        self.commit = snapshot.index;
+        self.applied = snapshot.index;
+        self.save_log()
+    }
+
+    fn advance_commit(&mut self) -> Result<()> {
+        let mut n = self.last_index();
+        while n > self.commit && self.term_at(n) == Some(self.term) {
+            let acks = 1 + self.match_index.values().filter(|m| **m >= n).count();
+            if acks >= self.quorum() {
+                self.commit = n;
+                return self.apply();
+            }
+            n -= 1;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        while self.applied < self.commit {
+            let entry = match self.entry(self.applied + 1) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            let res = match entry.op.clone() {
+                Some(Op::Set { key, value }) => self.engine.set(key, value),
+                Some(Op::Remove { key }) => self.engine.remove(key),
+                None => Ok(()),
+            };
+            self.applied += 1;
+            self.applied_out.push((entry, res));
+        }
+        if self.applied - self.snapshot_index >= self.snapshot_threshold {
+            self.snapshot_term = self.term_at(self.applied).unwrap_or(self.snapshot_term);
+            let drop = (self.applied - self.snapshot_index) as usize;
+            self.log.drain(..drop);
+            self.snapshot_index = self.applied;
+            self.save_log()?;
+        }
+        Ok(())
+    }
+
+    fn save_state(&self) -> Result<()> {
+        if let Some(storage) = &self.storage {
+            storage.save(&HardState {
+                term: self.term,
+                voted_for: self.voted_for,
+                commit: self.commit,
+                snapshot_index: self.snapshot_index,
+                snapshot_term: self.snapshot_term,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// save the state and write the whole log again, once it was compacted
+    fn save_log(&mut self) -> Result<()> {
+        self.save_state()?;
+        if let Some(storage) = self.storage.as_mut() {
+            storage.rewrite(&self.log)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::Result;
+
+use super::{Entry, NodeId};
+
+const STATE_FILE: &str = "raft.json";
+const LOG_FILE: &str = "raft.log";
+
+/// What must survive a restart besides the log, the engine itself holds
+/// the snapshot.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub commit: u64,
+    pub snapshot_index: u64,
+    pub snapshot_term: u64,
+}
+
+/// The raft state file and log of a node, both synced before a write
+/// returns, as votes and acknowledged entries must not be forgotten.
+///
+/// Entries are appended to the log file, which is only rewritten when the
+/// log is compacted.
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log: File,
+    /// the offset of each entry in the log file
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl Storage {
+    /// Load the state and log kept in `dir`, cutting an entry torn by a
+    /// crash off the end of the log.
+    pub fn open(dir: &Path) -> Result<(Storage, HardState, Vec<Entry>)> {
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(_) => HardState::default(),
+        };
+        let path = dir.join(LOG_FILE);
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut len = 0;
+        if path.exists() {
+            let mut stream = Deserializer::from_reader(BufReader::new(File::open(&path)?)).into_iter::<Entry>();
+            while let Some(Ok(entry)) = stream.next() {
+                offsets.push(len);
+                entries.push(entry);
+                len = stream.byte_offset() as u64;
+            }
+        }
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        log.set_len(len)?;
+        let mut storage = Storage { dir: dir.to_owned(), log, offsets, len };
+        // the log is rewritten after the state on compaction, a crash in
+        // between leaves entries the snapshot holds already
+        let stale = entries.iter().take_while(|e| e.index <= state.snapshot_index).count();
+        if stale > 0 {
+            entries.drain(..stale);
+            storage.rewrite(&entries)?;
+        }
+        Ok((storage, state, entries))
+    }
+
+    /// write the state aside, sync it and rename it in place
+    pub fn save(&self, state: &HardState) -> Result<()> {
+        let path = self.dir.join(STATE_FILE);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(state)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// add `entries` after those kept
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            self.offsets.push(self.len + buf.len() as u64);
+            serde_json::to_writer(&mut buf, entry)?;
+        }
+        self.log.write_all(&buf)?;
+        self.log.sync_data()?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// keep the first `keep` entries only
+    pub fn truncate(&mut self, keep: usize) -> Result<()> {
+        if keep < self.offsets.len() {
+            self.len = self.offsets[keep];
+            self.offsets.truncate(keep);
+            self.log.set_len(self.len)?;
+            self.log.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// replace the log file with `entries`, after a compaction
+    pub fn rewrite(&mut self, entries: &[Entry]) -> Result<()> {
+        let path = self.dir.join(LOG_FILE);
+        let tmp = path.with_extension("log.tmp");
+        let mut buf = Vec::new();
+        let mut offsets = Vec::new();
+        for entry in entries {
+            offsets.push(buf.len() as u64);
+            serde_json::to_writer(&mut buf, entry)?;
+        }
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        self.log = OpenOptions::new().append(true).open(&path)?;
+        self.offsets = offsets;
+        self.len = buf.len() as u64;
+        Ok(())
+    }
+}
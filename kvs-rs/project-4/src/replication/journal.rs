@@ -0,0 +1,174 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::IgnoredAny;
+use serde_json::Deserializer;
+
+use crate::Result;
+use crate::dbengines::{Op, Record};
+use crate::dbengines::crypto::{self, Keyring};
+
+/// ops per journal file
+const SEGMENT_OPS: u64 = 1_000_000;
+/// journal files kept, a follower further behind than the older one needs
+/// a full resync
+const SEGMENTS: usize = 2;
+/// a read position is kept every this many ops
+const MARK_EVERY: u64 = 1000;
+const PREFIX: &str = "replication-";
+const SUFFIX: &str = ".journal";
+
+/// The ops a leader accepted, appended to files named after their first
+/// sequence number, so followers can catch up after a restart. With `keys`
+/// the ops are sealed, as the records of an encrypted `KvStore` are.
+pub(crate) struct Journal {
+    dir: PathBuf,
+    keys: Option<Keyring>,
+    /// oldest first, the last one is written to
+    segments: Vec<Segment>,
+    /// written a whole record at a time, so unbuffered
+    writer: File,
+    head: u64,
+    /// whether `open` cut a torn record off
+    torn: bool,
+}
+
+struct Segment {
+    start: u64,
+    /// sequence and offset of the first op and every `MARK_EVERY`th one
+    marks: Vec<(u64, u64)>,
+    len: u64,
+}
+
+impl Segment {
+    fn push(&mut self, seq: u64, len: u64) {
+        if seq == self.start || seq.is_multiple_of(MARK_EVERY) {
+            self.marks.push((seq, self.len));
+        }
+        self.len += len;
+    }
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{}{}{}", PREFIX, start, SUFFIX))
+}
+
+impl Journal {
+    /// Load the journal files of `dir`, cutting a record torn by a crash
+    /// off the end of a file.
+    pub fn open(dir: &Path, keys: Option<Keyring>) -> Result<Journal> {
+        let mut starts: Vec<u64> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse().ok()
+            })
+            .collect();
+        starts.sort_unstable();
+        let mut segments = Vec::new();
+        let mut head = 0;
+        let mut torn = false;
+        for start in starts {
+            let path = segment_path(dir, start);
+            let mut segment = Segment { start, marks: Vec::new(), len: 0 };
+            let mut stream = Deserializer::from_reader(BufReader::new(File::open(&path)?))
+                .into_iter::<(u64, IgnoredAny)>();
+            while let Some(Ok((seq, _))) = stream.next() {
+                let end = stream.byte_offset() as u64;
+                segment.push(seq, end - segment.len);
+                head = seq;
+            }
+            let file = OpenOptions::new().write(true).open(&path)?;
+            if file.metadata()?.len() > segment.len {
+                file.set_len(segment.len)?;
+                torn = true;
+            }
+            head = head.max(start - 1);
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            segments.push(Segment { start: head + 1, marks: Vec::new(), len: 0 });
+        }
+        let last = segments.last().unwrap().start;
+        let writer = OpenOptions::new().create(true).append(true).open(segment_path(dir, last))?;
+        Ok(Journal { dir: dir.to_owned(), keys, segments, writer, head, torn })
+    }
+
+    /// the keys the ops are sealed with
+    pub fn keys(&self) -> Option<&Keyring> {
+        self.keys.as_ref()
+    }
+
+    /// the last sequence number written
+    pub fn head(&self) -> u64 {
+        self.head
+    }
+
+    /// Whether a record was cut off at `open`. Sequence numbers after it get
+    /// reused, so followers have to resync.
+    pub fn torn(&self) -> bool {
+        self.torn
+    }
+
+    /// Append `op` as `seq`. A record written only in part is cut off again,
+    /// so the file stays readable past a failed append.
+    pub fn append(&mut self, seq: u64, op: &Op) -> Result<()> {
+        if seq - self.segments.last().unwrap().start >= SEGMENT_OPS {
+            self.rotate(seq)?;
+        }
+        let record = match self.keys {
+            Some(ref keys) => keys.seal(&op.clone().into())?,
+            None => op.clone().into(),
+        };
+        let bytes = serde_json::to_vec(&(seq, record))?;
+        let segment = self.segments.last_mut().unwrap();
+        if let Err(e) = self.writer.write_all(&bytes) {
+            self.writer.set_len(segment.len)?;
+            return Err(e.into());
+        }
+        segment.push(seq, bytes.len() as u64);
+        self.head = seq;
+        Ok(())
+    }
+
+    /// start a file at `seq`, dropping the oldest ones past `SEGMENTS`
+    fn rotate(&mut self, seq: u64) -> Result<()> {
+        let path = segment_path(&self.dir, seq);
+        self.writer = OpenOptions::new().create(true).append(true).open(path)?;
+        self.segments.push(Segment { start: seq, marks: Vec::new(), len: 0 });
+        while self.segments.len() > SEGMENTS {
+            let old = self.segments.remove(0);
+            fs::remove_file(segment_path(&self.dir, old.start))?;
+        }
+        Ok(())
+    }
+
+    /// The file and offset to read the ops after `since` from, `None` if
+    /// they are no longer kept.
+    pub fn locate(&self, since: u64) -> Option<(PathBuf, u64)> {
+        let next = since + 1;
+        let segment = self.segments.iter().rev().find(|s| s.start <= next)?;
+        let offset = segment.marks.iter().rev().find(|(seq, _)| *seq <= next)?.1;
+        Some((segment_path(&self.dir, segment.start), offset))
+    }
+}
+
+/// Up to `limit` ops after `since`, read from `offset` of the journal file
+/// `path`, stopping early at a gap in the sequence numbers.
+pub(crate) fn read(path: &Path, offset: u64, since: u64, limit: usize, keys: Option<&Keyring>) -> Result<Vec<(u64, Op)>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut ops = Vec::new();
+    // a record being appended right now is incomplete, so stop at an error
+    for (seq, record) in Deserializer::from_reader(BufReader::new(file)).into_iter::<(u64, Record)>().map_while(|r| r.ok()) {
+        if seq <= since {
+            continue;
+        }
+        if seq != since + 1 + ops.len() as u64 || ops.len() == limit {
+            break;
+        }
+        ops.push((seq, crypto::unseal(record, keys)?.into_op()?));
+    }
+    Ok(ops)
+}
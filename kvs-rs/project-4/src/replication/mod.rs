@@ -0,0 +1,515 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsClient, KvsEngine, Keyring, Result};
+use crate::dbengines::Op;
+use crate::error::KvsError;
+use crate::msg::Response;
+use crate::utils::fnv1a;
+
+use self::journal::Journal;
+
+mod journal;
+
+/// bytes of ops kept in memory for followers, older ones are read back
+/// from the journal, or need a full resync without one
+const LOG_BYTES: usize = 64 * 1024 * 1024;
+/// writes to keys hashing to the same lock run one at a time
+const KEY_LOCKS: usize = 64;
+/// max ops or pairs moved per round trip
+const BATCH: usize = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+const STATE_FILE: &str = "replication.json";
+
+/// Replication state reported by `Request::ReplicaStatus`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    /// "leader" or "replica"
+    pub role: String,
+    /// the leader to follow, if replica
+    pub leader: Option<SocketAddr>,
+    /// last op sequence applied locally
+    pub applied: u64,
+    /// last op sequence known on the leader
+    pub leader_head: u64,
+    /// ops the replica is behind the leader
+    pub lag: u64,
+}
+
+/// What a server keeps in its replication directory besides the journal.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// the epoch of the journal
+    epoch: u64,
+    /// the epoch of the leader followed and the last of its ops applied
+    leader_epoch: u64,
+    applied: u64,
+    /// the engine has every journaled op up to this one, missing in files
+    /// written before it was kept
+    #[serde(default)]
+    settled: Option<u64>,
+}
+
+/// Ordered log of the mutations accepted by a leader.
+///
+/// Sequence numbers start at 1 and are only meaningful together with the
+/// epoch. Without a journal the epoch changes every time the server starts.
+struct ReplicationLog {
+    head: u64,
+    ops: VecDeque<(u64, Op)>,
+    /// what `ops` take, as `op_size` counts it
+    bytes: usize,
+    /// logged ops the engine is still running
+    pending: BTreeSet<u64>,
+    journal: Option<Journal>,
+}
+
+/// Where the ops a follower asked for are.
+enum Ops {
+    Ready(Vec<(u64, Op)>),
+    /// a journal file and the offset to read from
+    Journal(PathBuf, u64),
+}
+
+impl ReplicationLog {
+    /// log `op`, failing if the journal can't take it
+    fn push(&mut self, op: Op) -> Result<u64> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(self.head + 1, &op)?;
+        }
+        self.head += 1;
+        self.bytes += op_size(&op);
+        self.ops.push_back((self.head, op));
+        while self.bytes > LOG_BYTES {
+            let (_, op) = self.ops.pop_front().unwrap();
+            self.bytes -= op_size(&op);
+        }
+        Ok(self.head)
+    }
+
+    /// the last op the engine has, along with all before it
+    fn settled(&self) -> u64 {
+        self.pending.iter().next().map_or(self.head, |seq| seq - 1)
+    }
+
+    /// ops after `since`, None if they are no longer retained
+    fn since(&self, since: u64, limit: usize) -> Option<Ops> {
+        if since > self.head {
+            return None;
+        }
+        let first = self.head + 1 - self.ops.len() as u64;
+        if since + 1 < first {
+            let (path, offset) = self.journal.as_ref()?.locate(since)?;
+            return Some(Ops::Journal(path, offset));
+        }
+        let skip = (since + 1 - first) as usize;
+        Some(Ops::Ready(self.ops.iter().skip(skip).take(limit).cloned().collect()))
+    }
+}
+
+/// bytes an op in memory accounts for
+fn op_size(op: &Op) -> usize {
+    match op {
+        Op::Set { key, value } => key.len() + value.len() + 64,
+        Op::Remove { key } => key.len() + 64,
+    }
+}
+
+pub(crate) struct Replication {
+    epoch: AtomicU64,
+    /// `None` while replication is off
+    log: Option<Mutex<ReplicationLog>>,
+    keys: Vec<Mutex<()>>,
+    leader: Mutex<Option<SocketAddr>>,
+    /// the epoch of the leader the applied ops came from
+    leader_epoch: AtomicU64,
+    applied: AtomicU64,
+    leader_head: AtomicU64,
+    /// `State::settled` as last saved
+    settled: AtomicU64,
+    /// where `State` is kept, if anywhere
+    state: Option<PathBuf>,
+}
+
+impl Replication {
+    /// Keep the ops followers pull. With a `dir`, they are journaled, sealed
+    /// with `keys` if given, and the offsets kept there, so a restarted
+    /// leader or follower picks up where it stopped.
+    ///
+    /// A replica gets a new epoch for its own log, as its data no longer
+    /// matches what it journaled while it led.
+    pub fn open(leader: Option<SocketAddr>, dir: Option<PathBuf>, keys: Option<Keyring>) -> Result<Self> {
+        let mut state = State::default();
+        let mut journal = None;
+        if let Some(dir) = dir.as_ref() {
+            if let Ok(data) = fs::read(dir.join(STATE_FILE)) {
+                state = serde_json::from_slice(&data)?;
+            }
+            let opened = Journal::open(dir, keys)?;
+            if leader.is_some() || opened.torn() {
+                state.epoch = 0;
+            }
+            journal = Some(opened);
+        }
+        if state.epoch == 0 {
+            state.epoch = new_epoch();
+        }
+        let head = journal.as_ref().map_or(0, |j| j.head());
+        let repl = Replication {
+            epoch: AtomicU64::new(state.epoch),
+            log: Some(Mutex::new(ReplicationLog {
+                head,
+                ops: VecDeque::new(),
+                bytes: 0,
+                pending: BTreeSet::new(),
+                journal,
+            })),
+            keys: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+            leader: Mutex::new(leader),
+            leader_epoch: AtomicU64::new(state.leader_epoch),
+            applied: AtomicU64::new(state.applied),
+            leader_head: AtomicU64::new(0),
+            settled: AtomicU64::new(state.settled.unwrap_or(head).min(head)),
+            state: dir.map(|d| d.join(STATE_FILE)),
+        };
+        repl.save()?;
+        Ok(repl)
+    }
+
+    /// a leader which logs nothing, followers are turned away
+    pub fn off() -> Self {
+        Replication {
+            epoch: AtomicU64::new(0),
+            log: None,
+            keys: Vec::new(),
+            leader: Mutex::new(None),
+            leader_epoch: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            leader_head: AtomicU64::new(0),
+            settled: AtomicU64::new(0),
+            state: None,
+        }
+    }
+
+    /// Run the journaled ops after the settled one against `engine`, those
+    /// a crash may have kept from it. The ones it has are run again in the
+    /// same order, which leaves it as it is.
+    pub fn recover<E: KvsEngine>(&self, engine: &E) -> Result<()> {
+        let log = match self.log {
+            Some(ref log) if !self.is_replica() => log.lock().unwrap(),
+            _ => return Ok(()),
+        };
+        let journal = match log.journal.as_ref() {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let mut since = self.settled.load(Ordering::SeqCst);
+        while since < log.head {
+            let ops = match journal.locate(since) {
+                Some((path, offset)) => journal::read(&path, offset, since, BATCH, journal.keys())?,
+                None => Vec::new(),
+            };
+            if ops.is_empty() {
+                warn!("replication journal has no ops after {}, {} not recovered", since, log.head);
+                break;
+            }
+            for (seq, op) in ops {
+                apply(engine, op)?;
+                since = seq;
+            }
+        }
+        if since > self.settled.load(Ordering::SeqCst) {
+            info!("recovered replication journal ops up to {}", since);
+        }
+        self.settled.store(log.head, Ordering::SeqCst);
+        self.save()
+    }
+
+    /// write the epochs and the applied offset aside and rename them in place
+    fn save(&self) -> Result<()> {
+        if let Some(path) = self.state.as_ref() {
+            let state = State {
+                epoch: self.epoch.load(Ordering::SeqCst),
+                leader_epoch: self.leader_epoch.load(Ordering::SeqCst),
+                applied: self.applied.load(Ordering::SeqCst),
+                settled: Some(self.settled.load(Ordering::SeqCst)),
+            };
+            let tmp = path.with_extension("json.tmp");
+            fs::write(&tmp, serde_json::to_vec(&state)?)?;
+            fs::rename(tmp, path)?;
+        }
+        Ok(())
+    }
+
+    pub fn leader(&self) -> Option<SocketAddr> {
+        *self.leader.lock().unwrap()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.leader().is_some()
+    }
+
+    /// stop following, the pull thread exits on its next round
+    pub fn promote(&self) {
+        *self.leader.lock().unwrap() = None;
+    }
+
+    /// Log `op`, then run it against `engine`.
+    ///
+    /// Writes to the same key run one at a time, so they are logged in the
+    /// order the engine applies them. Writes to other keys commute, so only
+    /// appending to the log is serialized.
+    ///
+    /// A write the journal can't take fails before it reaches the engine.
+    /// One the engine fails once logged is followed by an op setting the key
+    /// to what the engine holds, which followers end up with as well.
+    pub fn write<E: KvsEngine>(&self, engine: &E, op: Op) -> Result<()> {
+        if self.is_replica() {
+            return Err(KvsError::StringError("read-only replica".to_string()));
+        }
+        let log = match self.log {
+            Some(ref log) => log,
+            None => return execute(engine, op),
+        };
+        let key = match &op {
+            Op::Set { key, .. } | Op::Remove { key } => key.clone(),
+        };
+        let _key = self.keys[(fnv1a(key.as_bytes()) % KEY_LOCKS as u64) as usize].lock().unwrap();
+        let seq = {
+            let mut log = log.lock().unwrap();
+            let seq = log.push(op.clone())?;
+            log.pending.insert(seq);
+            seq
+        };
+        let res = execute(engine, op);
+        match res {
+            // followers skip removing a missing key as well
+            Ok(()) | Err(KvsError::KeyNotFound) => {}
+            Err(_) => self.repair(engine, log, key),
+        }
+        let mut log = log.lock().unwrap();
+        log.pending.remove(&seq);
+        let settled = log.settled();
+        // a crash replays the journal from the settled op saved last
+        if settled >= self.settled.load(Ordering::SeqCst) + BATCH as u64 {
+            self.settled.store(settled, Ordering::SeqCst);
+            if let Err(e) = self.save() {
+                warn!("saving replication state failed: {}", e);
+            }
+        }
+        res
+    }
+
+    /// Log what the engine holds for `key` after a failed write. Failing
+    /// that, followers are sent to resync with a new epoch.
+    fn repair<E: KvsEngine>(&self, engine: &E, log: &Mutex<ReplicationLog>, key: String) {
+        let res = engine.get(key.clone()).and_then(|value| {
+            let op = match value {
+                Some(value) => Op::Set { key, value },
+                None => Op::Remove { key },
+            };
+            log.lock().unwrap().push(op).map(|_| ())
+        });
+        if let Err(e) = res {
+            warn!("logging the value of a failed write failed: {}", e);
+            self.epoch.store(new_epoch(), Ordering::SeqCst);
+            if let Err(e) = self.save() {
+                warn!("saving replication state failed: {}", e);
+            }
+        }
+    }
+
+    /// answer a follower's `Request::Pull`
+    pub fn pull(&self, epoch: u64, since: u64, limit: usize) -> Response {
+        if self.is_replica() {
+            return Response::Err("replica can not be followed".to_string());
+        }
+        let log = match self.log {
+            Some(ref log) => log,
+            None => return Response::Err("replication is off on this server".to_string()),
+        };
+        let (ops, head, settled, keys) = {
+            let log = log.lock().unwrap();
+            let keys = log.journal.as_ref().and_then(|j| j.keys().cloned());
+            (log.since(since, limit), log.head, log.settled(), keys)
+        };
+        // journal files are read without the lock, so writes go on
+        let ops = match ops {
+            Some(Ops::Ready(ops)) => Some(ops),
+            Some(Ops::Journal(path, offset)) => match journal::read(&path, offset, since, limit, keys.as_ref()) {
+                Ok(ops) if !ops.is_empty() => Some(ops),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("reading replication journal {} failed: {}", path.display(), e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let current = self.epoch.load(Ordering::SeqCst);
+        match ops {
+            Some(ops) if epoch == current => Response::Pull { epoch, head, ops },
+            // a resync copies what the engine has, which are the settled ops
+            _ => Response::Resync { epoch: current, head: settled },
+        }
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        let leader = self.leader();
+        if leader.is_some() {
+            let applied = self.applied.load(Ordering::SeqCst);
+            let leader_head = self.leader_head.load(Ordering::SeqCst);
+            ReplicaStatus {
+                role: "replica".to_string(),
+                leader,
+                applied,
+                leader_head,
+                lag: leader_head.saturating_sub(applied),
+            }
+        } else {
+            let head = self.log.as_ref().map_or(0, |log| log.lock().unwrap().head);
+            ReplicaStatus {
+                role: "leader".to_string(),
+                leader,
+                applied: head,
+                leader_head: head,
+                lag: 0,
+            }
+        }
+    }
+}
+
+/// Tail the leader's log and apply it to `engine` until promoted.
+pub(crate) fn follow<E: KvsEngine>(engine: E, repl: Arc<Replication>) {
+    let mut client: Option<KvsClient> = None;
+    let mut epoch = repl.leader_epoch.load(Ordering::SeqCst);
+    while let Some(leader) = repl.leader() {
+        let res = match client.as_mut() {
+            Some(c) => pull_once(c, &engine, &repl, &mut epoch),
+            None => KvsClient::connect(leader).map(|c| {
+                client = Some(c);
+                true
+            }),
+        };
+        match res {
+            Ok(true) => {}
+            Ok(false) => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                warn!("replication from {} failed: {}", leader, e);
+                client = None;
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// return whether there may be more ops to pull right away
+fn pull_once<E: KvsEngine>(client: &mut KvsClient, engine: &E, repl: &Replication, epoch: &mut u64) -> Result<bool> {
+    let since = repl.applied.load(Ordering::SeqCst);
+    match client.pull(*epoch, since, BATCH)? {
+        Response::Pull { head, ops, .. } => {
+            repl.leader_head.store(head, Ordering::SeqCst);
+            let more = ops.len() == BATCH;
+            for (seq, op) in ops {
+                if !repl.is_replica() {
+                    return Ok(false);
+                }
+                apply(engine, op)?;
+                repl.applied.store(seq, Ordering::SeqCst);
+            }
+            repl.save()?;
+            Ok(more)
+        }
+        Response::Resync { epoch: leader_epoch, head } => {
+            info!("full resync from leader at seq {}", head);
+            repl.leader_head.store(head, Ordering::SeqCst);
+            resync(client, engine)?;
+            // writes racing with the copy are replayed from `head`, they are idempotent
+            repl.applied.store(head, Ordering::SeqCst);
+            repl.leader_epoch.store(leader_epoch, Ordering::SeqCst);
+            repl.save()?;
+            *epoch = leader_epoch;
+            Ok(true)
+        }
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// a fresh epoch, for a log whose sequence numbers start over or can't be trusted
+fn new_epoch() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0) ^ std::process::id() as u64
+}
+
+/// run `op` against `engine`
+fn execute<E: KvsEngine>(engine: &E, op: Op) -> Result<()> {
+    match op {
+        Op::Set { key, value } => engine.set(key, value),
+        Op::Remove { key } => engine.remove(key),
+    }
+}
+
+/// run a replicated `op`, whose key may be gone here already
+fn apply<E: KvsEngine>(engine: &E, op: Op) -> Result<()> {
+    match execute(engine, op) {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        r => r,
+    }
+}
+
+/// copy the leader's data set page by page, dropping local keys it doesn't have
+fn resync<E: KvsEngine>(client: &mut KvsClient, engine: &E) -> Result<()> {
+    let mut start = String::new();
+    loop {
+        let page = client.scan(start.clone(), BATCH)?;
+        let done = page.len() < BATCH;
+        let upper = page.last().map(|(k, _)| k.clone());
+        let local = local_range(engine, &start, if done { None } else { upper.as_deref() })?;
+        let remote: HashSet<&String> = page.iter().map(|(k, _)| k).collect();
+        for key in local.keys().filter(|k| !remote.contains(k)) {
+            apply(engine, Op::Remove { key: key.clone() })?;
+        }
+        for (key, value) in page.iter() {
+            if local.get(key) != Some(value) {
+                engine.set(key.clone(), value.clone())?;
+            }
+        }
+        match upper {
+            // the smallest key greater than the last one
+            Some(last) if !done => start = last + "\0",
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// local pairs in `[start, upper]`, unbounded if `upper` is None
+fn local_range<E: KvsEngine>(engine: &E, start: &str, upper: Option<&str>) -> Result<HashMap<String, String>> {
+    let mut pairs = HashMap::new();
+    let mut from = start.to_string();
+    loop {
+        let page = engine.scan(from, BATCH)?;
+        let done = page.len() < BATCH;
+        let last = page.last().map(|(k, _)| k.clone());
+        for (key, value) in page {
+            if upper.is_some_and(|u| key.as_str() > u) {
+                return Ok(pairs);
+            }
+            pairs.insert(key, value);
+        }
+        match last {
+            Some(last) if !done => from = last + "\0",
+            _ => return Ok(pairs),
+        }
+    }
+}
@@ -1,16 +1,62 @@
+use std::collections::HashMap;
 use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
+use tracing::{debug, debug_span, error, info_span};
 
-use crate::KvsEngine;
+use crate::{KvsEngine, Keyring};
+use crate::dbengines::{EngineInfo, Op};
+use crate::error::KvsError;
+use crate::metrics::{self, Counter, Histogram, LATENCY_BUCKETS};
 use crate::msg::{Request, Response};
+use crate::raft::{NodeId, RaftHandle};
+use crate::replication::{follow, Replication};
 use crate::Result;
+use crate::slowlog::{self, SlowLog};
 use crate::thread_pool::ThreadPool;
 
+/// What a running server reports about itself, see `KvsClient::info`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    pub engine: EngineInfo,
+    pub uptime_secs: u64,
+    /// connections open now, clients and raft peers
+    pub connections: u64,
+    /// connections accepted since the server started
+    pub total_connections: u64,
+    pub pool: String,
+    pub pool_threads: u32,
+}
+
+/// The state every connection shares, besides the engine.
+struct Shared {
+    started: Instant,
+    open: AtomicU64,
+    accepted: AtomicU64,
+    pool: &'static str,
+    pool_threads: u32,
+    slowlog: Option<Arc<SlowLog>>,
+    backup_dir: Option<PathBuf>,
+}
+
 pub struct KvsServer<E: KvsEngine + 'static, P: ThreadPool> {
     engine: E,
     pool: P,
+    replica_of: Option<SocketAddr>,
+    replication: bool,
+    replication_dir: Option<PathBuf>,
+    replication_keys: Option<Keyring>,
+    raft: Option<(NodeId, HashMap<NodeId, SocketAddr>, PathBuf)>,
+    slowlog: Option<Arc<SlowLog>>,
+    backup_dir: Option<PathBuf>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -18,58 +64,293 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
         KvsServer {
             engine,
             pool,
+            replica_of: None,
+            replication: false,
+            replication_dir: None,
+            replication_keys: None,
+            raft: None,
+            slowlog: None,
+            backup_dir: None,
         }
     }
 
+    /// Start as a read-only replica tailing the leader at `leader`, which
+    /// turns `replication` on for when it is promoted.
+    pub fn replica_of(mut self, leader: SocketAddr) -> Self {
+        self.replica_of = Some(leader);
+        self.replication = true;
+        self
+    }
+
+    /// Keep the writes for replicas to pull, in memory unless there is a
+    /// `replication_dir`. Off by default, so writes are not logged twice.
+    pub fn replication(mut self) -> Self {
+        self.replication = true;
+        self
+    }
+
+    /// Journal the writes followers pull, and keep the replication offsets,
+    /// in `dir`, so a restarted leader or replica resumes instead of
+    /// resyncing everything. Turns `replication` on.
+    pub fn replication_dir(mut self, dir: PathBuf) -> Self {
+        self.replication = true;
+        self.replication_dir = Some(dir);
+        self
+    }
+
+    /// seal the values in the replication journal with `keys`
+    pub fn replication_keys(mut self, keys: Keyring) -> Self {
+        self.replication_keys = Some(keys);
+        self
+    }
+
+    /// Run as raft node `id`, `members` maps every node id, this one included,
+    /// to its server address. The raft state is kept in `dir`.
+    ///
+    /// Each peer holds a connection open, so the pool needs a thread per peer
+    /// on top of the ones serving clients.
+    pub fn raft(mut self, id: NodeId, members: HashMap<NodeId, SocketAddr>, dir: PathBuf) -> Self {
+        self.raft = Some((id, members, dir));
+        self
+    }
+
+    /// Keep the last `len` requests which took `threshold` or longer,
+    /// for `Request::SlowLog`.
+    pub fn slowlog(mut self, threshold: Duration, len: usize) -> Self {
+        self.slowlog = Some(Arc::new(SlowLog::new(threshold, len)));
+        self
+    }
+
+    /// Take `Request::Backup`s, into directories under `dir`. Without one
+    /// the server refuses them.
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backup_dir = Some(dir);
+        self
+    }
+
     pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
         let tcp_listener = TcpListener::bind(addr)?;
+        let repl = match self.replication {
+            true => Replication::open(self.replica_of, self.replication_dir, self.replication_keys)?,
+            false => Replication::off(),
+        };
+        repl.recover(&self.engine)?;
+        let repl = Arc::new(repl);
+        if self.replica_of.is_some() {
+            let (e, r) = (self.engine.clone(), repl.clone());
+            thread::Builder::new().name("replication".to_string()).spawn(move || follow(e, r))?;
+        }
+        let raft = match self.raft {
+            Some((id, members, dir)) => Some(RaftHandle::start(id, members, self.engine.clone(), dir)?),
+            None => None,
+        };
+        let shared = Arc::new(Shared {
+            started: Instant::now(),
+            open: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            pool: self.pool.name(),
+            pool_threads: self.pool.threads(),
+            slowlog: self.slowlog,
+            backup_dir: self.backup_dir,
+        });
         for stream in tcp_listener.incoming() {
             let e = self.engine.clone();
+            let repl = repl.clone();
+            let raft = raft.clone();
+            let shared = shared.clone();
+            let accepted = Instant::now();
+            let conn = shared.accepted.fetch_add(1, Ordering::Relaxed);
             self.pool.spawn(move ||
                 match stream {
                     Ok(s) => {
-                        server(e, s).unwrap();
+                        let peer = s.peer_addr().map_or_else(|_| "unknown".to_owned(), |a| a.to_string());
+                        let _span = info_span!("conn", id = conn, peer = %peer).entered();
+                        shared.open.fetch_add(1, Ordering::Relaxed);
+                        if let Err(e) = server(e, repl, raft, &shared, accepted, s) {
+                            error!(error = %e, "connection failed");
+                        }
+                        shared.open.fetch_sub(1, Ordering::Relaxed);
                     }
-                    Err(e) => eprintln!("connect err,{}", e),
+                    Err(e) => error!(error = %e, "accept failed"),
                 });
         }
         Ok(())
     }
 }
 
-fn server<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let addr = stream.local_addr()?;
-    println!("connect from addr {}", addr);
+/// `accepted` is when the connection was accepted, the wait for a pool
+/// thread since then is the queue time of its first request.
+fn server<E: KvsEngine + 'static>(
+    engine: E,
+    repl: Arc<Replication>,
+    raft: Option<Arc<RaftHandle<E>>>,
+    shared: &Shared,
+    accepted: Instant,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut queued = accepted.elapsed();
+    debug!(queue_us = queued.as_micros() as u64, "connected");
+    metrics::global().counter("kvs_connections_total", "Client and peer connections accepted", &[]).inc();
+    let mut command_metrics: HashMap<&'static str, CommandMetrics> = HashMap::new();
 
     let reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
     let reqs = Deserializer::from_reader(reader).into_iter::<Request>();
+    // reads are served by the raft leader only, once it knows it still is
+    let check_leader = || raft.as_ref().map_or(Ok(()), |r| r.read_index());
+    // requests are consumed by the engine, so the part of the key a slow log
+    // entry keeps is copied aside, into a buffer reused for every request;
+    // an owned copy is only made for a request over the threshold
+    let mut slow_key = String::new();
 
     for req in reqs {
         let req = req?;
+        let command = req.command();
+        if shared.slowlog.is_some() {
+            slow_key.clear();
+            slow_key.push_str(slowlog::logged_key(req.key()));
+        }
+        let _span = debug_span!("request", command, key_bytes = req.key_bytes()).entered();
+        let start = Instant::now();
         let resp = match req {
             Request::Get { key } => {
-                match engine.get(key) {
+                match check_leader().and_then(|_| engine.get(key)) {
                     Ok(res) => Response::Get(res),
-                    Err(e) => Response::Err(format!("{:?}", e))
+                    Err(e) => error_response(e)
                 }
             }
             Request::Set { key, value } => {
-                match engine.set(key, value.clone()) {
+                let op = Op::Set { key, value: value.clone() };
+                let res = match &raft {
+                    Some(raft) => raft.propose(op),
+                    None => repl.write(&engine, op),
+                };
+                match res {
                     Ok(()) => Response::Set(value),
-                    Err(e) => Response::Err(format!("{:?}", e))
+                    Err(e) => error_response(e)
                 }
             }
             Request::Remove { key } => {
-                match engine.remove(key) {
+                let op = Op::Remove { key };
+                let res = match &raft {
+                    Some(raft) => raft.propose(op),
+                    None => repl.write(&engine, op),
+                };
+                match res {
                     Ok(()) => Response::Remove,
-                    Err(e) => Response::Err(format!("{:?}", e))
+                    Err(e) => error_response(e)
+                }
+            }
+            Request::Scan { start, limit } => {
+                match check_leader().and_then(|_| engine.scan(start, limit)) {
+                    Ok(pairs) => Response::Scan(pairs),
+                    Err(e) => error_response(e)
+                }
+            }
+            Request::Pull { epoch, since, limit } => repl.pull(epoch, since, limit),
+            Request::ReplicaStatus => Response::ReplicaStatus(repl.status()),
+            Request::Promote => {
+                repl.promote();
+                Response::Promote
+            }
+            Request::Raft(env) => {
+                match &raft {
+                    Some(raft) => match raft.step(env) {
+                        Ok(()) => Response::Raft,
+                        Err(e) => error_response(e)
+                    },
+                    None => Response::Err("raft is not enabled".to_string()),
+                }
+            }
+            Request::Backup { dest } => {
+                match backup_path(shared.backup_dir.as_deref(), &dest).and_then(|dest| engine.backup(&dest)) {
+                    Ok(()) => Response::Backup,
+                    Err(e) => error_response(e)
+                }
+            }
+            Request::SlowLog { limit } => {
+                match &shared.slowlog {
+                    Some(slowlog) => Response::SlowLog(slowlog.latest(limit)),
+                    None => Response::Err("slowlog is not enabled".to_string()),
+                }
+            }
+            Request::Info => {
+                match engine.info() {
+                    Ok(info) => Response::Info(ServerInfo {
+                        version: env!("CARGO_PKG_VERSION").to_owned(),
+                        engine: info,
+                        uptime_secs: shared.started.elapsed().as_secs(),
+                        connections: shared.open.load(Ordering::Relaxed),
+                        total_connections: shared.accepted.load(Ordering::Relaxed),
+                        pool: shared.pool.to_owned(),
+                        pool_threads: shared.pool_threads,
+                    }),
+                    Err(e) => error_response(e)
+                }
+            }
+            Request::Compact => {
+                match engine.compact() {
+                    Ok(()) => Response::Compact,
+                    Err(e) => error_response(e)
                 }
             }
         };
+        let m = command_metrics.entry(command).or_insert_with(|| CommandMetrics::new(command));
+        let latency = start.elapsed();
+        m.requests.inc();
+        m.latency.observe(latency);
+        let result = match resp {
+            Response::Err(ref e) => e.as_str(),
+            Response::NotLeader(_) => "not leader",
+            _ => "ok",
+        };
+        if result != "ok" {
+            m.errors.inc();
+        }
+        debug!(latency_us = latency.as_micros() as u64, result, "served");
+        if let Some(slowlog) = &shared.slowlog {
+            slowlog.record(command, &slow_key, queued, latency);
+        }
+        queued = Duration::ZERO;
         //println!("server process result {:?}",resp);
         serde_json::to_writer(&mut writer, &resp)?;
         writer.flush()?;
     }
+    debug!("disconnected");
     Ok(())
-}
\ No newline at end of file
+}
+
+/// the series of one command, looked up once per connection
+struct CommandMetrics {
+    requests: Arc<Counter>,
+    errors: Arc<Counter>,
+    latency: Arc<Histogram>,
+}
+
+impl CommandMetrics {
+    fn new(command: &str) -> CommandMetrics {
+        let (registry, labels) = (metrics::global(), [("command", command)]);
+        CommandMetrics {
+            requests: registry.counter("kvs_requests_total", "Requests served, by command", &labels),
+            errors: registry.counter("kvs_request_errors_total", "Requests answered with an error, by command", &labels),
+            latency: registry.histogram("kvs_request_duration_seconds", "Time to serve a request, by command", &labels, LATENCY_BUCKETS),
+        }
+    }
+}
+
+/// `dest` under the backup directory, which it must not leave
+fn backup_path(dir: Option<&Path>, dest: &str) -> Result<PathBuf> {
+    let dir = dir.ok_or_else(|| KvsError::StringError("backups are not enabled on this server".to_string()))?;
+    let dest = Path::new(dest);
+    if dest.as_os_str().is_empty() || !dest.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(KvsError::StringError(format!("backup destination {} is not a relative path inside the backup directory", dest.display())));
+    }
+    Ok(dir.join(dest))
+}
+
+fn error_response(e: KvsError) -> Response {
+    match e {
+        KvsError::NotLeader(leader) => Response::NotLeader(leader),
+        e => Response::Err(format!("{:?}", e)),
+    }
+}
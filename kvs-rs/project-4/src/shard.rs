@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsClient, Result};
+use crate::error::KvsError;
+use crate::utils::fnv1a;
+
+/// points each shard gets on the ring, more points give a more even spread
+const VIRTUAL_NODES: u32 = 128;
+/// pairs fetched per scan while resharding
+const SCAN_BATCH: usize = 1000;
+
+/// Which `kvs-server` owns which key, through consistent hashing.
+///
+/// Adding or removing a server only moves the keys of the ring ranges it
+/// takes over or gives up, about `1 / shards` of the data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SavedShardMap")]
+pub struct ShardMap {
+    /// bumped on every change, an older map never replaces a newer one
+    pub version: u64,
+    pub shards: Vec<SocketAddr>,
+    #[serde(skip)]
+    ring: BTreeMap<u64, usize>,
+}
+
+/// A `ShardMap` as saved, the ring is rebuilt from the shards.
+#[derive(Deserialize)]
+struct SavedShardMap {
+    version: u64,
+    shards: Vec<SocketAddr>,
+}
+
+impl From<SavedShardMap> for ShardMap {
+    fn from(saved: SavedShardMap) -> Self {
+        ShardMap::new(saved.version, saved.shards)
+    }
+}
+
+impl ShardMap {
+    pub fn new(version: u64, shards: Vec<SocketAddr>) -> Self {
+        let mut ring = BTreeMap::new();
+        for (i, addr) in shards.iter().enumerate() {
+            for v in 0..VIRTUAL_NODES {
+                ring.insert(ring_hash(format!("{}#{}", addr, v).as_bytes()), i);
+            }
+        }
+        ShardMap { version, shards, ring }
+    }
+
+    /// read a map saved as JSON
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        Ok(fs::write(path, serde_json::to_vec_pretty(self)?)?)
+    }
+
+    /// the server owning `key`
+    pub fn owner(&self, key: &str) -> Result<SocketAddr> {
+        let h = ring_hash(key.as_bytes());
+        self.ring.range(h..).next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, i)| self.shards[*i])
+            .ok_or_else(|| KvsError::StringError("empty shard map".to_string()))
+    }
+}
+
+/// fnv1a with a final mix, so similar vnode names land far apart
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut h = fnv1a(bytes);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// A client routing every key to the server that owns it.
+///
+/// While keys are being moved to a new map with `reshard`, the previous map
+/// stays around: reads fall back to the old owner and removes hit both, so
+/// the data set looks the same whether a key was moved yet or not.
+pub struct ShardedClient {
+    map: ShardMap,
+    previous: Option<ShardMap>,
+    clients: HashMap<SocketAddr, KvsClient>,
+}
+
+impl ShardedClient {
+    pub fn new(map: ShardMap) -> Self {
+        ShardedClient { map, previous: None, clients: HashMap::new() }
+    }
+
+    pub fn map(&self) -> &ShardMap {
+        &self.map
+    }
+
+    /// Switch to `map`, keeping the current one as a fallback until
+    /// `finish_resharding`. Maps not newer than the current one are ignored.
+    pub fn update_map(&mut self, map: ShardMap) {
+        if map.version <= self.map.version {
+            return;
+        }
+        let old = std::mem::replace(&mut self.map, map);
+        self.previous = Some(old);
+        let (map, previous) = (&self.map, &self.previous);
+        self.clients.retain(|addr, _| {
+            map.shards.contains(addr) || previous.as_ref().is_some_and(|p| p.shards.contains(addr))
+        });
+    }
+
+    /// Forget the previous map once every key has been moved.
+    pub fn finish_resharding(&mut self) {
+        self.previous = None;
+        let map = &self.map;
+        self.clients.retain(|addr, _| map.shards.contains(addr));
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let owner = self.map.owner(&key)?;
+        self.client(owner)?.set(key, value)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let owner = self.map.owner(&key)?;
+        let value = self.client(owner)?.get(key.clone())?;
+        match self.previous_owner(&key, owner)? {
+            Some(old) if value.is_none() => self.client(old)?.get(key),
+            _ => Ok(value),
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let owner = self.map.owner(&key)?;
+        let removed = self.client(owner)?.remove(key.clone());
+        match self.previous_owner(&key, owner)? {
+            Some(old) => match (removed, self.client(old)?.remove(key)) {
+                (Err(_), Err(_)) => Err(KvsError::KeyNotFound),
+                _ => Ok(()),
+            },
+            None => removed,
+        }
+    }
+
+    /// the owner under the previous map, if the key may not be moved yet
+    fn previous_owner(&self, key: &str, owner: SocketAddr) -> Result<Option<SocketAddr>> {
+        match &self.previous {
+            Some(previous) => Ok(Some(previous.owner(key)?).filter(|old| *old != owner)),
+            None => Ok(None),
+        }
+    }
+
+    fn client(&mut self, addr: SocketAddr) -> Result<&mut KvsClient> {
+        match self.clients.entry(addr) {
+            Entry::Occupied(e) => Ok(e.into_mut()),
+            Entry::Vacant(e) => Ok(e.insert(KvsClient::connect(addr)?)),
+        }
+    }
+}
+
+/// Move every key that changes owner between `from` and `to`, scanning each
+/// server of `from`. Returns the number of keys moved.
+///
+/// A key already present on its new owner was written there through a
+/// `ShardedClient` using `to`, so it is newer and is not overwritten.
+pub fn reshard(from: &ShardMap, to: &ShardMap) -> Result<u64> {
+    let mut moved = 0;
+    let mut targets: HashMap<SocketAddr, KvsClient> = HashMap::new();
+    for source in from.shards.iter() {
+        let mut client = KvsClient::connect(source)?;
+        let mut start = String::new();
+        loop {
+            let page = client.scan(start.clone(), SCAN_BATCH)?;
+            let done = page.len() < SCAN_BATCH;
+            let last = page.last().map(|(k, _)| k.clone());
+            for (key, value) in page {
+                let owner = to.owner(&key)?;
+                if owner == *source {
+                    continue;
+                }
+                let target = match targets.entry(owner) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(KvsClient::connect(owner)?),
+                };
+                if target.get(key.clone())?.is_none() {
+                    target.set(key.clone(), value)?;
+                }
+                // gone already if a client removed it meanwhile
+                let _ = client.remove(key);
+                moved += 1;
+            }
+            match last {
+                // the smallest key greater than the last one
+                Some(last) if !done => start = last + "\0",
+                _ => break,
+            }
+        }
+    }
+    Ok(moved)
+}
@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// keys longer than this are cut in the slow log
+const MAX_KEY_LEN: usize = 256;
+
+/// the part of `key` a slow log entry keeps, cut on a char boundary
+pub(crate) fn logged_key(key: &str) -> &str {
+    let mut cut = key.len().min(MAX_KEY_LEN);
+    while !key.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    &key[..cut]
+}
+
+/// A request which took longer than the slow log threshold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlowEntry {
+    /// increases by one per entry, so gaps show entries dropped from the log
+    pub id: u64,
+    /// when the request was served, in microseconds since the Unix epoch
+    pub timestamp: u64,
+    pub command: String,
+    pub key: String,
+    /// microseconds from the request being received to its response
+    pub duration_us: u64,
+    /// microseconds spent in the engine, replication or raft included
+    pub engine_us: u64,
+    /// microseconds waiting before being served, for a pool thread on the
+    /// first request of a connection
+    pub queue_us: u64,
+}
+
+/// The last `capacity` requests slower than `threshold`, oldest dropped first.
+#[derive(Debug)]
+pub(crate) struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    inner: Mutex<(u64, VecDeque<SlowEntry>)>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, capacity: usize) -> SlowLog {
+        SlowLog { threshold, capacity, inner: Mutex::new((0, VecDeque::with_capacity(capacity))) }
+    }
+
+    /// record the request if `queue + engine` is over the threshold
+    pub fn record(&self, command: &str, key: &str, queue: Duration, engine: Duration) {
+        let duration = queue + engine;
+        if duration < self.threshold || self.capacity == 0 {
+            return;
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let mut inner = self.inner.lock().unwrap();
+        let (next_id, entries) = &mut *inner;
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(SlowEntry {
+            id: *next_id,
+            timestamp,
+            command: command.to_owned(),
+            key: logged_key(key).to_owned(),
+            duration_us: duration.as_micros() as u64,
+            engine_us: engine.as_micros() as u64,
+            queue_us: queue.as_micros() as u64,
+        });
+        *next_id += 1;
+    }
+
+    /// the `limit` latest entries, newest first
+    pub fn latest(&self, limit: usize) -> Vec<SlowEntry> {
+        self.inner.lock().unwrap().1.iter().rev().take(limit).cloned().collect()
+    }
+}
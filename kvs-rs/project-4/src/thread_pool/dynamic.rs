@@ -0,0 +1,156 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+
+use crate::Result;
+use crate::error::KvsError;
+use crate::metrics::{self, Gauge};
+
+use super::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// A thread pool which grows under load and shrinks when idle.
+///
+/// A thread is started whenever more tasks wait than threads are idle, up to
+/// the maximum, and a thread left without a task for the keep-alive exits,
+/// down to the minimum. Like `SharedQueueThreadPool`, a thread whose task panics
+/// is replaced by a new one.
+pub struct DynamicThreadPool {
+    tx: Sender<Job>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    rx: Receiver<Job>,
+    min: u32,
+    max: u32,
+    keep_alive: Duration,
+    counts: Mutex<Counts>,
+    /// tasks spawned and not started yet, of every pool
+    queued: Arc<Gauge>,
+}
+
+#[derive(Default)]
+struct Counts {
+    threads: u32,
+    /// threads waiting for a task
+    idle: u32,
+    /// tasks sent and not taken yet, a thread takes one and stops being idle
+    /// in one step, so a task it received is not also counted as waiting
+    queued: u32,
+}
+
+impl DynamicThreadPool {
+    /// Start `min` threads, which grow up to `max` and shrink back once idle
+    /// for `keep_alive`.
+    ///
+    /// Returns an error if `max` is 0 or below `min`, or if a thread fails
+    /// to spawn.
+    pub fn with_limits(min: u32, max: u32, keep_alive: Duration) -> Result<Self> {
+        if max == 0 || max < min {
+            return Err(KvsError::StringError(format!("invalid thread pool limits {}-{}", min, max)));
+        }
+        let (tx, rx) = channel::unbounded();
+        let shared = Arc::new(Shared {
+            rx,
+            min,
+            max,
+            keep_alive,
+            counts: Mutex::new(Counts::default()),
+            queued: metrics::global().gauge("kvs_pool_queue_depth", "Tasks waiting for a thread of the pool", &[]),
+        });
+        for _ in 0..min {
+            start_thread(&shared)?;
+            shared.counts.lock().unwrap().threads += 1;
+        }
+        Ok(DynamicThreadPool { tx, shared })
+    }
+}
+
+impl ThreadPool for DynamicThreadPool {
+    /// Keeps one thread, grows up to `threads`, and retires idle ones after a minute.
+    fn new(threads: u32) -> Result<Self> {
+        Self::with_limits(threads.min(1), threads, DEFAULT_KEEP_ALIVE)
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        let queued = self.shared.queued.clone();
+        queued.add(1);
+        // sent under the lock, so `queued` counts it before a thread takes it,
+        // and a thread retiring now sees the task or is not counted idle
+        let mut counts = self.shared.counts.lock().unwrap();
+        self.tx.send(Box::new(move || {
+            queued.add(-1);
+            job()
+        }))
+            .expect("The thread pool has no receiver.");
+        counts.queued += 1;
+        if counts.queued > counts.idle && counts.threads < self.shared.max {
+            match start_thread(&self.shared) {
+                Ok(()) => counts.threads += 1,
+                Err(e) => eprintln!("Failed to spawn a thread: {}", e),
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "dynamic"
+    }
+
+    /// the threads running now
+    fn threads(&self) -> u32 {
+        self.shared.counts.lock().unwrap().threads
+    }
+}
+
+fn start_thread(shared: &Arc<Shared>) -> Result<()> {
+    let worker = Worker(shared.clone());
+    thread::Builder::new().spawn(move || run_tasks(worker))?;
+    Ok(())
+}
+
+/// A running thread, replaced by a new one if a task panics.
+struct Worker(Arc<Shared>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = start_thread(&self.0) {
+                eprintln!("Failed to spawn a thread: {}", e);
+                self.0.counts.lock().unwrap().threads -= 1;
+            }
+        }
+    }
+}
+
+fn run_tasks(worker: Worker) {
+    let shared = &worker.0;
+    loop {
+        shared.counts.lock().unwrap().idle += 1;
+        let task = shared.rx.recv_timeout(shared.keep_alive);
+        let mut counts = shared.counts.lock().unwrap();
+        counts.idle -= 1;
+        match task {
+            Ok(task) => {
+                counts.queued -= 1;
+                drop(counts);
+                task();
+            }
+            Err(RecvTimeoutError::Timeout) if counts.threads > shared.min && counts.queued == 0 => {
+                counts.threads -= 1;
+                return;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // the pool is dropped and every task done
+            Err(RecvTimeoutError::Disconnected) => {
+                counts.threads -= 1;
+                return;
+            }
+        }
+    }
+}
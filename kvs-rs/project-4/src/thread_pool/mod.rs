@@ -1,12 +1,16 @@
 use crate::Result;
 
+pub use self::dynamic::DynamicThreadPool;
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::work_stealing::WorkStealingThreadPool;
 
+mod dynamic;
 mod naive;
 mod rayon;
 mod shared_queue;
+mod work_stealing;
 
 
 /// The trait that all thread pools should implement.
@@ -24,4 +28,16 @@ pub trait ThreadPool {
     /// to operate with the same number of threads &mdash; the thread count is not
     /// reduced nor is the thread pool destroyed, corrupted or invalidated.
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
+
+    /// The kind of pool, as `kvs-client info` shows it, the type name unless
+    /// the pool says otherwise.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// The number of threads running jobs, 0 if each job gets a thread of its
+    /// own or the pool does not say.
+    fn threads(&self) -> u32 {
+        0
+    }
 }
\ No newline at end of file
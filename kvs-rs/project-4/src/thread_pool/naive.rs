@@ -13,4 +13,12 @@ impl ThreadPool for NaiveThreadPool {
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
         thread::spawn(job);
     }
+
+    fn name(&self) -> &'static str {
+        "naive"
+    }
+
+    fn threads(&self) -> u32 {
+        0
+    }
 }
\ No newline at end of file
@@ -18,4 +18,12 @@ impl ThreadPool for RayonThreadPool {
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
         self.0.spawn(job)
     }
+
+    fn name(&self) -> &'static str {
+        "rayon"
+    }
+
+    fn threads(&self) -> u32 {
+        self.0.current_num_threads() as u32
+    }
 }
@@ -1,8 +1,10 @@
+use std::sync::Arc;
 use std::thread;
 
 use crossbeam::channel::{self, Receiver, Sender};
 
 use crate::Result;
+use crate::metrics::{self, Gauge};
 
 use super::ThreadPool;
 
@@ -17,6 +19,9 @@ use super::ThreadPool;
 /// can decrease to zero, then spawning a task to the thread pool will panic.
 pub struct SharedQueueThreadPool {
     tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    /// tasks spawned and not started yet, of every pool
+    queued: Arc<Gauge>,
+    threads: u32,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
@@ -26,7 +31,8 @@ impl ThreadPool for SharedQueueThreadPool {
             let rx = TaskReceiver(rx.clone());
             thread::Builder::new().spawn(move || run_tasks(rx))?;
         }
-        Ok(SharedQueueThreadPool { tx })
+        let queued = metrics::global().gauge("kvs_pool_queue_depth", "Tasks waiting for a thread of the pool", &[]);
+        Ok(SharedQueueThreadPool { tx, queued, threads })
     }
 
     /// Spawns a function into the thread pool.
@@ -35,9 +41,24 @@ impl ThreadPool for SharedQueueThreadPool {
     ///
     /// Panics if the thread pool has no thread.
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static, {
-        self.tx.send(Box::new(job))
+        let queued = self.queued.clone();
+        queued.add(1);
+        self.tx.send(Box::new(move || {
+            queued.add(-1);
+            job()
+        }))
             .expect("The thread pool has no thread.");
     }
+
+    fn name(&self) -> &'static str {
+        "shared_queue"
+    }
+
+    /// the threads it was made with, a thread replacing one which panicked
+    /// may have failed to start
+    fn threads(&self) -> u32 {
+        self.threads
+    }
 }
 
 #[derive(Clone)]
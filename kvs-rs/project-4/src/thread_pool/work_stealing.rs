@@ -0,0 +1,147 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+
+use crate::Result;
+use crate::metrics::{self, Gauge};
+
+use super::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool where each thread has its own deque of tasks.
+///
+/// Spawned tasks go to a shared injector queue. An idle thread takes a batch
+/// of them into its deque, waking a sleeping thread to steal from it, and
+/// once both are empty steals from the deques of the other threads, so a
+/// thread stuck on a long task does not hold back the tasks it took. Like
+/// `SharedQueueThreadPool`, a thread whose task panics is replaced by a new
+/// one, which keeps the deque of the old one.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    threads: u32,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    /// taken to check for work before sleeping, and to wake sleepers
+    sleep: Mutex<()>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+    /// tasks spawned and not started yet, of every pool
+    queued: Arc<Gauge>,
+}
+
+impl Shared {
+    fn find_task(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| loop {
+            let steal = self.injector.steal_batch_and_pop(local)
+                .or_else(|| self.stealers.iter().map(|s| s.steal()).collect());
+            match steal {
+                Steal::Success(job) => {
+                    // the rest of a batch is left to steal, a sleeping thread may take it
+                    if self.stealers.iter().any(|s| !s.is_empty()) {
+                        self.wake_one();
+                    }
+                    return Some(job);
+                }
+                Steal::Empty => return None,
+                Steal::Retry => {}
+            }
+        })
+    }
+
+    /// under the sleep lock, so a thread about to sleep sees the work or the wake-up
+    fn wake_one(&self) {
+        let _sleep = self.sleep.lock().unwrap();
+        self.wake.notify_one();
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let workers: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(|w| w.stealer()).collect(),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            queued: metrics::global().gauge("kvs_pool_queue_depth", "Tasks waiting for a thread of the pool", &[]),
+        });
+        for local in workers {
+            let state = WorkerState { local: Some(local), shared: shared.clone() };
+            thread::Builder::new().spawn(move || run_tasks(state))?;
+        }
+        Ok(WorkStealingThreadPool { shared, threads })
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        let queued = self.shared.queued.clone();
+        queued.add(1);
+        self.shared.injector.push(Box::new(move || {
+            queued.add(-1);
+            job()
+        }));
+        self.shared.wake_one();
+    }
+
+    fn name(&self) -> &'static str {
+        "work_stealing"
+    }
+
+    fn threads(&self) -> u32 {
+        self.threads
+    }
+}
+
+/// the threads finish the tasks already spawned, then exit
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        let _sleep = self.shared.sleep.lock().unwrap();
+        self.shared.wake.notify_all();
+    }
+}
+
+/// The deque of a thread, handed to a new thread if a task panics.
+struct WorkerState {
+    local: Option<Worker<Job>>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for WorkerState {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let state = WorkerState { local: self.local.take(), shared: self.shared.clone() };
+            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(state)) {
+                eprintln!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+fn run_tasks(state: WorkerState) {
+    let (local, shared) = (state.local.as_ref().unwrap(), &state.shared);
+    loop {
+        if let Some(job) = shared.find_task(local) {
+            job();
+            continue;
+        }
+        let sleep = shared.sleep.lock().unwrap();
+        if shared.has_work() {
+            continue;
+        }
+        if shared.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        drop(shared.wake.wait(sleep).unwrap());
+    }
+}
@@ -19,6 +19,27 @@ pub fn format_path(path: &Path, id: u16) -> PathBuf {
     path.join(format!("{}{}", id, SUFFIX))
 }
 
+/// bytes of the files under `path`, subdirectories included
+pub fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() { dir_size(&entry.path())? } else { meta.len() };
+    }
+    Ok(size)
+}
+
 pub fn del_file(path: PathBuf) -> Result<()> {
     Ok(fs::remove_file(path)?)
-}
\ No newline at end of file
+}
+
+/// 64-bit FNV-1a, stable across builds and platforms
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
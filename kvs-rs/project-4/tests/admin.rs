@@ -0,0 +1,161 @@
+use std::fs;
+use std::process::Command;
+
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvStore, Op, Result, SledKvsEngine};
+use kvs::admin::{self, migrate, summarize, RepairMode};
+
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let (kvs_dir, sled_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let store = KvStore::open(kvs_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let engine = SledKvsEngine::open(sled_dir.path())?;
+    let summary = migrate(&store, &engine)?;
+    assert_eq!(summary.keys, 1999);
+    assert_eq!(summarize(&engine)?, summary);
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.get("key1999".to_owned())?, Some("value1999".to_owned()));
+
+    // the target must be empty
+    assert!(migrate(&store, &engine).is_err());
+    Ok(())
+}
+
+#[test]
+fn cli_migrate_kvs_to_sled_and_back() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(dir.path())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+    fs::write(dir.path().join("engine"), "kvs")?;
+
+    // the engine file names the source engine
+    Command::cargo_bin("kvs-admin").unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs", dir.path().to_str().unwrap()])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin").unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(contains("Migrated 100 keys"));
+    assert_eq!(fs::read_to_string(dir.path().join("engine"))?, "sled");
+    assert!(!dir.path().join("1.log").exists());
+    {
+        let engine = SledKvsEngine::open(dir.path())?;
+        assert_eq!(engine.get("key42".to_owned())?, Some("value42".to_owned()));
+    }
+
+    Command::cargo_bin("kvs-admin").unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs", "--keep-source", dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dir.path().join("engine"))?, "kvs");
+    assert!(dir.path().join("db").exists());
+    let store = KvStore::open(dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+/// a directory with two logs, the second one corrupted in the middle
+fn corrupted_dir() -> Result<TempDir> {
+    let dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(dir.path())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+    {
+        let store = KvStore::open(dir.path())?;
+        for i in 10..20 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+    }
+    let log = dir.path().join("2.log");
+    let data = fs::read_to_string(&log)?;
+    let off = data.find("{\"Set\":{\"key\":\"key15\"").unwrap();
+    fs::write(&log, format!("{}garbage{}", &data[..off], &data[off + 3..]))?;
+    Ok(dir)
+}
+
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let dir = corrupted_dir()?;
+    assert!(KvStore::open(dir.path()).is_err());
+
+    let report = admin::verify(dir.path())?;
+    assert_eq!(report.bad.len(), 1);
+    assert_eq!(report.bad[0].id, 2);
+    assert_eq!(report.records, 20);
+    assert!(report.dangling_tombstones.is_empty());
+
+    let (records, _) = admin::dump(dir.path())?;
+    let data = fs::read(dir.path().join("1.log"))?;
+    let first = &records[0];
+    let op: Op = serde_json::from_slice(&data[first.off as usize..(first.off + first.size) as usize])?;
+    assert_eq!(serde_json::to_string(&op)?, serde_json::to_string(&first.op)?);
+
+    // skip keeps the records after the bad one
+    assert_eq!(admin::repair(dir.path(), RepairMode::Skip)?.len(), 1);
+    assert!(admin::verify(dir.path())?.is_ok());
+    let store = KvStore::open(dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key15".to_owned())?, None);
+    assert_eq!(store.get("key16".to_owned())?, Some("value16".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_truncate_and_stats() -> Result<()> {
+    let dir = corrupted_dir()?;
+    let path = dir.path().to_str().unwrap();
+    Command::cargo_bin("kvs-admin").unwrap()
+        .args(&["verify", path])
+        .assert()
+        .failure()
+        .stdout(contains("corrupted: 2.log"));
+    Command::cargo_bin("kvs-admin").unwrap()
+        .args(&["repair", "--mode", "truncate", path])
+        .assert()
+        .success()
+        .stdout(contains("dropped 1 bad ranges"));
+    Command::cargo_bin("kvs-admin").unwrap()
+        .args(&["verify", path])
+        .assert()
+        .success()
+        .stdout(contains("ok"));
+
+    let store = KvStore::open(dir.path())?;
+    assert_eq!(store.get("key14".to_owned())?, Some("value14".to_owned()));
+    assert_eq!(store.get("key16".to_owned())?, None);
+    // the remove of key0 was after the bad record
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    drop(store);
+
+    let stats = admin::stats(dir.path())?;
+    assert_eq!(stats[0].id, 1);
+    assert_eq!(stats[0].live_records, 10);
+    assert_eq!(stats[0].dead_bytes, 0);
+    Command::cargo_bin("kvs-admin").unwrap()
+        .args(&["stats", path])
+        .assert()
+        .success()
+        .stdout(contains("live bytes"));
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use tempfile::TempDir;
+
+use kvs::{Keyring, KvsClient, KvsEngine, KvsServer, KvStore, KvStoreOptions, MemoryEngine, Result, SledKvsEngine};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+#[test]
+fn backup_and_restore_kvs() -> Result<()> {
+    let (dir, backup, restored) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+    let store = KvStore::open(dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.backup(&backup.path().join("1"))?;
+
+    // later writes are not in the backup
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    let store = KvStore::restore(&backup.path().join("1"), restored.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // a backup never goes into a directory holding data
+    assert!(KvStore::restore(&backup.path().join("1"), restored.path()).is_err());
+    assert!(store.backup(&backup.path().join("1")).is_err());
+    Ok(())
+}
+
+#[test]
+fn backup_blob_values() -> Result<()> {
+    let (dir, backup, restored) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+    let store = KvStore::open_with(dir.path(), KvStoreOptions::new().blob_threshold(100).blob_file_size(16 * 1024))?;
+    for round in 0..5 {
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("{}{}", round, "v".repeat(1000)))?;
+        }
+    }
+    store.backup(backup.path())?;
+
+    // a backup restores into another engine too, dead sets into collected blob files are skipped
+    let engine = MemoryEngine::restore(backup.path(), restored.path())?;
+    let store = KvStore::restore(backup.path(), &restored.path().join("kvs"))?;
+    for i in 0..20 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("4{}", "v".repeat(1000))));
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("4{}", "v".repeat(1000))));
+    }
+    Ok(())
+}
+
+#[test]
+fn backup_encrypted_kvs() -> Result<()> {
+    let (dir, backup, restored) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+    let keys = Keyring::parse(&format!("1:{}", "11".repeat(32)))?;
+    let options = || KvStoreOptions::new().encryption(keys.clone());
+    let store = KvStore::open_with(dir.path(), options())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("secret{}", i))?;
+    }
+    store.backup(backup.path())?;
+
+    // the backup is sealed like the store, so it needs the same keys
+    assert!(KvStore::restore_with(backup.path(), &restored.path().join("nokeys"), KvStoreOptions::new()).is_err());
+    let store = KvStore::restore_with(backup.path(), restored.path(), options())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("secret{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn backup_sled_restore_kvs() -> Result<()> {
+    let (dir, backup, restored) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+    let engine = SledKvsEngine::new(sled::open(dir.path())?);
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.backup(backup.path())?;
+
+    let store = KvStore::restore(backup.path(), restored.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn corrupted_backup_is_refused() -> Result<()> {
+    let (dir, backup, restored) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+    let store = KvStore::open(dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.backup(backup.path())?;
+
+    let log = fs::read_dir(backup.path())?
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "log"))
+        .unwrap();
+    let mut data = fs::read(&log)?;
+    data[10] ^= 1;
+    fs::write(&log, data)?;
+    assert!(KvStore::restore(backup.path(), restored.path()).is_err());
+    assert!(SledKvsEngine::restore(backup.path(), &restored.path().join("sled")).is_err());
+    Ok(())
+}
+
+#[test]
+fn backup_running_server() -> Result<()> {
+    let (dir, backup, restored) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+    let addr = "127.0.0.1:4140";
+    let server = KvsServer::new(KvStore::open(dir.path())?, SharedQueueThreadPool::new(4)?)
+        .backup_dir(backup.path().to_owned());
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // writes keep going while the backup is taken
+    let writer = thread::spawn(move || {
+        let mut client = KvsClient::connect(addr).unwrap();
+        for i in 100..2000 {
+            client.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+    });
+    Command::cargo_bin("kvs-server").unwrap()
+        .args(&["backup", "--addr", addr, "snapshot"])
+        .assert()
+        .success();
+    writer.join().unwrap();
+    // backups stay inside the backup directory
+    let outside = restored.path().join("outside");
+    assert!(client.backup(outside.to_str().unwrap().to_owned()).is_err());
+    assert!(client.backup("../outside".to_owned()).is_err());
+    assert!(client.backup("snapshot/../../outside".to_owned()).is_err());
+    assert!(!outside.exists());
+
+    Command::cargo_bin("kvs-server").unwrap()
+        .args(&["restore", backup.path().join("snapshot").to_str().unwrap()])
+        .current_dir(&restored)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(restored.path().join("engine"))?, "kvs");
+    let store = KvStore::open(restored.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    // the backup is a prefix of the writes: every later key is either there or not
+    let present: Vec<bool> = (100..2000)
+        .map(|i| store.get(format!("key{}", i)).unwrap().is_some())
+        .collect();
+    assert!(present.windows(2).all(|w| w[0] || !w[1]));
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::thread;
+
+use tempfile::TempDir;
+
+use kvs::{BTreeEngine, BTreeOptions, KvsEngine, Result};
+
+#[test]
+fn set_get_remove() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = BTreeEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.remove("key1".to_owned())?;
+    assert!(engine.remove("key1".to_owned()).is_err());
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.set("k".repeat(2000), "value".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn splits_and_scans() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    // a pool far smaller than the tree, so pages are evicted and read back
+    let options = || BTreeOptions::new().cache_pages(16);
+    let engine = BTreeEngine::open_with(temp_dir.path(), options())?;
+    for i in (0..5000).rev() {
+        engine.set(format!("key{:05}", i), format!("value{}", i))?;
+    }
+    for i in (0..5000).step_by(2) {
+        engine.remove(format!("key{:05}", i))?;
+    }
+    assert!(engine.pages() > 50);
+
+    let check = |engine: &BTreeEngine| -> Result<()> {
+        for i in 0..5000 {
+            let expected = if i % 2 == 0 { None } else { Some(format!("value{}", i)) };
+            assert_eq!(engine.get(format!("key{:05}", i))?, expected);
+        }
+        let page = engine.scan("key02500".to_owned(), 3)?;
+        let keys: Vec<&str> = page.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["key02501", "key02503", "key02505"]);
+        let all = engine.scan(String::new(), 10000)?;
+        assert_eq!(all.len(), 2500);
+        assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(engine.info()?.keys, 2500);
+        Ok(())
+    };
+    check(&engine)?;
+    drop(engine);
+    let engine = BTreeEngine::open_with(temp_dir.path(), options())?;
+    check(&engine)
+}
+
+#[test]
+fn parallel_reads_and_writes() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    // small enough that readers keep missing and evicting pages
+    let engine = BTreeEngine::open_with(temp_dir.path(), BTreeOptions::new().cache_pages(16))?;
+    for i in 0..2000 {
+        engine.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    let readers: Vec<_> = (0..4).map(|t| {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in (t..2000).step_by(4) {
+                assert_eq!(engine.get(format!("key{:04}", i))?, Some(format!("value{}", i)));
+                let page = engine.scan(format!("key{:04}", i), 2)?;
+                assert_eq!(page[0], (format!("key{:04}", i), format!("value{}", i)));
+            }
+            Ok(())
+        })
+    }).collect();
+    for i in 2000..3000 {
+        engine.set(format!("key{:04}", i), "x".repeat(2000))?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(engine.scan(String::new(), 5000)?.len(), 3000);
+    Ok(())
+}
+
+#[test]
+fn large_values_reuse_pages() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = BTreeEngine::open(temp_dir.path())?;
+    let value = |i: usize| format!("{}", i).repeat(10000);
+    engine.set("key".to_owned(), value(1))?;
+    let pages = engine.pages();
+    for i in 2..20 {
+        engine.set("key".to_owned(), value(i))?;
+    }
+    assert_eq!(engine.get("key".to_owned())?, Some(value(19)));
+    // the overflow pages of each old value are freed and taken again
+    assert!(engine.pages() <= pages + 13, "{} pages after {}", engine.pages(), pages);
+    Ok(())
+}
+
+#[test]
+fn replays_the_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = BTreeEngine::open(temp_dir.path())?;
+    for i in 0..1000 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.checkpoint()?;
+    for i in 0..1000 {
+        engine.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    drop(engine);
+
+    // the writes since the checkpoint are only in the log, which ends with a torn batch
+    let mut wal = OpenOptions::new().append(true).open(temp_dir.path().join("btree.wal"))?;
+    wal.write_all(&[3, 0, 0, 0, 1, 2])?;
+    drop(wal);
+    let engine = BTreeEngine::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("new{}", i)));
+    }
+    Ok(())
+}
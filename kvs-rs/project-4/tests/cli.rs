@@ -2,12 +2,15 @@ use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use tempfile::TempDir;
 
+use kvs::KvsClient;
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -172,6 +175,211 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// requests are logged inside the span of their connection
+#[test]
+fn cli_json_request_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4011", "--log-format", "json", "--log-level", "debug"])
+        .current_dir(&temp_dir)
+        .env_remove("RUST_LOG")
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    let lines: Vec<serde_json::Value> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert!(lines.iter().any(|l| l["fields"]["message"].as_str().unwrap_or("").contains("127.0.0.1:4011")));
+    let served = lines.iter().find(|l| l["fields"]["message"] == "served").expect("no request logged");
+    assert_eq!(served["fields"]["result"], "ok");
+    assert_eq!(served["span"]["command"], "set");
+    assert_eq!(served["span"]["key_bytes"], 4);
+    assert!(served["spans"][0]["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+}
+
+#[test]
+fn cli_slowlog() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4012", "--slowlog-threshold", "0", "--slowlog-len", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for key in &["key1", "key2", "key3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, "value", "--addr", "127.0.0.1:4012"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["slowlog", "--limit", "5", "--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", stdout);
+    assert!(lines[0].starts_with("2 ") && lines[0].contains(" set \"key3\" duration="), "{}", stdout);
+    assert!(lines[1].starts_with("1 ") && lines[1].contains(" set \"key2\" "), "{}", stdout);
+}
+
+#[test]
+fn cli_slowlog_disabled() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["slowlog", "--addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("slowlog is not enabled"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_info() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for key in &["key1", "key2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, "value", "--addr", "127.0.0.1:4014"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("version: {}\n", env!("CARGO_PKG_VERSION"))))
+        .stdout(contains("engine: kvs\n"))
+        .stdout(contains("connections: 1\ntotal_connections: 3\n"))
+        .stdout(contains("keys: 2\n"))
+        .stdout(contains("generation: 1\n"))
+        .stdout(contains("pool: rayon (4 threads)\n"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4015", "--compact-manual"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", "127.0.0.1:4015"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+    client(&["set", "key1", "value1"]);
+    client(&["set", "key1", "value2"]);
+    client(&["info"]).stdout(contains("generation: 1\n")).stdout(contains("dead_bytes: 0\n").not());
+    client(&["compact"]);
+    client(&["info"]).stdout(contains("generation: 3\n")).stdout(contains("dead_bytes: 0\n"));
+    client(&["get", "key1"]).stdout("value2\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_compact_window() {
+    let temp_dir = TempDir::new().unwrap();
+    // a window around now, which only limits the default dead bytes trigger
+    let hour = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 3600 % 24;
+    let window = format!("{}-{}", hour, (hour + 2) % 24);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4017", "--compact-window", &window])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvsClient::connect("127.0.0.1:4017").unwrap();
+    let value = "v".repeat(20 * 1024);
+    for _ in 0..60 {
+        client.set("key1".to_owned(), value.clone()).unwrap();
+    }
+    let info = client.info().unwrap();
+    assert!(info.engine.generation > Some(1), "{:?}", info.engine);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_dynamic_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4016", "--pool", "dynamic", "--min-threads", "2", "--max-threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("pool: dynamic (2 threads)\n"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4016", "--pool", "dynamic", "--min-threads", "8", "--max-threads", "4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -335,4 +543,54 @@ fn cli_access_server_kvs_engine() {
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
-}
\ No newline at end of file
+}
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_access_server_btree_engine() {
+    cli_access_server("btree", "127.0.0.1:4009");
+}
+
+#[test]
+fn cli_memory_engine_snapshot() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let start = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "memory", "--snapshot-every", "1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+
+    let mut child = start();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    // let a snapshot run before the server dies
+    thread::sleep(Duration::from_secs(2));
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let mut child = start();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -1,10 +1,13 @@
+use std::fs;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::{KvsEngine, KvStore, Result};
+use kvs::{Codec, CompactionPolicy, IndexMode, Keyring, KvsEngine, KvStore, KvStoreOptions, Result};
+use kvs::admin;
 
 // Should get previously stored value
 #[test]
@@ -129,6 +132,576 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Manual, ratio, timed and windowed policies, and the rate limit
+#[test]
+fn compaction_policies() -> Result<()> {
+    let overwrite = |store: &KvStore, rounds: usize| -> Result<()> {
+        for round in 0..rounds {
+            for i in 0..100 {
+                store.set(format!("key{}", i), format!("{}-{}", "v".repeat(100), round))?;
+            }
+        }
+        Ok(())
+    };
+    let dead = |store: &KvStore| store.info().unwrap().dead_bytes.unwrap();
+    // the policy compacts on a background thread
+    let wait_for = |done: &dyn Fn() -> bool| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "no compaction");
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    // manual only compacts when asked, past the default 1 MiB of dead bytes
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compaction(CompactionPolicy::new()))?;
+    overwrite(&store, 120)?;
+    assert!(dead(&store) > 1024 * 1024);
+    store.compact()?;
+    assert_eq!(dead(&store), 0);
+    // the live records went to log 2, the writes made meanwhile to log 3
+    assert_eq!(store.info()?.generation, Some(3));
+    assert_eq!(store.get("key7".to_owned())?, Some(format!("{}-119", "v".repeat(100))));
+
+    // half dead compacts well before 1 MiB
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy::new().dead_ratio(0.5);
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compaction(policy))?;
+    overwrite(&store, 20)?;
+    wait_for(&|| dead(&store) < 200 * 1024);
+    assert!(store.info()?.generation > Some(1));
+
+    // the timer compacts an idle store, unless outside the window
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy::new().every(Duration::from_millis(100));
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compaction(policy.clone()))?;
+    overwrite(&store, 2)?;
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(dead(&store), 0);
+    let hour = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 3600 % 24) as u8;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = policy.window((hour + 2) % 24, (hour + 3) % 24);
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compaction(policy))?;
+    overwrite(&store, 2)?;
+    thread::sleep(Duration::from_millis(2500));
+    assert!(dead(&store) > 0);
+
+    // 100 live records of about 130 bytes at 40 KB/s when the policy
+    // compacts, which writes do not wait for, and at full speed when asked to
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy::new().dead_bytes(10 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compaction(policy).compaction_rate(40_000))?;
+    overwrite(&store, 1)?;
+    let start = Instant::now();
+    overwrite(&store, 1)?;
+    assert!(start.elapsed() < Duration::from_millis(250), "{:?}", start.elapsed());
+    wait_for(&|| store.info().unwrap().generation == Some(3));
+    // after the running one
+    store.compact()?;
+    assert!(start.elapsed() >= Duration::from_millis(250), "{:?}", start.elapsed());
+    let start = Instant::now();
+    store.compact()?;
+    assert!(start.elapsed() < Duration::from_millis(250), "{:?}", start.elapsed());
+    Ok(())
+}
+
+// Writes go on while a throttled compaction copies the live records, and win over the copies
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy::new().dead_bytes(64 * 1024);
+    let options = KvStoreOptions::new().compaction(policy).compaction_rate(40_000);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // about 16 KB of live records take the compaction over 300 ms
+    let filler = store.clone();
+    let compaction = thread::spawn(move || -> Result<()> {
+        while filler.info()?.generation == Some(1) {
+            filler.set("filler".to_owned(), "v".repeat(1000))?;
+        }
+        Ok(())
+    });
+    while store.info()?.generation == Some(1) {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let start = Instant::now();
+    for i in 0..100 {
+        store.set(format!("key{}", i), "changed".to_owned())?;
+        store.remove(format!("key{}", i + 100))?;
+    }
+    assert!(start.elapsed() < Duration::from_millis(200), "{:?}", start.elapsed());
+    compaction.join().unwrap()?;
+    assert_eq!(store.info()?.generation, Some(3));
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some("changed".to_owned()));
+            assert_eq!(store.get(format!("key{}", i + 100))?, None);
+        }
+        for i in 200..500 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    // and once more replayed from the compacted log, its hint and the writes made meanwhile
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// Reads racing a compaction which deletes the log they looked the key up in
+#[test]
+fn reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactionPolicy::new().dead_bytes(16 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compaction(policy).max_open_files(2))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let readers: Vec<_> = (0..4).map(|_| {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 0..200 {
+                for i in 0..100 {
+                    assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)), "round {}", round);
+                }
+            }
+            Ok(())
+        })
+    }).collect();
+    let filler = "v".repeat(1000);
+    while readers.iter().any(|reader| !reader.is_finished()) {
+        store.set("filler".to_owned(), filler.clone())?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert!(store.info()?.generation > Some(3));
+    Ok(())
+}
+
+/// overwrite one big value until the store compacts, then return the compacted log id
+fn compact_with_hint(store: &KvStore, dir: &TempDir) -> Result<String> {
+    let value = "v".repeat(2048);
+    for _ in 0..1000 {
+        store.set("big".to_owned(), value.clone())?;
+        let hint = fs::read_dir(dir.path())?
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .find(|name| name.ends_with(".hint"));
+        if let Some(hint) = hint {
+            return Ok(hint.trim_end_matches(".hint").to_owned());
+        }
+    }
+    panic!("No compaction detected");
+}
+
+#[test]
+fn open_with_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    compact_with_hint(&store, &temp_dir)?;
+    // written after the hinted part of the log
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for key_id in 2..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.get("big".to_owned())?, Some("v".repeat(2048)));
+    Ok(())
+}
+
+#[test]
+fn stale_hint_is_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let id = compact_with_hint(&store, &temp_dir)?;
+    drop(store);
+
+    // a hint pointing at the wrong records must not be trusted
+    let hint = temp_dir.path().join(format!("{}.hint", id));
+    let shifted = fs::read_to_string(&hint)?.replace("\"off\":", "\"off\":1");
+    fs::write(&hint, shifted)?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
+
+#[test]
+fn open_from_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().checkpoint_every(100);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..250 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(temp_dir.path().join("index.checkpoint").exists());
+    drop(store);
+
+    // the writes after the last checkpoint are replayed from the log
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..250 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.checkpoint()?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stale_checkpoint_is_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.checkpoint()?;
+    drop(store);
+
+    // a checkpoint pointing at the wrong records must not be trusted
+    let path = temp_dir.path().join("index.checkpoint");
+    let shifted = fs::read_to_string(&path)?.replace("\"off\":", "\"off\":1");
+    fs::write(&path, shifted)?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
+
+#[test]
+fn hashed_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_mode(IndexMode::Hashed).checkpoint_every(300);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key0".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, None);
+
+    // scans still come back in key order
+    let page = store.scan("key5".to_owned(), 3)?;
+    let keys: Vec<&str> = page.iter().map(|(k, _)| k.as_str()).collect();
+    assert_eq!(keys, vec!["key5", "key50", "key500"]);
+
+    compact_with_hint(&store, &temp_dir)?;
+    drop(store);
+    for mode in [IndexMode::Hashed, IndexMode::Ordered] {
+        let store = KvStore::open_with(temp_dir.path(), options.clone().index_mode(mode))?;
+        assert_eq!(store.get("key0".to_owned())?, Some("changed".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        for key_id in 2..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+    }
+    Ok(())
+}
+
+fn log_bytes(dir: &TempDir) -> Result<u64> {
+    Ok(fs::read_dir(dir.path())?
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "log"))
+        .map(|p| p.metadata().unwrap().len())
+        .sum())
+}
+
+// Compressed values read back through get, compaction, reopen and a codec change
+#[test]
+fn compressed_values() -> Result<()> {
+    let big = |i: usize| format!("{{\"id\":{},\"payload\":\"{}\"}}", i, "abc".repeat(200));
+    for codec in [Codec::Lz4, Codec::Snappy, Codec::Zstd].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compression(*codec))?;
+        for i in 0..1000 {
+            store.set(format!("key{}", i), big(i))?;
+            store.set(format!("small{}", i), "tiny".to_owned())?;
+        }
+        // the raw values take over 600 KB
+        assert!(log_bytes(&temp_dir)? < 300 * 1024, "{:?} logs take {} bytes", codec, log_bytes(&temp_dir)?);
+        for i in 0..1000 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(big(i)));
+        }
+        // values below the threshold stay readable in the log
+        let log = fs::read_to_string(temp_dir.path().join("1.log"))?;
+        assert!(log.contains("\"tiny\""));
+
+        // another codec for the new records, enough overwrites to compact
+        let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compression(Codec::Zstd))?;
+        for _ in 0..3 {
+            for i in 0..1000 {
+                store.set(format!("key{}", i), big(i + 1))?;
+            }
+        }
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key7".to_owned())?, Some(big(8)));
+        assert_eq!(store.scan("key99".to_owned(), 2)?.len(), 2);
+        assert!(admin::verify(temp_dir.path())?.is_ok());
+    }
+    Ok(())
+}
+
+// Records are sealed with the active key, and sealed again with a new one on compaction
+#[test]
+fn encrypted_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key1 = format!("1:{}", "11".repeat(32));
+    let key2 = format!("2:{}", "22".repeat(32));
+    let open = |keys: &str| KvStore::open_with(temp_dir.path(), KvStoreOptions::new().encryption(Keyring::parse(keys)?));
+
+    let store = open(&key1)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("secret{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+    let log = fs::read_to_string(temp_dir.path().join("1.log"))?;
+    assert!(!log.contains("secret") && !log.contains("key1"));
+
+    // a missing or wrong key is reported as such
+    let wrong_key = |r: Result<KvStore>| r.err().is_some_and(|e| e.to_string().contains("encryption key 1"));
+    assert!(wrong_key(KvStore::open(temp_dir.path())));
+    assert!(wrong_key(open(&format!("1:{}", "33".repeat(32)))));
+
+    // rotate: the overwrites compact, which seals the live records with key 2
+    let store = open(&format!("{},{}", key1, key2))?;
+    assert_eq!(store.get("key42".to_owned())?, Some("secret42".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    let value = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set("filler".to_owned(), format!("{}{}", value, i))?;
+    }
+    drop(store);
+    let store = open(&key2)?;
+    assert_eq!(store.get("key42".to_owned())?, Some("secret42".to_owned()));
+    assert_eq!(store.scan(String::new(), 1000)?.len(), 100);
+    Ok(())
+}
+
+// Values in blob files are sealed again with the new key on compaction too
+#[test]
+fn encrypted_blobs_rotate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key1 = format!("1:{}", "11".repeat(32));
+    let key2 = format!("2:{}", "22".repeat(32));
+    let open = |keys: &str| {
+        let options = KvStoreOptions::new().blob_threshold(1000).encryption(Keyring::parse(keys)?);
+        KvStore::open_with(temp_dir.path(), options)
+    };
+    let big = |i: usize| format!("{}{}", i, "v".repeat(2000));
+
+    let store = open(&key1)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), big(i))?;
+    }
+    drop(store);
+
+    let store = open(&format!("{},{}", key1, key2))?;
+    store.compact()?;
+    drop(store);
+    let store = open(&key2)?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(big(i)));
+    }
+    Ok(())
+}
+
+// A sealed record past 64 KiB keeps its full size in the index and hints
+#[test]
+fn large_encrypted_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys = Keyring::parse(&format!("1:{}", "11".repeat(32)))?;
+    let open = || KvStore::open_with(temp_dir.path(), KvStoreOptions::new().encryption(keys.clone()));
+    let large = "v".repeat(200 * 1024);
+
+    let store = open()?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    drop(store);
+
+    // replayed from the log, then moved by a compaction and read back from its hint
+    let store = open()?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    store.compact()?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Large values live in blob files, which are collected once mostly garbage
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().blob_threshold(1000).blob_file_size(64 * 1024);
+    let blob_bytes = || -> u64 {
+        fs::read_dir(temp_dir.path()).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "blob"))
+            .map(|p| fs::metadata(p).unwrap().len())
+            .sum()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for round in 0..20 {
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("{}{}", round, "v".repeat(2000)))?;
+        }
+        store.set(format!("small{}", round), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    // 50 live values of 2 KB, the garbage of the 19 older rounds is collected in the background
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while blob_bytes() >= 400 * 1024 {
+        assert!(Instant::now() < deadline, "{} bytes of blobs", blob_bytes());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let log_bytes = log_bytes(&temp_dir)?;
+    assert!(log_bytes < 200 * 1024, "{} bytes of logs", log_bytes);
+    store.gc_blobs()?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key7".to_owned())?, Some(format!("19{}", "v".repeat(2000))));
+    assert_eq!(store.get("small3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.scan(String::new(), 100)?.len(), 49 + 20);
+    assert!(admin::verify(temp_dir.path())?.is_ok());
+    Ok(())
+}
+
+// The value cache is shared by clones and never serves an overwritten value
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().cache_capacity(64 * 1024))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let clone = store.clone();
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, Some("value2".to_owned()));
+    clone.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // the cache stays within its capacity, evicting the least recently used values
+    let value = "v".repeat(1000);
+    for i in 0..1000 {
+        store.set(format!("key{}", i), value.clone())?;
+        store.get(format!("key{}", i))?;
+    }
+    assert!(store.cache_stats().unwrap().bytes <= 64 * 1024);
+    assert_eq!(store.get("key999".to_owned())?, Some(value.clone()));
+    let hits = store.cache_stats().unwrap().hits;
+    assert_eq!(store.get("key0".to_owned())?, Some(value));
+    assert_eq!(store.cache_stats().unwrap().hits, hits);
+    Ok(())
+}
+
+// Sealed generations are read through a mapping, which compaction releases
+#[test]
+fn mmap_sealed_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let seek = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().mmap(false))?;
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, seek.get(format!("key{}", i))?);
+    }
+    drop(seek);
+    let clone = store.clone();
+    let value = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 10), value.clone())?;
+    }
+    assert_eq!(clone.get("key5".to_owned())?, Some(value));
+    assert_eq!(clone.get("key500".to_owned())?, Some("value500".to_owned()));
+    Ok(())
+}
+
+// Clones share a bounded pool of log handles, compaction closes the deleted logs
+#[test]
+fn bounded_file_handles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for gen in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..100 {
+            store.set(format!("key{}-{}", gen, i), format!("value{}", i))?;
+        }
+    }
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().mmap(false).max_open_files(2))?;
+    let clones: Vec<KvStore> = (0..4).map(|_| store.clone()).collect();
+    for (n, clone) in clones.iter().enumerate() {
+        for gen in 0..5 {
+            assert_eq!(clone.get(format!("key{}-{}", gen, n))?, Some(format!("value{}", n)));
+        }
+    }
+    assert_eq!(store.open_files(), 2);
+
+    let value = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set(format!("key0-{}", i % 10), value.clone())?;
+    }
+    assert!(store.open_files() <= 1);
+    assert_eq!(clones[0].get("key4-99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.open_files(), 1);
+    Ok(())
+}
+
+#[test]
+fn engine_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    let info = store.info()?;
+    assert_eq!(info.engine, "kvs");
+    assert_eq!(info.keys, 9);
+    assert_eq!(info.generation, Some(1));
+    assert!(info.disk_bytes >= log_bytes(&temp_dir)?);
+    let dead = info.dead_bytes.unwrap();
+    assert!(dead > 0 && dead < info.disk_bytes);
+    assert!(info.dead_ratio().unwrap() > 0.8);
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
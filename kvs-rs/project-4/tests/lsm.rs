@@ -0,0 +1,109 @@
+use std::fs;
+
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, LsmEngine, LsmOptions, Result};
+
+/// small enough to flush and compact after a few hundred writes
+fn small() -> LsmOptions {
+    LsmOptions::new().memtable_size(4096).level0_tables(2).table_size(8192).level1_size(8 * 1024)
+}
+
+#[test]
+fn set_get_remove() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = LsmEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.remove("key1".to_owned())?;
+    assert!(engine.remove("key1".to_owned()).is_err());
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    // the write-ahead log brings the memtable back
+    engine.set("key2".to_owned(), "value".to_owned())?;
+    drop(engine);
+    let engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn overwrites_do_not_fill_the_memtable() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = LsmEngine::open_with(temp_dir.path(), small())?;
+    for i in 0..1000 {
+        engine.set("key".to_owned(), format!("value{}", i))?;
+    }
+    // one live entry never reaches the memtable size, with or without a reopen
+    assert!(engine.level_tables().iter().all(|&tables| tables == 0));
+    drop(engine);
+    let engine = LsmEngine::open_with(temp_dir.path(), small())?;
+    engine.set("key".to_owned(), "last".to_owned())?;
+    assert!(engine.level_tables().iter().all(|&tables| tables == 0));
+    assert_eq!(engine.get("key".to_owned())?, Some("last".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn flush_and_compact() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = LsmEngine::open_with(temp_dir.path(), small())?;
+    for round in 0..5 {
+        for i in 0..1000 {
+            engine.set(format!("key{:04}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    for i in (0..1000).step_by(3) {
+        engine.remove(format!("key{:04}", i))?;
+    }
+    let levels = engine.level_tables();
+    assert!(levels.len() >= 3, "levels {:?}", levels);
+    assert!(levels[0] <= 2);
+
+    let check = |engine: &LsmEngine| -> Result<()> {
+        for i in 0..1000 {
+            let expected = if i % 3 == 0 { None } else { Some(format!("value{}-4", i)) };
+            assert_eq!(engine.get(format!("key{:04}", i))?, expected);
+        }
+        let page = engine.scan("key0500".to_owned(), 4)?;
+        let keys: Vec<&str> = page.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["key0500", "key0502", "key0503", "key0505"]);
+        assert_eq!(engine.scan(String::new(), 2000)?.len(), 666);
+        assert_eq!(engine.info()?.keys, 666);
+        Ok(())
+    };
+    check(&engine)?;
+    drop(engine);
+    let engine = LsmEngine::open_with(temp_dir.path(), small())?;
+    check(&engine)?;
+
+    // a full compaction leaves only the deepest level
+    engine.compact()?;
+    let levels = engine.level_tables();
+    assert!(levels[..levels.len() - 1].iter().all(|n| *n == 0), "levels {:?}", levels);
+    check(&engine)
+}
+
+#[test]
+fn corrupted_table_is_detected() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = LsmEngine::open_with(temp_dir.path(), small())?;
+    for i in 0..200 {
+        engine.set(format!("key{:04}", i), "v".repeat(100))?;
+    }
+    drop(engine);
+
+    let table = fs::read_dir(temp_dir.path())?
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "sst"))
+        .unwrap();
+    let mut data = fs::read(&table)?;
+    data[20] ^= 1;
+    fs::write(&table, data)?;
+    let engine = LsmEngine::open_with(temp_dir.path(), small())?;
+    assert!(engine.scan(String::new(), 1000).is_err());
+    Ok(())
+}
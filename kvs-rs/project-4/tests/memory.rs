@@ -0,0 +1,57 @@
+use std::thread;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, MemoryEngine, MemoryOptions, Result};
+
+#[test]
+fn set_get_remove() -> Result<()> {
+    let engine = MemoryEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert_eq!(engine.scan("key".to_owned(), 10)?.len(), 2);
+    engine.remove("key1".to_owned())?;
+    assert!(engine.remove("key1".to_owned()).is_err());
+    assert_eq!(engine.scan(String::new(), 10)?, vec![("key2".to_owned(), "value3".to_owned())]);
+
+    // clones share the pairs
+    let clone = engine.clone();
+    let handle = thread::spawn(move || clone.set("key4".to_owned(), "value4".to_owned()));
+    handle.join().unwrap()?;
+    assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn snapshot_and_reload() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = MemoryEngine::open(temp_dir.path())?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.snapshot()?;
+    engine.set("lost".to_owned(), "value".to_owned())?;
+    drop(engine);
+
+    let engine = MemoryEngine::open(temp_dir.path())?;
+    assert_eq!(engine.len(), 100);
+    assert_eq!(engine.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(engine.get("lost".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn periodic_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let options = MemoryOptions::new().snapshot_every(Duration::from_millis(50));
+    let engine = MemoryEngine::open_with(temp_dir.path(), options)?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    let reopened = MemoryEngine::open(temp_dir.path())?;
+    assert_eq!(reopened.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use tempfile::TempDir;
+
+use kvs::{KvsClient, KvsServer, KvStore, Result};
+use kvs::metrics::{self, Registry, LATENCY_BUCKETS};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+fn scrape(addr: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: kvs\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    response
+}
+
+#[test]
+fn render_prometheus_text() {
+    let registry = Registry::new();
+    registry.counter("requests_total", "Requests", &[("command", "get")]).add(3);
+    registry.gauge("depth", "Queue depth", &[]).set(-2);
+    registry.gauge_fn("keys", "Keys", &[], || Some(7));
+    let h = registry.histogram("latency_seconds", "Latency", &[("command", "get")], LATENCY_BUCKETS);
+    h.observe(Duration::from_micros(300));
+    h.observe(Duration::from_secs(20));
+
+    let text = registry.render();
+    assert!(text.contains("# TYPE requests_total counter\nrequests_total{command=\"get\"} 3\n"));
+    assert!(text.contains("# HELP depth Queue depth\n# TYPE depth gauge\ndepth -2\n"));
+    assert!(text.contains("keys 7\n"));
+    assert!(text.contains("latency_seconds_bucket{command=\"get\",le=\"0.00025\"} 0\n"));
+    assert!(text.contains("latency_seconds_bucket{command=\"get\",le=\"0.0005\"} 1\n"));
+    assert!(text.contains("latency_seconds_bucket{command=\"get\",le=\"10\"} 1\n"));
+    assert!(text.contains("latency_seconds_bucket{command=\"get\",le=\"+Inf\"} 2\n"));
+    assert!(text.contains("latency_seconds_count{command=\"get\"} 2\n"));
+}
+
+#[test]
+fn server_and_engine_metrics() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let (addr, metrics_addr) = ("127.0.0.1:4150", "127.0.0.1:4151");
+    metrics::serve(metrics_addr, metrics::global())?;
+    let server = KvsServer::new(KvStore::open(dir.path())?, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..10 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.get("key1".to_owned())?;
+    assert!(client.remove("missing".to_owned()).is_err());
+
+    let text = scrape(metrics_addr);
+    assert!(text.contains("kvs_requests_total{command=\"set\"} 10\n"), "{}", text);
+    assert!(text.contains("kvs_request_errors_total{command=\"remove\"} 1\n"));
+    assert!(text.contains("kvs_request_duration_seconds_count{command=\"get\"} 1\n"));
+    assert!(text.contains("# TYPE kvs_engine_bytes_written_total counter"));
+    assert!(text.contains("# TYPE kvs_pool_queue_depth gauge"));
+    assert!(text.contains("kvs_uncompacted_bytes "));
+    Ok(())
+}
+
+#[test]
+fn server_metrics_addr() {
+    let dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server").unwrap()
+        .args(&["--addr", "127.0.0.1:4152", "--metrics-addr", "127.0.0.1:4153"])
+        .current_dir(&dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let text = scrape("127.0.0.1:4153");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(text.contains("kvs_index_keys 0\n"), "{}", text);
+}
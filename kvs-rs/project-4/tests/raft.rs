@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use tempfile::TempDir;
+
+use kvs::{KvsClient, KvsEngine, Op, Result};
+use kvs::raft::{Envelope, NodeId, RaftNode};
+
+/// An in-memory state machine, so runs are fast and repeatable.
+#[derive(Clone, Default)]
+struct MapEngine(Arc<Mutex<BTreeMap<String, String>>>);
+
+impl KvsEngine for MapEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.lock().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.lock().unwrap().remove(&key);
+        Ok(())
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let map = self.0.lock().unwrap();
+        Ok(map.range(start..).take(limit).map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Delivers messages between nodes in order, dropping those crossing a partition.
+struct Network {
+    nodes: BTreeMap<NodeId, RaftNode<MapEngine>>,
+    group: HashMap<NodeId, u32>,
+    queue: VecDeque<Envelope>,
+}
+
+impl Network {
+    fn new(n: u64, snapshot_threshold: u64) -> Network {
+        let ids: Vec<NodeId> = (1..=n).collect();
+        let nodes = ids.iter().map(|id| {
+            let peers = ids.iter().cloned().filter(|p| p != id).collect();
+            let node = RaftNode::new(*id, peers, MapEngine::default()).snapshot_threshold(snapshot_threshold);
+            (*id, node)
+        }).collect();
+        Network { nodes, group: HashMap::new(), queue: VecDeque::new() }
+    }
+
+    /// nodes in different groups can't talk, nodes not listed are in group 0
+    fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.group.clear();
+        for (i, ids) in groups.iter().enumerate() {
+            for id in ids.iter() {
+                self.group.insert(*id, i as u32 + 1);
+            }
+        }
+    }
+
+    fn heal(&mut self) {
+        self.group.clear();
+    }
+
+    fn connected(&self, a: NodeId, b: NodeId) -> bool {
+        self.group.get(&a).unwrap_or(&0) == self.group.get(&b).unwrap_or(&0)
+    }
+
+    fn tick(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            for node in self.nodes.values_mut() {
+                node.tick().unwrap();
+            }
+            self.deliver();
+        }
+    }
+
+    fn deliver(&mut self) {
+        loop {
+            for node in self.nodes.values_mut() {
+                self.queue.extend(node.take_messages());
+            }
+            let env = match self.queue.pop_front() {
+                Some(env) => env,
+                None => return,
+            };
+            if self.connected(env.from, env.to) {
+                self.nodes.get_mut(&env.to).unwrap().step(env).unwrap();
+            }
+        }
+    }
+
+    fn leaders(&self) -> Vec<NodeId> {
+        self.nodes.values().filter(|n| n.is_leader()).map(|n| n.id()).collect()
+    }
+
+    /// the leader of the highest term among `ids`
+    fn leader_among(&self, ids: &[NodeId]) -> NodeId {
+        ids.iter().map(|id| &self.nodes[id])
+            .filter(|n| n.is_leader())
+            .max_by_key(|n| n.term())
+            .expect("no leader")
+            .id()
+    }
+
+    fn propose(&mut self, leader: NodeId, key: &str, value: &str) -> Result<u64> {
+        let op = Op::Set { key: key.to_owned(), value: value.to_owned() };
+        let index = self.nodes.get_mut(&leader).unwrap().propose(op)?;
+        self.deliver();
+        Ok(index)
+    }
+
+    fn get(&self, id: NodeId, key: &str) -> Option<String> {
+        self.nodes[&id].engine().get(key.to_owned()).unwrap()
+    }
+}
+
+#[test]
+fn elect_single_leader() {
+    let mut net = Network::new(3, 1000);
+    net.tick(50);
+    assert_eq!(net.leaders().len(), 1);
+    let leader = net.leaders()[0];
+    let term = net.nodes[&leader].term();
+    for node in net.nodes.values() {
+        assert_eq!(node.leader(), Some(leader));
+        assert_eq!(node.term(), term);
+    }
+
+    // a stable cluster keeps its leader
+    net.tick(100);
+    assert_eq!(net.leaders(), vec![leader]);
+    assert_eq!(net.nodes[&leader].term(), term);
+}
+
+#[test]
+fn replicate_to_all_nodes() -> Result<()> {
+    let mut net = Network::new(3, 1000);
+    net.tick(50);
+    let leader = net.leaders()[0];
+    for i in 0..20 {
+        net.propose(leader, &format!("key{}", i), &format!("value{}", i))?;
+    }
+    let op = Op::Remove { key: "key0".to_owned() };
+    net.nodes.get_mut(&leader).unwrap().propose(op)?;
+    net.tick(5);
+
+    for id in 1..=3 {
+        assert_eq!(net.get(id, "key0"), None);
+        for i in 1..20 {
+            assert_eq!(net.get(id, &format!("key{}", i)), Some(format!("value{}", i)));
+        }
+        // the default info counts the keys
+        assert_eq!(net.nodes[&id].engine().info()?.keys, 19);
+    }
+
+    // followers refuse proposals
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    assert!(net.propose(follower, "key", "value").is_err());
+    Ok(())
+}
+
+#[test]
+fn partition_and_heal() -> Result<()> {
+    let mut net = Network::new(5, 1000);
+    net.tick(50);
+    let old = net.leaders()[0];
+    net.propose(old, "key", "before")?;
+    net.tick(5);
+
+    // the old leader is cut off with one follower, the other three elect a new leader
+    let minority: Vec<NodeId> = vec![old, (1..=5).find(|id| *id != old).unwrap()];
+    let majority: Vec<NodeId> = (1..=5).filter(|id| !minority.contains(id)).collect();
+    net.partition(&[&minority, &majority]);
+    let lost = net.propose(old, "key", "lost")?;
+    net.tick(100);
+    assert!(net.nodes[&old].commit_index() < lost);
+
+    let new = net.leader_among(&majority);
+    assert_ne!(new, old);
+    net.propose(new, "key", "after")?;
+    net.tick(5);
+    for id in majority.iter() {
+        assert_eq!(net.get(*id, "key"), Some("after".to_owned()));
+    }
+    for id in minority.iter() {
+        assert_eq!(net.get(*id, "key"), Some("before".to_owned()));
+    }
+
+    // after healing the old leader steps down and drops its uncommitted entry
+    net.heal();
+    net.tick(50);
+    assert_eq!(net.leaders(), vec![new]);
+    for id in 1..=5 {
+        assert_eq!(net.get(id, "key"), Some("after".to_owned()));
+        assert_eq!(net.nodes[&id].leader(), Some(new));
+    }
+    Ok(())
+}
+
+#[test]
+fn lagging_follower_gets_snapshot() -> Result<()> {
+    let mut net = Network::new(3, 10);
+    net.tick(50);
+    let leader = net.leaders()[0];
+    let lagging = (1..=3).find(|id| *id != leader).unwrap();
+    let others: Vec<NodeId> = (1..=3).filter(|id| *id != lagging).collect();
+    net.partition(&[&[lagging], &others]);
+
+    for i in 0..50 {
+        net.propose(leader, &format!("key{}", i), &format!("value{}", i))?;
+    }
+    net.tick(5);
+    assert!(net.nodes[&leader].snapshot_index() >= 40);
+    assert_eq!(net.get(lagging, "key0"), None);
+
+    net.heal();
+    net.tick(50);
+    assert_eq!(net.leaders(), vec![leader]);
+    assert!(net.nodes[&lagging].snapshot_index() >= 40);
+    for i in 0..50 {
+        assert_eq!(net.get(lagging, &format!("key{}", i)), Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn reads_wait_for_a_quorum() -> Result<()> {
+    let mut net = Network::new(3, 1000);
+    net.tick(50);
+    let old = net.leaders()[0];
+    let read = net.nodes.get_mut(&old).unwrap().read_index()?;
+    net.deliver();
+    assert_eq!(net.nodes.get_mut(&old).unwrap().take_reads(), vec![(read, true)]);
+
+    // cut off, the old leader can't confirm a read, and steps down
+    let others: Vec<NodeId> = (1..=3).filter(|id| *id != old).collect();
+    net.partition(&[&[old], &others]);
+    let stale = net.nodes.get_mut(&old).unwrap().read_index()?;
+    net.tick(5);
+    assert!(net.nodes.get_mut(&old).unwrap().take_reads().is_empty());
+    net.tick(50);
+    assert!(!net.nodes[&old].is_leader());
+    assert_eq!(net.nodes.get_mut(&old).unwrap().take_reads(), vec![(stale, false)]);
+    assert!(net.nodes.get_mut(&old).unwrap().read_index().is_err());
+
+    let new = net.leader_among(&others);
+    net.propose(new, "key", "value")?;
+    let read = net.nodes.get_mut(&new).unwrap().read_index()?;
+    net.deliver();
+    assert_eq!(net.nodes.get_mut(&new).unwrap().take_reads(), vec![(read, true)]);
+    Ok(())
+}
+
+#[test]
+fn restart_keeps_term_and_log() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let engine = MapEngine::default();
+    let mut node = RaftNode::open(1, vec![], engine.clone(), dir.path().to_owned())?.snapshot_threshold(5);
+    while !node.is_leader() {
+        node.tick()?;
+    }
+    for i in 0..12 {
+        node.propose(Op::Set { key: format!("key{}", i), value: format!("value{}", i) })?;
+    }
+    let (term, last, snapshot) = (node.term(), node.last_index(), node.snapshot_index());
+    assert!(snapshot > 0 && snapshot < last);
+    drop(node);
+    // an entry torn by a crash is cut off
+    OpenOptions::new().append(true).open(dir.path().join("raft.log"))?.write_all(b"{\"term\":")?;
+
+    let mut node = RaftNode::open(1, vec![], engine.clone(), dir.path().to_owned())?;
+    assert_eq!((node.term(), node.last_index(), node.snapshot_index()), (term, last, snapshot));
+    while !node.is_leader() {
+        node.tick()?;
+    }
+    assert_eq!(node.term(), term + 1);
+    node.propose(Op::Remove { key: "key0".to_owned() })?;
+    assert_eq!(node.last_index(), last + 2);
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.get("key11".to_owned())?, Some("value11".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cluster_of_servers() -> Result<()> {
+    let addrs = ["127.0.0.1:4120", "127.0.0.1:4121", "127.0.0.1:4122"];
+    let peers = addrs.iter().enumerate()
+        .map(|(i, addr)| format!("{}={}", i + 1, addr))
+        .collect::<Vec<_>>()
+        .join(",");
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut children: Vec<Child> = addrs.iter().enumerate().map(|(i, addr)| {
+        Command::cargo_bin("kvs-server").unwrap()
+            .args(&["--addr", addr, "--raft-id", &(i + 1).to_string(), "--raft-peers", &peers])
+            .current_dir(&dirs[i])
+            .spawn()
+            .unwrap()
+    }).collect();
+    thread::sleep(Duration::from_secs(3));
+
+    // any node redirects to the leader
+    KvsClient::connect(addrs[0])?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::connect(addrs[1])?.set("key2".to_owned(), "value2".to_owned())?;
+    let mut client = KvsClient::connect(addrs[2])?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert_eq!(KvsClient::connect(addrs[0])?.get("key1".to_owned())?, None);
+
+    for child in children.iter_mut() {
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+
+use kvs::{Keyring, KvsClient, KvsEngine, KvsServer, KvStore, Result};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+fn start_server(dir: &TempDir, addr: &str, leader: Option<&str>) -> Result<KvStore> {
+    start_with(dir, addr, leader, false)
+}
+
+/// `journal` keeps the replication journal and offsets in `dir`
+fn start_with(dir: &TempDir, addr: &str, leader: Option<&str>, journal: bool) -> Result<KvStore> {
+    let store = KvStore::open(dir.path())?;
+    let mut server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(4)?).replication();
+    if journal {
+        server = server.replication_dir(dir.path().to_owned());
+    }
+    if let Some(leader) = leader {
+        server = server.replica_of(leader.parse().unwrap());
+    }
+    run(server, addr);
+    Ok(store)
+}
+
+fn run<E: KvsEngine + 'static>(server: KvsServer<E, SharedQueueThreadPool>, addr: &str) {
+    let addr = addr.to_string();
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+}
+
+/// names of the replication journal files in `dir`
+fn journals(dir: &TempDir) -> Vec<PathBuf> {
+    fs::read_dir(dir.path()).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "journal"))
+        .collect()
+}
+
+/// a `KvStore` which fails to set the key `bad`
+#[derive(Clone)]
+struct Failing(KvStore);
+
+impl KvsEngine for Failing {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if key == "bad" {
+            return Err(io::Error::other("disk full").into());
+        }
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        self.0.scan(start, limit)
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.0.compact()
+    }
+}
+
+fn wait_for<F: FnMut() -> bool>(mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !f() {
+        assert!(Instant::now() < deadline, "replica did not catch up");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn replica_follows_leader_and_promotes() -> Result<()> {
+    let (leader_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let (leader_addr, replica_addr) = ("127.0.0.1:4110", "127.0.0.1:4111");
+
+    // data written before the leader started is copied by the initial resync
+    KvStore::open(leader_dir.path())?.set("old".to_owned(), "value".to_owned())?;
+    start_server(&leader_dir, leader_addr, None)?;
+    let replica = start_server(&replica_dir, replica_addr, Some(leader_addr))?;
+
+    let mut leader = KvsClient::connect(leader_addr)?;
+    for i in 0..100 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..10 {
+        leader.remove(format!("key{}", i))?;
+    }
+
+    wait_for(|| replica.get("key99".to_owned()).unwrap().is_some());
+    let mut client = KvsClient::connect(replica_addr)?;
+    // lag is measured against the head seen at the last pull, so wait for every op
+    wait_for(|| client.replica_status().unwrap().applied == 110);
+    assert_eq!(client.replica_status()?.lag, 0);
+    assert_eq!(replica.get("old".to_owned())?, Some("value".to_owned()));
+    for i in 0..10 {
+        assert_eq!(client.get(format!("key{}", i))?, None);
+    }
+    for i in 10..100 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    let status = client.replica_status()?;
+    assert_eq!(status.role, "replica");
+    assert_eq!(status.applied, 110);
+
+    // replicas serve reads only
+    assert!(client.set("key0".to_owned(), "value".to_owned()).is_err());
+    assert!(client.remove("key10".to_owned()).is_err());
+
+    client.promote()?;
+    assert_eq!(client.replica_status()?.role, "leader");
+    client.set("key0".to_owned(), "promoted".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, Some("promoted".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn restarted_servers_resume_from_the_journal() -> Result<()> {
+    let (leader_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    start_with(&leader_dir, "127.0.0.1:4112", None, true)?;
+    start_with(&replica_dir, "127.0.0.1:4113", Some("127.0.0.1:4112"), true)?;
+    let mut leader = KvsClient::connect("127.0.0.1:4112")?;
+    for i in 0..100 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut replica = KvsClient::connect("127.0.0.1:4113")?;
+    wait_for(|| replica.replica_status().unwrap().applied == 100);
+
+    // a key only the replica has would be dropped by a full resync
+    replica.promote()?;
+    replica.set("local".to_owned(), "value".to_owned())?;
+    for i in 100..150 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    // the leader restarts with its ops in the journal only
+    start_with(&leader_dir, "127.0.0.1:4114", None, true)?;
+    let mut leader = KvsClient::connect("127.0.0.1:4114")?;
+    leader.set("key150".to_owned(), "value150".to_owned())?;
+    let store = start_with(&replica_dir, "127.0.0.1:4115", Some("127.0.0.1:4114"), true)?;
+    let mut replica = KvsClient::connect("127.0.0.1:4115")?;
+    wait_for(|| replica.replica_status().unwrap().applied == 151);
+    assert_eq!(store.get("key149".to_owned())?, Some("value149".to_owned()));
+    assert_eq!(store.get("key150".to_owned())?, Some("value150".to_owned()));
+    assert_eq!(store.get("local".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn replication_is_off_unless_asked_for() -> Result<()> {
+    let (leader_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let store = KvStore::open(leader_dir.path())?;
+    run(KvsServer::new(store, SharedQueueThreadPool::new(4)?), "127.0.0.1:4116");
+    let replica = start_server(&replica_dir, "127.0.0.1:4117", Some("127.0.0.1:4116"))?;
+    let mut leader = KvsClient::connect("127.0.0.1:4116")?;
+    leader.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(leader.replica_status()?.applied, 0);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(replica.get("key".to_owned())?, None);
+    assert!(journals(&leader_dir).is_empty());
+    Ok(())
+}
+
+#[test]
+fn journal_values_are_sealed() -> Result<()> {
+    let (leader_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let keys = Keyring::parse(&format!("1:{}", "11".repeat(32)))?;
+    let server = KvsServer::new(KvStore::open(leader_dir.path())?, SharedQueueThreadPool::new(4)?)
+        .replication_dir(leader_dir.path().to_owned())
+        .replication_keys(keys);
+    run(server, "127.0.0.1:4118");
+    let mut leader = KvsClient::connect("127.0.0.1:4118")?;
+    for i in 0..10 {
+        leader.set(format!("key{}", i), format!("secret{}", i))?;
+    }
+    let journal = fs::read_to_string(&journals(&leader_dir)[0])?;
+    assert!(journal.contains("Sealed") && !journal.contains("secret"));
+
+    let replica = start_server(&replica_dir, "127.0.0.1:4119", Some("127.0.0.1:4118"))?;
+    wait_for(|| replica.get("key9".to_owned()).unwrap().is_some());
+    assert_eq!(replica.get("key0".to_owned())?, Some("secret0".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn failed_writes_are_undone_on_replicas() -> Result<()> {
+    let (leader_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let store = KvStore::open(leader_dir.path())?;
+    store.set("bad".to_owned(), "old".to_owned())?;
+    run(KvsServer::new(Failing(store), SharedQueueThreadPool::new(4)?).replication(), "127.0.0.1:4123");
+    let replica = start_server(&replica_dir, "127.0.0.1:4124", Some("127.0.0.1:4123"))?;
+    let mut leader = KvsClient::connect("127.0.0.1:4123")?;
+    assert!(leader.set("bad".to_owned(), "new".to_owned()).is_err());
+    leader.set("after".to_owned(), "value".to_owned())?;
+    wait_for(|| replica.get("after".to_owned()).unwrap().is_some());
+    assert_eq!(replica.get("bad".to_owned())?, Some("old".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn journaled_writes_a_crash_kept_from_the_engine_are_recovered() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path())?;
+    store.set("a".to_owned(), "old".to_owned())?;
+    store.set("b".to_owned(), "old".to_owned())?;
+    // the engine has the first op, the crash came before it ran the others
+    fs::write(dir.path().join("replication.json"), r#"{"epoch":1,"leader_epoch":0,"applied":0,"settled":0}"#)?;
+    fs::write(
+        dir.path().join("replication-1.journal"),
+        r#"[1,{"Set":{"key":"a","value":"old"}}][2,{"Set":{"key":"a","value":"new"}}][3,{"Remove":{"key":"b"}}]"#,
+    )?;
+    let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(4)?).replication_dir(dir.path().to_owned());
+    run(server, "127.0.0.1:4125");
+    assert_eq!(store.get("a".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    let state = fs::read_to_string(dir.path().join("replication.json"))?;
+    assert!(state.contains(r#""settled":3"#));
+    Ok(())
+}
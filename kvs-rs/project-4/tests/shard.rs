@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use kvs::{KvsClient, KvsServer, KvStore, Result};
+use kvs::shard::{reshard, ShardedClient, ShardMap};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+fn start_servers(addrs: &[SocketAddr]) -> Result<Vec<TempDir>> {
+    let mut dirs = Vec::new();
+    for addr in addrs {
+        let dir = TempDir::new().unwrap();
+        let server = KvsServer::new(KvStore::open(dir.path())?, SharedQueueThreadPool::new(4)?);
+        let addr = *addr;
+        thread::spawn(move || server.run(addr).unwrap());
+        dirs.push(dir);
+    }
+    thread::sleep(Duration::from_millis(300));
+    Ok(dirs)
+}
+
+/// keys held by each server
+fn keys_per_server(addrs: &[SocketAddr]) -> Result<HashMap<SocketAddr, Vec<String>>> {
+    let mut keys = HashMap::new();
+    for addr in addrs {
+        let pairs = KvsClient::connect(addr)?.scan(String::new(), 10000)?;
+        keys.insert(*addr, pairs.into_iter().map(|(k, _)| k).collect());
+    }
+    Ok(keys)
+}
+
+#[test]
+fn adding_a_shard_moves_few_keys() -> Result<()> {
+    let addrs: Vec<SocketAddr> = (0..4).map(|i| format!("127.0.0.1:{}", 4200 + i).parse().unwrap()).collect();
+    let small = ShardMap::new(1, addrs[..3].to_vec());
+    let big = ShardMap::new(2, addrs.clone());
+    let moved = (0..10000)
+        .map(|i| format!("key{}", i))
+        .filter(|k| small.owner(k).unwrap() != big.owner(k).unwrap())
+        .count();
+    // about a quarter moves to the new shard, nothing moves between old ones
+    assert!(moved > 1500 && moved < 3500, "moved {}", moved);
+    for i in 0..10000 {
+        let key = format!("key{}", i);
+        let owner = big.owner(&key)?;
+        assert!(owner == addrs[3] || owner == small.owner(&key)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn deserialized_map_routes_keys() -> Result<()> {
+    let addrs: Vec<SocketAddr> = (0..3).map(|i| format!("127.0.0.1:{}", 4200 + i).parse().unwrap()).collect();
+    let map = ShardMap::new(1, addrs);
+    let copy: ShardMap = serde_json::from_slice(&serde_json::to_vec(&map)?)?;
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        assert_eq!(copy.owner(&key)?, map.owner(&key)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn reshard_across_servers() -> Result<()> {
+    let addrs: Vec<SocketAddr> = (0..3).map(|i| format!("127.0.0.1:{}", 4130 + i).parse().unwrap()).collect();
+    let _dirs = start_servers(&addrs)?;
+
+    let old = ShardMap::new(1, addrs[..2].to_vec());
+    let mut client = ShardedClient::new(old.clone());
+    for i in 0..300 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let keys = keys_per_server(&addrs)?;
+    assert!(!keys[&addrs[0]].is_empty() && !keys[&addrs[1]].is_empty());
+    assert!(keys[&addrs[2]].is_empty());
+
+    // switch online, unmoved keys are still found on their old owner
+    let new = ShardMap::new(2, addrs.clone());
+    client.update_map(new.clone());
+    client.update_map(old.clone());
+    assert_eq!(client.map().version, 2);
+    for i in 0..300 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    client.set("key0".to_owned(), "new".to_owned())?;
+    client.remove("key1".to_owned())?;
+
+    let moved = reshard(&old, &new)?;
+    assert!(moved > 0);
+    client.finish_resharding();
+
+    let keys = keys_per_server(&addrs)?;
+    assert!(!keys[&addrs[2]].is_empty());
+    for (addr, keys) in keys.iter() {
+        for key in keys {
+            assert_eq!(new.owner(key)?, *addr);
+        }
+    }
+    assert_eq!(keys.values().map(|k| k.len()).sum::<usize>(), 299);
+    assert_eq!(client.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    for i in 2..300 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
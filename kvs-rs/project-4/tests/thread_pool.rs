@@ -1,5 +1,7 @@
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -62,8 +64,49 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn dynamic_thread_pool_spawn_counter() -> Result<()> {
+    let pool = DynamicThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn dynamic_thread_pool_grows_and_shrinks() -> Result<()> {
+    let pool = DynamicThreadPool::with_limits(1, 8, Duration::from_millis(200))?;
+    assert_eq!(pool.threads(), 1);
+    // every task waits for the others, so they only finish on 8 threads
+    let barrier = Arc::new(Barrier::new(9));
+    for _ in 0..8 {
+        let barrier = barrier.clone();
+        pool.spawn(move || {
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+    assert_eq!(pool.threads(), 8);
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(pool.threads(), 1);
+    assert!(DynamicThreadPool::with_limits(4, 2, Duration::from_secs(1)).is_err());
+    Ok(())
+}
 
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn dynamic_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<DynamicThreadPool>()
+}